@@ -24,7 +24,7 @@ fn bench_base(block_size: u32, n: u32, cache_size: usize) {
     let mut remove_num = n / 100 * 20;
     while remove_num > 0 {
         let idx: usize = rng.gen_range(0, n as usize);
-        if r[idx] == true {
+        if r[idx] {
             continue;
         }
         r[idx] = true;
@@ -32,23 +32,23 @@ fn bench_base(block_size: u32, n: u32, cache_size: usize) {
     }
     let path = std::path::Path::new("test.idx");
     {
-        setup(&path);
-        let bt = btree::Btree::new(std::path::Path::new("test.idx"), block_size, 2, n, 100);
+        setup(path);
+        let bt = btree::Btree::new(std::path::Path::new("test.idx"), block_size, 2, 100).unwrap();
         for i in &v {
             let _ = bt.insert(*i, i * 10 + i);
         }
-        bt.flush_cache();
+        bt.flush_cache().unwrap();
     }
 
-    let bt = btree::Btree::load(path, block_size, n, cache_size);
+    let bt: btree::Btree<u32, u32> = btree::Btree::load(path, cache_size).unwrap();
 
     let now = Instant::now();
     for i in 0..(n as usize) {
         if r[i] {
-            let result = bt.remove(v[i]);
+            let result = bt.remove(&v[i]);
             result.unwrap();
         } else {
-            let result = bt.find(v[i]);
+            let result = bt.find(&v[i]);
             result.unwrap();
         }
     }
@@ -0,0 +1,274 @@
+extern crate btree_rs;
+extern crate env_logger;
+use btree_rs::btree::{Btree, BtreeError, BtreeOptions, CacheKind, Key, Val};
+use std::fmt::Display;
+use std::ops::Bound;
+use std::path::PathBuf;
+use std::process::exit;
+use std::str::FromStr;
+
+const USAGE: &str = "\
+usage: btree [options] <command> <file> [args]
+
+commands:
+  create <file>              create an empty index
+  get <file> <key>           print the value of the key
+  put <file> <key> <value>   insert the pair or replace the value
+  del <file> <key>           remove the key
+  scan <file> [from] [to]    print the pairs with from <= key < to
+  stats <file>               print the cache, io and shape counters
+  verify <file>              check the index, exit code 1 on problems
+  compact <file>             compact the index file
+  dump <file>                print the nodes
+
+options:
+  --key <type>         key type: string, u32, u64 or i64 (default string)
+  --val <type>         value type: string, u32, u64 or i64 (default string)
+  --cache <n>          cache size in nodes (default 100 or the stored one)
+  --cache-policy <p>   cache policy: lru, clock, 2q or arc (default lru or the stored one)
+  --block-size <n>     block size of the new index (default 4096)
+  --alpha <n>          fill factor 1/alpha of the new index (default 2)
+  --multimap           new index keeps several values of the key
+  --limit <n>          print at most n pairs of the scan
+  --step <n>           compact incrementally, n blocks per step
+  --format <format>    dump format: text, json or dot (default text)
+
+Reading commands open the index read-only and run along with each other, the other ones
+fail while the index is opened by another process.
+Settings of the new index are stored in it, --cache and --cache-policy override them for one run.
+Index doesn't keep the key and value types, they must match the ones it's created with.";
+
+#[derive(Debug)]
+struct Args {
+    command: String,
+    path: PathBuf,
+    params: Vec<String>,
+    key_type: String,
+    val_type: String,
+    options: BtreeOptions,
+    limit: Option<usize>,
+    step: Option<usize>,
+    format: String,
+}
+
+fn parse_num<T: FromStr>(name: &str, value: Option<String>) -> Result<T, String> {
+    let value = value.ok_or_else(|| format!("{} needs a value", name))?;
+    value
+        .parse()
+        .map_err(|_| format!("bad value of {}: {}", name, value))
+}
+
+fn parse_args(mut args: impl Iterator<Item = String>) -> Result<Args, String> {
+    let mut positional = Vec::new();
+    let mut parsed = Args {
+        command: String::new(),
+        path: PathBuf::new(),
+        params: Vec::new(),
+        key_type: "string".to_string(),
+        val_type: "string".to_string(),
+        options: BtreeOptions::new(),
+        limit: None,
+        step: None,
+        format: "text".to_string(),
+    };
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--key" => parsed.key_type = args.next().ok_or("--key needs a value")?,
+            "--val" => parsed.val_type = args.next().ok_or("--val needs a value")?,
+            "--cache" => parsed.options.cache_size = Some(parse_num(&arg, args.next())?),
+            "--cache-policy" => {
+                parsed.options.cache_policy = Some(match args.next().as_deref() {
+                    Some("lru") => CacheKind::Lru,
+                    Some("clock") => CacheKind::Clock,
+                    Some("2q") => CacheKind::TwoQ,
+                    Some("arc") => CacheKind::Arc,
+                    Some(policy) => return Err(format!("unknown cache policy {}", policy)),
+                    None => return Err("--cache-policy needs a value".to_string()),
+                })
+            }
+            "--block-size" => parsed.options.block_size = Some(parse_num(&arg, args.next())?),
+            "--alpha" => parsed.options.alpha = Some(parse_num(&arg, args.next())?),
+            "--multimap" => parsed.options.multimap = Some(true),
+            "--limit" => parsed.limit = Some(parse_num(&arg, args.next())?),
+            "--step" => parsed.step = Some(parse_num(&arg, args.next())?),
+            "--format" => parsed.format = args.next().ok_or("--format needs a value")?,
+            "-h" | "--help" => return Err(String::new()),
+            _ if arg.starts_with("--") => return Err(format!("unknown option {}", arg)),
+            _ => positional.push(arg),
+        }
+    }
+    if positional.len() < 2 {
+        return Err("command and file are required".to_string());
+    }
+    parsed.params = positional.split_off(2);
+    parsed.path = PathBuf::from(positional.pop().unwrap());
+    parsed.command = positional.pop().unwrap();
+    let expected = match parsed.command.as_str() {
+        "create" | "stats" | "verify" | "compact" | "dump" => 0..=0,
+        "get" | "del" => 1..=1,
+        "put" => 2..=2,
+        "scan" => 0..=2,
+        command => return Err(format!("unknown command {}", command)),
+    };
+    if !expected.contains(&parsed.params.len()) {
+        return Err(format!("wrong number of arguments of {}", parsed.command));
+    }
+    Ok(parsed)
+}
+
+fn parse<T: FromStr>(what: &str, value: &str) -> Result<T, String> {
+    value
+        .parse()
+        .map_err(|_| format!("bad {}: {}", what, value))
+}
+
+fn run<K, V>(args: &Args) -> Result<bool, String>
+where
+    K: Key + FromStr + Display,
+    V: Val + FromStr + Display,
+{
+    let error = |err: BtreeError| err.to_string();
+    let bt: Btree<K, V> = match args.command.as_str() {
+        "create" => Btree::create_with(&args.path, &args.options),
+        // readers share the index with each other, writers lock it
+        "get" | "scan" | "stats" | "verify" | "dump" => {
+            Btree::open_read_only(&args.path, &args.options)
+        }
+        _ => Btree::open(&args.path, &args.options),
+    }
+    .map_err(error)?;
+    let mut ok = true;
+    match args.command.as_str() {
+        "create" => {}
+        "get" => {
+            let key: K = parse("key", &args.params[0])?;
+            if bt.is_multimap() {
+                for val in bt.find_all(&key).map_err(error)? {
+                    println!("{}", val);
+                }
+            } else {
+                println!("{}", bt.find(&key).map_err(error)?);
+            }
+        }
+        "put" => {
+            let key: K = parse("key", &args.params[0])?;
+            let val: V = parse("value", &args.params[1])?;
+            if bt.is_multimap() {
+                bt.insert(key, val).map_err(error)?;
+            } else {
+                bt.upsert(key, val).map_err(error)?;
+            }
+        }
+        "del" => {
+            let key: K = parse("key", &args.params[0])?;
+            bt.remove(&key).map_err(error)?;
+        }
+        "scan" => {
+            let bound = |index: usize| -> Result<Bound<K>, String> {
+                match args.params.get(index) {
+                    Some(key) => Ok(if index == 0 {
+                        Bound::Included(parse("key", key)?)
+                    } else {
+                        Bound::Excluded(parse("key", key)?)
+                    }),
+                    None => Ok(Bound::Unbounded),
+                }
+            };
+            let range = (bound(0)?, bound(1)?);
+            let limit = args.limit.unwrap_or(usize::MAX);
+            for entry in bt.range(range).take(limit) {
+                let (key, val) = entry.map_err(error)?;
+                println!("{}\t{}", key, val);
+            }
+        }
+        "stats" => {
+            let stats = bt.stats().map_err(error)?;
+            println!("{:#?}", bt.options());
+            println!("{:#?}", stats);
+        }
+        "verify" => {
+            let report = bt.verify().map_err(error)?;
+            println!(
+                "nodes={} leaves={} depth={} entries={} overflow_blocks={} free_blocks={}",
+                report.nodes,
+                report.leaves,
+                report.depth,
+                report.entries,
+                report.overflow_blocks,
+                report.free_blocks
+            );
+            for problem in report.problems.iter() {
+                println!("{:?}", problem);
+            }
+            ok = report.is_ok();
+        }
+        "compact" => match args.step {
+            Some(step) => {
+                // the last step may only find out there is nothing left to do
+                let mut steps = 0;
+                loop {
+                    let progress = bt.compact_step(step).map_err(error)?;
+                    if progress.moved > 0 || progress.trimmed > 0 {
+                        steps += 1;
+                    }
+                    if progress.done {
+                        break;
+                    }
+                }
+                println!("steps={}", steps);
+            }
+            None => bt.compact().map_err(error)?,
+        },
+        "dump" => match args.format.as_str() {
+            "text" => print!("{}", bt.dump_to_string().map_err(error)?),
+            "json" => println!("{}", bt.to_json().map_err(error)?),
+            "dot" => print!("{}", bt.to_dot().map_err(error)?),
+            format => return Err(format!("unsupported dump format {}", format)),
+        },
+        _ => unreachable!(),
+    }
+    bt.flush_cache().map_err(error)?;
+    Ok(ok)
+}
+
+fn run_key<K>(args: &Args) -> Result<bool, String>
+where
+    K: Key + FromStr + Display,
+{
+    match args.val_type.as_str() {
+        "string" => run::<K, String>(args),
+        "u32" => run::<K, u32>(args),
+        "u64" => run::<K, u64>(args),
+        "i64" => run::<K, i64>(args),
+        other => Err(format!("unknown value type {}", other)),
+    }
+}
+
+fn main() {
+    let _ = env_logger::try_init();
+    let args = match parse_args(std::env::args().skip(1)) {
+        Ok(args) => args,
+        Err(err) => {
+            if !err.is_empty() {
+                eprintln!("btree: {}", err);
+            }
+            eprintln!("{}", USAGE);
+            exit(2);
+        }
+    };
+    let result = match args.key_type.as_str() {
+        "string" => run_key::<String>(&args),
+        "u32" => run_key::<u32>(&args),
+        "u64" => run_key::<u64>(&args),
+        "i64" => run_key::<i64>(&args),
+        other => Err(format!("unknown key type {}", other)),
+    };
+    match result {
+        Ok(true) => {}
+        Ok(false) => exit(1),
+        Err(err) => {
+            eprintln!("btree: {}", err);
+            exit(1);
+        }
+    }
+}
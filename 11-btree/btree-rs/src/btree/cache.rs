@@ -0,0 +1,513 @@
+use super::Addr;
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashMap, VecDeque};
+use std::fmt::Debug;
+
+/// Replacement policy of the node cache.
+///
+/// Policy tracks the addresses of the cached nodes and chooses the one to evict,
+/// the nodes themselves and their dirty bits are kept by the cache.
+pub trait CachePolicy {
+    /// Cache capacity in nodes, set before any node is added and on changes.
+    fn set_capacity(&mut self, capacity: usize);
+    /// Node is added to the cache.
+    fn insert(&mut self, addr: Addr);
+    /// Cached node is read or changed.
+    fn access(&mut self, addr: Addr);
+    /// Node is dropped from the cache by the tree, it's not a victim.
+    fn remove(&mut self, addr: Addr);
+    /// Chooses the cached node to evict among the `evictable` ones
+    /// and stops tracking it. None if there is no such node.
+    fn victim(&mut self, evictable: &dyn Fn(Addr) -> bool) -> Option<Addr>;
+    /// All nodes are dropped from the cache.
+    fn clear(&mut self);
+}
+
+/// Built-in replacement policies, the one chosen by `BtreeOptions` is stored in the header.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum CacheKind {
+    Lru,
+    Clock,
+    TwoQ,
+    Arc,
+}
+
+impl CacheKind {
+    pub(super) fn policy(self) -> Box<dyn CachePolicy> {
+        match self {
+            CacheKind::Lru => Box::new(LruPolicy::default()),
+            CacheKind::Clock => Box::new(ClockPolicy::default()),
+            CacheKind::TwoQ => Box::new(TwoQPolicy::default()),
+            CacheKind::Arc => Box::new(ArcPolicy::default()),
+        }
+    }
+}
+
+/// Counters of the node cache since the tree is created or loaded.
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+pub struct CacheStats {
+    pub hits: u64,
+    pub misses: u64,
+    pub evictions: u64,
+    pub write_backs: u64, // changed nodes written to the file
+}
+
+// cached node and its dirty bit, set when the node is changed since it's written
+struct Entry<T> {
+    node: T,
+    dirty: bool,
+}
+
+// write-back cache of the nodes. Pinned nodes aren't evicted, the cache may
+// outgrow its capacity while they are pinned.
+pub(super) struct NodeCache<T> {
+    entries: HashMap<Addr, Entry<T>>,
+    pins: HashMap<Addr, usize>,
+    capacity: usize,
+    policy: Box<dyn CachePolicy>,
+    stats: CacheStats,
+}
+
+impl<T: Clone> NodeCache<T> {
+    pub(super) fn new(capacity: usize, kind: CacheKind) -> Self {
+        let mut policy = kind.policy();
+        policy.set_capacity(capacity);
+        NodeCache {
+            entries: HashMap::new(),
+            pins: HashMap::new(),
+            capacity,
+            policy,
+            stats: CacheStats::default(),
+        }
+    }
+
+    pub(super) fn capacity(&self) -> usize {
+        self.capacity
+    }
+
+    pub(super) fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub(super) fn stats(&self) -> CacheStats {
+        self.stats
+    }
+
+    pub(super) fn get(&mut self, addr: Addr) -> Option<T> {
+        match self.entries.get(&addr) {
+            Some(entry) => {
+                self.stats.hits += 1;
+                self.policy.access(addr);
+                Some(entry.node.clone())
+            }
+            None => {
+                self.stats.misses += 1;
+                None
+            }
+        }
+    }
+
+    // cached node, the policy and the counters are not changed
+    pub(super) fn peek(&self, addr: Addr) -> Option<T> {
+        self.entries.get(&addr).map(|entry| entry.node.clone())
+    }
+
+    pub(super) fn contains(&self, addr: Addr) -> bool {
+        self.entries.contains_key(&addr)
+    }
+
+    // adds the node or replaces the cached one, the dirty bit stays set
+    // until the node is written. Returns the evicted dirty nodes.
+    pub(super) fn put(&mut self, addr: Addr, node: T, dirty: bool) -> Vec<T> {
+        match self.entries.get_mut(&addr) {
+            Some(entry) => {
+                entry.node = node;
+                entry.dirty |= dirty;
+                self.policy.access(addr);
+            }
+            None => {
+                self.entries.insert(addr, Entry { node, dirty });
+                self.policy.insert(addr);
+            }
+        }
+        self.evict()
+    }
+
+    pub(super) fn set_clean(&mut self, addr: Addr) {
+        if let Some(entry) = self.entries.get_mut(&addr) {
+            entry.dirty = false;
+        }
+    }
+
+    pub(super) fn remove(&mut self, addr: Addr) {
+        if self.entries.remove(&addr).is_some() {
+            self.policy.remove(addr);
+        }
+    }
+
+    // nodes to write back, in address order
+    pub(super) fn dirty(&self) -> Vec<(Addr, T)> {
+        let mut dirty: Vec<(Addr, T)> = self
+            .entries
+            .iter()
+            .filter(|(_, entry)| entry.dirty)
+            .map(|(addr, entry)| (*addr, entry.node.clone()))
+            .collect();
+        dirty.sort_by_key(|(addr, _)| *addr);
+        dirty
+    }
+
+    pub(super) fn record_write_back(&mut self) {
+        self.stats.write_backs += 1;
+    }
+
+    pub(super) fn clear(&mut self) {
+        self.entries.clear();
+        self.policy.clear();
+    }
+
+    pub(super) fn set_capacity(&mut self, capacity: usize) -> Vec<T> {
+        self.capacity = capacity;
+        self.policy.set_capacity(capacity);
+        self.evict()
+    }
+
+    // the cached nodes are handed to the new policy, in no particular order
+    pub(super) fn set_policy(&mut self, mut policy: Box<dyn CachePolicy>) {
+        policy.set_capacity(self.capacity);
+        for addr in self.entries.keys() {
+            policy.insert(*addr);
+        }
+        self.policy = policy;
+    }
+
+    pub(super) fn pin(&mut self, addr: Addr) {
+        *self.pins.entry(addr).or_insert(0) += 1;
+    }
+
+    pub(super) fn unpin(&mut self, addr: Addr) {
+        if let Some(count) = self.pins.get_mut(&addr) {
+            *count -= 1;
+            if *count == 0 {
+                self.pins.remove(&addr);
+            }
+        }
+    }
+
+    fn evict(&mut self) -> Vec<T> {
+        let mut evicted = Vec::new();
+        while self.entries.len() > self.capacity {
+            let pins = &self.pins;
+            let addr = match self.policy.victim(&|addr| !pins.contains_key(&addr)) {
+                Some(addr) => addr,
+                None => break,
+            };
+            if let Some(entry) = self.entries.remove(&addr) {
+                self.stats.evictions += 1;
+                if entry.dirty {
+                    evicted.push(entry.node);
+                }
+            }
+        }
+        evicted
+    }
+}
+
+impl<T> Debug for NodeCache<T> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "NodeCache len={}, capacity={}, pinned={}",
+            self.entries.len(),
+            self.capacity,
+            self.pins.len()
+        )
+    }
+}
+
+// addresses in the order of use, the least recent first
+#[derive(Default)]
+struct Recency {
+    stamps: HashMap<Addr, u64>,
+    order: BTreeMap<u64, Addr>,
+    clock: u64,
+}
+
+impl Recency {
+    fn len(&self) -> usize {
+        self.stamps.len()
+    }
+
+    fn contains(&self, addr: Addr) -> bool {
+        self.stamps.contains_key(&addr)
+    }
+
+    // adds the address as the most recent or moves it there
+    fn push(&mut self, addr: Addr) {
+        self.remove(addr);
+        self.clock += 1;
+        self.stamps.insert(addr, self.clock);
+        self.order.insert(self.clock, addr);
+    }
+
+    fn remove(&mut self, addr: Addr) -> bool {
+        match self.stamps.remove(&addr) {
+            Some(stamp) => {
+                self.order.remove(&stamp);
+                true
+            }
+            None => false,
+        }
+    }
+
+    // removes the least recent address matching the predicate
+    fn pop(&mut self, pred: &dyn Fn(Addr) -> bool) -> Option<Addr> {
+        let (stamp, addr) = self
+            .order
+            .iter()
+            .find(|(_, addr)| pred(**addr))
+            .map(|(stamp, addr)| (*stamp, *addr))?;
+        self.order.remove(&stamp);
+        self.stamps.remove(&addr);
+        Some(addr)
+    }
+
+    fn clear(&mut self) {
+        self.stamps.clear();
+        self.order.clear();
+    }
+}
+
+/// Evicts the least recently used node.
+#[derive(Default)]
+pub struct LruPolicy {
+    recency: Recency,
+}
+
+impl CachePolicy for LruPolicy {
+    fn set_capacity(&mut self, _capacity: usize) {}
+
+    fn insert(&mut self, addr: Addr) {
+        self.recency.push(addr);
+    }
+
+    fn access(&mut self, addr: Addr) {
+        self.recency.push(addr);
+    }
+
+    fn remove(&mut self, addr: Addr) {
+        self.recency.remove(addr);
+    }
+
+    fn victim(&mut self, evictable: &dyn Fn(Addr) -> bool) -> Option<Addr> {
+        self.recency.pop(evictable)
+    }
+
+    fn clear(&mut self) {
+        self.recency.clear();
+    }
+}
+
+/// Second chance: the hand skips and clears the nodes used since it passed them.
+#[derive(Default)]
+pub struct ClockPolicy {
+    ring: VecDeque<(Addr, u64)>,       // front is under the hand
+    nodes: HashMap<Addr, (bool, u64)>, // reference bit and the generation in the ring
+    generation: u64,
+}
+
+impl CachePolicy for ClockPolicy {
+    fn set_capacity(&mut self, _capacity: usize) {}
+
+    fn insert(&mut self, addr: Addr) {
+        // removed nodes stay in the ring, their entries are stale
+        self.generation += 1;
+        self.nodes.insert(addr, (false, self.generation));
+        self.ring.push_back((addr, self.generation));
+    }
+
+    fn access(&mut self, addr: Addr) {
+        if let Some((referenced, _)) = self.nodes.get_mut(&addr) {
+            *referenced = true;
+        }
+    }
+
+    fn remove(&mut self, addr: Addr) {
+        self.nodes.remove(&addr);
+    }
+
+    fn victim(&mut self, evictable: &dyn Fn(Addr) -> bool) -> Option<Addr> {
+        // two turns clear all reference bits, pinned nodes are passed by
+        let mut steps = 2 * self.nodes.len();
+        while let Some((addr, generation)) = self.ring.pop_front() {
+            let (referenced, current) = match self.nodes.get_mut(&addr) {
+                Some(node) => node,
+                None => continue,
+            };
+            if *current != generation {
+                continue;
+            }
+            if !*referenced && evictable(addr) {
+                self.nodes.remove(&addr);
+                return Some(addr);
+            }
+            *referenced = false;
+            self.ring.push_back((addr, generation));
+            if steps == 0 {
+                return None;
+            }
+            steps -= 1;
+        }
+        None
+    }
+
+    fn clear(&mut self) {
+        self.ring.clear();
+        self.nodes.clear();
+    }
+}
+
+/// 2Q: new nodes go to a FIFO queue, nodes seen again after their eviction
+/// from it go to the LRU list. One time scans don't push out the hot nodes.
+#[derive(Default)]
+pub struct TwoQPolicy {
+    recent: Recency,   // A1in, FIFO of the nodes used once
+    ghosts: Recency,   // A1out, addresses evicted from recent
+    frequent: Recency, // Am, LRU of the nodes used again
+    capacity: usize,
+}
+
+impl TwoQPolicy {
+    fn recent_size(&self) -> usize {
+        (self.capacity / 4).max(1)
+    }
+
+    fn ghost_size(&self) -> usize {
+        (self.capacity / 2).max(1)
+    }
+}
+
+impl CachePolicy for TwoQPolicy {
+    fn set_capacity(&mut self, capacity: usize) {
+        self.capacity = capacity;
+    }
+
+    fn insert(&mut self, addr: Addr) {
+        if self.ghosts.remove(addr) {
+            self.frequent.push(addr);
+        } else {
+            self.recent.push(addr);
+        }
+    }
+
+    fn access(&mut self, addr: Addr) {
+        // the FIFO order isn't changed by use
+        if self.frequent.contains(addr) {
+            self.frequent.push(addr);
+        }
+    }
+
+    fn remove(&mut self, addr: Addr) {
+        if !self.recent.remove(addr) {
+            self.frequent.remove(addr);
+        }
+    }
+
+    fn victim(&mut self, evictable: &dyn Fn(Addr) -> bool) -> Option<Addr> {
+        if self.recent.len() > self.recent_size() || self.frequent.len() == 0 {
+            if let Some(addr) = self.recent.pop(evictable) {
+                self.ghosts.push(addr);
+                while self.ghosts.len() > self.ghost_size() {
+                    self.ghosts.pop(&|_| true);
+                }
+                return Some(addr);
+            }
+        }
+        self.frequent
+            .pop(evictable)
+            .or_else(|| self.recent.pop(evictable))
+    }
+
+    fn clear(&mut self) {
+        self.recent.clear();
+        self.ghosts.clear();
+        self.frequent.clear();
+    }
+}
+
+/// ARC: balances the nodes used once and the nodes used again,
+/// the target size of the first list adapts to the hits of the evicted addresses.
+#[derive(Default)]
+pub struct ArcPolicy {
+    t1: Recency, // used once recently
+    t2: Recency, // used at least twice recently
+    b1: Recency, // addresses evicted from t1
+    b2: Recency, // addresses evicted from t2
+    target: usize,
+    capacity: usize,
+}
+
+impl CachePolicy for ArcPolicy {
+    fn set_capacity(&mut self, capacity: usize) {
+        self.capacity = capacity;
+        self.target = self.target.min(capacity);
+    }
+
+    fn insert(&mut self, addr: Addr) {
+        if self.b1.remove(addr) {
+            let delta = (self.b2.len() / self.b1.len().max(1)).max(1);
+            self.target = (self.target + delta).min(self.capacity);
+            self.t2.push(addr);
+        } else if self.b2.remove(addr) {
+            let delta = (self.b1.len() / self.b2.len().max(1)).max(1);
+            self.target = self.target.saturating_sub(delta);
+            self.t2.push(addr);
+        } else {
+            self.t1.push(addr);
+            // history is bounded by the capacity for each list and twice it in total
+            if self.t1.len() + self.b1.len() > self.capacity {
+                self.b1.pop(&|_| true);
+            }
+            while self.t1.len() + self.t2.len() + self.b1.len() + self.b2.len() > 2 * self.capacity
+                && self.b2.pop(&|_| true).is_some()
+            {}
+        }
+    }
+
+    fn access(&mut self, addr: Addr) {
+        if self.t1.remove(addr) || self.t2.contains(addr) {
+            self.t2.push(addr);
+        }
+    }
+
+    fn remove(&mut self, addr: Addr) {
+        if !self.t1.remove(addr) {
+            self.t2.remove(addr);
+        }
+    }
+
+    fn victim(&mut self, evictable: &dyn Fn(Addr) -> bool) -> Option<Addr> {
+        let from_t1 = self.t1.len() > 0 && (self.t1.len() > self.target || self.t2.len() == 0);
+        let (first, second) = if from_t1 { (1, 2) } else { (2, 1) };
+        for list in [first, second] {
+            let (resident, ghosts) = if list == 1 {
+                (&mut self.t1, &mut self.b1)
+            } else {
+                (&mut self.t2, &mut self.b2)
+            };
+            if let Some(addr) = resident.pop(evictable) {
+                ghosts.push(addr);
+                return Some(addr);
+            }
+        }
+        None
+    }
+
+    fn clear(&mut self) {
+        self.t1.clear();
+        self.t2.clear();
+        self.b1.clear();
+        self.b2.clear();
+        self.target = 0;
+    }
+}
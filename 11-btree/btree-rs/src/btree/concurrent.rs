@@ -0,0 +1,1048 @@
+use super::{
+    get_max_degree, get_min_degree, lock_file, map_file, max_entry_size, node_overhead, Addr,
+    Block, BtreeError, BtreeHeader, BtreeOptions, Degree, Growth, Journal, JournalRecord, Key,
+    NodeStored, OverflowStored, Slot, StoredOptions, Val, DEFAULT_BLOCK_SIZE, FORMAT_VERSION,
+    HEADER_MAGIC, PAGE_SLOT_SIZE,
+};
+use log::{debug, trace};
+use memmap::MmapMut;
+use parking_lot::{ArcRwLockReadGuard, ArcRwLockWriteGuard, Mutex, RawRwLock, RwLock};
+use std::collections::HashMap;
+use std::fs::{File, OpenOptions};
+use std::mem::size_of;
+use std::ops::{Bound, RangeBounds};
+use std::path::Path as FilePath;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+
+type Latch<K, V> = Arc<RwLock<LatchedNode<K, V>>>;
+type ReadLatch<K, V> = ArcRwLockReadGuard<RawRwLock, LatchedNode<K, V>>;
+type WriteLatch<K, V> = ArcRwLockWriteGuard<RawRwLock, LatchedNode<K, V>>;
+
+/// Btree, which can be shared between threads.
+///
+/// Every node has its own read/write latch, the root address has one more.
+/// Operations go down the tree latch crabbing: a latch is released as soon
+/// as the child is latched and the operation can't split or merge it.
+/// Writers first go down with read latches and write latch the leaf only.
+/// When the leaf has to be split or merged, the operation is restarted with
+/// write latches on the path. Latches are taken top-down and left to right,
+/// so the left sibling is latched before the node it is merged with.
+///
+/// The file has the same format as the one of `Btree`. Values too large for
+/// the leaf are spilled to the overflow blocks under the leaf write latch, the
+/// same way as `Btree` does it. Changed nodes and spilled values are kept in
+/// memory and written by `flush` in one journal record. Flush also runs once
+/// more than `cache_size` blocks are changed.
+///
+/// Of the settings it uses the block size, alpha, cache size and growth, the others
+/// are kept in the header for `Btree`. Every flush is synced to the disk, whatever
+/// the durability is, and the node cache has no replacement policy.
+pub struct ConcurrentBtree<K: Key, V: Val>(Arc<Shared<K, V>>);
+
+struct Shared<K: Key, V: Val> {
+    root: RwLock<Addr>, // latch above the root node
+    gate: RwLock<()>,   // shared by the operations, exclusive for flush
+    nodes: Mutex<HashMap<Addr, Latch<K, V>>>,
+    space: Mutex<Space>,
+    block_size: Block,
+    min_degree: Degree,
+    max_degree: Degree,
+    cache_size: usize,
+    growth: Growth,
+    options: StoredOptions, // written back with the header, overrides are not stored
+    dirty: AtomicUsize,     // number of blocks changed since the last flush
+}
+
+struct LatchedNode<K, V> {
+    addr: Addr,
+    st: NodeStored<K, Slot<V>>,
+    dirty: bool,
+}
+
+// index file and the block allocation state
+struct Space {
+    store: Store,
+    len: u64,                         // file size after the next flush
+    free: Vec<Addr>,                  // free blocks, the last one is the head of the list
+    free_dirty: bool,                 // the list is changed since the last flush
+    overflow: HashMap<Addr, Vec<u8>>, // encoded blocks of the values spilled since the last flush
+}
+
+struct Store {
+    fd: File,
+    mmap: MmapMut,
+    file_len: u64,
+    journal: Journal,
+    _lock: Option<File>, // exclusive lock of the writer, released on drop
+}
+
+// write latched node on the path down, with the child taken from it
+struct Level<K, V> {
+    node: WriteLatch<K, V>,
+    index: usize,
+    // child is merged with or takes entries from the sibling, right one if any
+    sibling: Option<WriteLatch<K, V>>,
+    // leaf after the child and the sibling, its prev changes on merge
+    next: Option<WriteLatch<K, V>>,
+}
+
+impl Store {
+    fn open(path: &FilePath, create: bool) -> Result<Self, BtreeError> {
+        let lock = lock_file(path, true)?;
+        let fd = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(create)
+            .truncate(create)
+            .open(path)?;
+        let file_len = fd.metadata()?.len();
+        let mmap = map_file(&fd, file_len)?;
+        if !create {
+            // don't touch the journal of a foreign file
+            BtreeHeader::check_format(&mmap.as_ref()[..(file_len as usize).min(mmap.len())])?;
+        }
+        let mut store = Store {
+            fd,
+            mmap,
+            file_len,
+            journal: Journal::open(path, create)?,
+            _lock: lock,
+        };
+        // finish the flush interrupted by a crash
+        for record in store.journal.records()? {
+            store.apply(&record)?;
+        }
+        store.journal.truncate()?;
+        Ok(store)
+    }
+
+    fn set_len(&mut self, len: u64) -> Result<(), BtreeError> {
+        self.fd.set_len(len)?;
+        self.file_len = len;
+        if len > self.mmap.len() as u64 {
+            self.mmap = map_file(&self.fd, len)?;
+            debug!("Store:set_len: remapped, len={}", self.mmap.len());
+        }
+        Ok(())
+    }
+
+    fn read(&self, addr: Addr, len: usize) -> Result<&[u8], BtreeError> {
+        let end = addr as u64 + len as u64;
+        if end > self.file_len {
+            return Err(BtreeError::Corrupt(format!("bad node address {}", addr)));
+        }
+        Ok(&self.mmap.as_ref()[(addr as usize)..(end as usize)])
+    }
+
+    fn apply(&mut self, record: &JournalRecord) -> Result<(), BtreeError> {
+        if self.file_len != record.file_len {
+            self.set_len(record.file_len)?;
+        }
+        for (addr, block) in record.blocks.iter() {
+            let addr = *addr as usize;
+            if (addr + block.len()) as u64 > self.file_len {
+                return Err(BtreeError::Corrupt(format!(
+                    "write beyond the file, addr={}",
+                    addr
+                )));
+            }
+            self.mmap.as_mut()[addr..(addr + block.len())].copy_from_slice(block);
+        }
+        self.mmap.flush()?;
+        Ok(())
+    }
+
+    fn commit(&mut self, record: &JournalRecord) -> Result<(), BtreeError> {
+        self.journal.append(record)?;
+        // record is synced, a failed apply is finished on load
+        self.apply(record)?;
+        self.journal.truncate()?;
+        Ok(())
+    }
+}
+
+impl<K: Key, V: Val> ConcurrentBtree<K, V> {
+    /// Creates the tree with the options, they are stored in the header.
+    /// Multimap is not supported, see `open`.
+    pub fn new(path: &FilePath, options: &BtreeOptions) -> Result<Self, BtreeError> {
+        trace!(
+            "ConcurrentBtree:new: path={:?}, options={:?}",
+            path,
+            options
+        );
+        options.check::<K, V>()?;
+        if options.multimap == Some(true) {
+            return Err(BtreeError::HeaderMismatch(
+                "multimap index is not supported".to_string(),
+            ));
+        }
+        let block_size = options.block_size.unwrap_or(DEFAULT_BLOCK_SIZE);
+        let stored = options.to_stored();
+        let max_degree = get_max_degree::<K, V>(block_size);
+        let min_degree = get_min_degree(max_degree, stored.alpha);
+        let header = BtreeHeader {
+            magic: HEADER_MAGIC,
+            version: FORMAT_VERSION,
+            root: block_size, // header goes first, then the root leaf
+            min_degree,
+            max_degree,
+            block_size,
+            free: None,
+            multimap: false,
+            options: stored,
+        };
+        let space = Space {
+            store: Store::open(path, true)?,
+            len: 2 * block_size as u64,
+            free: Vec::new(),
+            free_dirty: false,
+            overflow: HashMap::new(),
+        };
+        let bt = ConcurrentBtree::build(header, space, stored);
+        drop(bt.0.create(block_size, NodeStored::new(true)));
+        bt.flush()?;
+        Ok(bt)
+    }
+}
+
+impl<K: Key, V: Val> ConcurrentBtree<K, V> {
+    /// Opens the tree with the settings it's created with and the overrides
+    /// of `options`, the same way as `Btree::open`. Multimap index is not supported,
+    /// it's `HeaderMismatch`.
+    pub fn open(path: &FilePath, options: &BtreeOptions) -> Result<Self, BtreeError> {
+        trace!(
+            "ConcurrentBtree:open: path={:?}, options={:?}",
+            path,
+            options
+        );
+        let store = Store::open(path, false)?;
+        if store.file_len == 0 {
+            return Err(BtreeError::Corrupt("empty index file".to_string()));
+        }
+        let len = (store.file_len as usize).min(store.mmap.len());
+        let header = BtreeHeader::decode(&store.mmap.as_ref()[..len])?;
+        debug!("ConcurrentBtree:open: header={:?}", header);
+        let resolved = options.resolve(&header)?;
+        if header.multimap {
+            return Err(BtreeError::HeaderMismatch(
+                "multimap index is not supported".to_string(),
+            ));
+        }
+        // free list is kept in memory, the head goes last
+        let mut free = Vec::new();
+        let mut next = header.free;
+        while let Some(addr) = next {
+            if free.len() as u64 > store.file_len / header.block_size as u64 {
+                return Err(BtreeError::Corrupt("free list is looped".to_string()));
+            }
+            let block = store.read(addr, header.block_size as usize)?;
+            next = NodeStored::<K, Slot<V>>::decode(block, addr)?.next;
+            free.push(addr);
+        }
+        free.reverse();
+        let space = Space {
+            len: store.file_len,
+            store,
+            free,
+            free_dirty: false,
+            overflow: HashMap::new(),
+        };
+        Ok(ConcurrentBtree::build(header, space, resolved))
+    }
+
+    fn build(header: BtreeHeader, space: Space, resolved: StoredOptions) -> Self {
+        ConcurrentBtree(Arc::new(Shared {
+            root: RwLock::new(header.root),
+            gate: RwLock::new(()),
+            nodes: Mutex::new(HashMap::new()),
+            space: Mutex::new(space),
+            block_size: header.block_size,
+            min_degree: header.min_degree,
+            max_degree: header.max_degree,
+            cache_size: resolved.cache_size as usize,
+            growth: resolved.growth,
+            options: header.options,
+            dirty: AtomicUsize::new(0),
+        }))
+    }
+
+    pub fn find(&self, key: &K) -> Result<V, BtreeError> {
+        trace!("ConcurrentBtree:find: key={:?}", key);
+        let result = {
+            let _gate = self.0.gate.read();
+            let leaf = self.0.read_leaf(Some(key))?;
+            match leaf.st.keys.binary_search(key) {
+                Ok(pos) => self.0.read_val(&leaf.st.vals[pos]).map(|(val, _)| val),
+                Err(_) => Err(BtreeError::KeyNotFound),
+            }
+        };
+        self.0.maintain()?;
+        result
+    }
+
+    pub fn insert(&self, key: K, val: V) -> Result<(), BtreeError> {
+        trace!("ConcurrentBtree:insert: key={:?}, val={:?}", key, val);
+        // same check as Btree::check_key, large values are spilled
+        let entry_size = bincode::serialized_size(&key)?
+            + Slot::<V>::overflow_size().max(size_of::<Addr>() as u64)
+            + PAGE_SLOT_SIZE;
+        if entry_size > self.0.max_entry_size() {
+            return Err(BtreeError::EntryTooLarge);
+        }
+        {
+            let _gate = self.0.gate.read();
+            if let Some(slot) = self.0.insert_leaf(&key, val)? {
+                self.0.insert_split(key, slot)?;
+            }
+        }
+        self.0.maintain()
+    }
+
+    pub fn remove(&self, key: &K) -> Result<V, BtreeError> {
+        trace!("ConcurrentBtree:remove: key={:?}", key);
+        let val = {
+            let _gate = self.0.gate.read();
+            match self.0.remove_leaf(key)? {
+                Some(val) => val,
+                None => self.0.remove_merge(key)?,
+            }
+        };
+        self.0.maintain()?;
+        Ok(val)
+    }
+
+    /// Key/value pairs in the range, in key order. Leaves are read one by one,
+    /// the next one is latched before the previous one is released.
+    pub fn range<R: RangeBounds<K>>(&self, range: R) -> Result<Vec<(K, V)>, BtreeError> {
+        let mut result = Vec::new();
+        {
+            let _gate = self.0.gate.read();
+            let start = match range.start_bound() {
+                Bound::Included(key) | Bound::Excluded(key) => Some(key),
+                Bound::Unbounded => None,
+            };
+            let mut leaf = self.0.read_leaf(start)?;
+            'leaves: loop {
+                for (key, val) in leaf.st.keys.iter().zip(leaf.st.vals.iter()) {
+                    let after_end = match range.end_bound() {
+                        Bound::Included(end) => key > end,
+                        Bound::Excluded(end) => key >= end,
+                        Bound::Unbounded => false,
+                    };
+                    if after_end {
+                        break 'leaves;
+                    }
+                    if range.contains(key) {
+                        result.push((key.clone(), self.0.read_val(val)?.0));
+                    }
+                }
+                match leaf.st.next {
+                    Some(next) => leaf = self.0.read(next)?,
+                    None => break,
+                }
+            }
+        }
+        self.0.maintain()?;
+        Ok(result)
+    }
+
+    /// Writes all changed nodes to the index file.
+    pub fn flush(&self) -> Result<(), BtreeError> {
+        self.0.flush()
+    }
+}
+
+impl<K: Key, V: Val> Clone for ConcurrentBtree<K, V> {
+    fn clone(&self) -> Self {
+        ConcurrentBtree(Arc::clone(&self.0))
+    }
+}
+
+impl<K: Key, V: Val> Shared<K, V> {
+    fn latch(&self, addr: Addr) -> Result<Latch<K, V>, BtreeError> {
+        let mut nodes = self.nodes.lock();
+        if let Some(latch) = nodes.get(&addr) {
+            return Ok(Arc::clone(latch));
+        }
+        if addr < self.block_size || !addr.is_multiple_of(self.block_size) {
+            return Err(BtreeError::Corrupt(format!("bad node address {}", addr)));
+        }
+        let st = {
+            let space = self.space.lock();
+            NodeStored::decode(space.store.read(addr, self.block_size as usize)?, addr)?
+        };
+        trace!("Shared:latch: loaded addr={}", addr);
+        let latch = Arc::new(RwLock::new(LatchedNode {
+            addr,
+            st,
+            dirty: false,
+        }));
+        nodes.insert(addr, Arc::clone(&latch));
+        Ok(latch)
+    }
+
+    fn read(&self, addr: Addr) -> Result<ReadLatch<K, V>, BtreeError> {
+        Ok(self.latch(addr)?.read_arc())
+    }
+
+    fn write(&self, addr: Addr) -> Result<WriteLatch<K, V>, BtreeError> {
+        Ok(self.latch(addr)?.write_arc())
+    }
+
+    fn create(&self, addr: Addr, st: NodeStored<K, Slot<V>>) -> WriteLatch<K, V> {
+        let latch = Arc::new(RwLock::new(LatchedNode {
+            addr,
+            st,
+            dirty: true,
+        }));
+        self.dirty.fetch_add(1, Ordering::Relaxed);
+        let node = latch.write_arc();
+        self.nodes.lock().insert(addr, latch);
+        node
+    }
+
+    fn touch(&self, node: &mut LatchedNode<K, V>) {
+        if !node.dirty {
+            node.dirty = true;
+            self.dirty.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    fn free(&self, node: &mut LatchedNode<K, V>) {
+        trace!("Shared:free: addr={}", node.addr);
+        self.nodes.lock().remove(&node.addr);
+        if node.dirty {
+            node.dirty = false;
+            self.dirty.fetch_sub(1, Ordering::Relaxed);
+        }
+        let mut space = self.space.lock();
+        space.free.push(node.addr);
+        space.free_dirty = true;
+    }
+
+    fn reserve(&self, count: usize) -> Result<Vec<Addr>, BtreeError> {
+        // blocks for the new nodes are taken before the tree is changed
+        let mut space = self.space.lock();
+        let block_size = self.block_size as u64;
+        let reused = count.min(space.free.len());
+        let start = space.free.len() - reused;
+        let mut addrs = space.free.split_off(start);
+        let missing = (count - reused) as u64;
+        if missing == 0 {
+            return Ok(addrs);
+        }
+        // blocks are addressed by u32 offsets
+        let limit = (Addr::MAX as u64 + 1).saturating_sub(space.len) / block_size;
+        if missing > limit {
+            space.free.append(&mut addrs);
+            return Err(BtreeError::FileFull);
+        }
+        // file grows by the growth setting, the extra blocks go to the free list
+        let grow = match self.growth {
+            Growth::Blocks(count) => count as u64,
+            Growth::Percent(percent) => space.len / block_size * percent as u64 / 100,
+        };
+        let grow = grow.clamp(missing, limit);
+        let start = space.len;
+        addrs.extend((0..missing).map(|i| (start + i * block_size) as Addr));
+        if grow > missing {
+            let extra = (missing..grow)
+                .rev()
+                .map(|i| (start + i * block_size) as Addr);
+            space.free.extend(extra);
+            space.free_dirty = true;
+        }
+        space.len = start + grow * block_size;
+        trace!("Shared:reserve: grown by {} blocks", grow);
+        Ok(addrs)
+    }
+
+    fn release(&self, mut addrs: Vec<Addr>) {
+        if !addrs.is_empty() {
+            let mut space = self.space.lock();
+            // spilled values which are not flushed yet are not written
+            let pending = addrs
+                .iter()
+                .filter(|addr| space.overflow.remove(addr).is_some())
+                .count();
+            self.dirty.fetch_sub(pending, Ordering::Relaxed);
+            space.free.append(&mut addrs);
+            space.free_dirty = true;
+        }
+    }
+
+    // slot of the value, it's spilled to the overflow blocks if the entry doesn't fit to the leaf
+    fn spill(&self, key: &K, val: V) -> Result<Slot<V>, BtreeError> {
+        let size = bincode::serialized_size(key)? + bincode::serialized_size(&Slot::Inline(&val))?;
+        if size + PAGE_SLOT_SIZE <= self.max_entry_size() {
+            return Ok(Slot::Inline(val));
+        }
+        let data = bincode::serialize(&val)?;
+        let addrs = self.reserve(OverflowStored::blocks(data.len(), self.block_size))?;
+        let blocks = match OverflowStored::chain(&data, &addrs, self.block_size) {
+            Ok(blocks) => blocks,
+            Err(err) => {
+                self.release(addrs);
+                return Err(err);
+            }
+        };
+        trace!("Shared:spill: key={:?}, blocks={:?}", key, addrs);
+        self.dirty.fetch_add(addrs.len(), Ordering::Relaxed);
+        self.space
+            .lock()
+            .overflow
+            .extend(addrs.iter().copied().zip(blocks));
+        Ok(Slot::Overflow {
+            head: addrs[0],
+            len: data.len() as u64,
+        })
+    }
+
+    // frees the overflow blocks of the slot, which is not stored in the tree
+    fn drop_slot(&self, slot: &Slot<V>) {
+        if let Ok((_, blocks)) = self.read_val(slot) {
+            self.release(blocks);
+        }
+    }
+
+    // value of the slot and the overflow blocks it takes
+    fn read_val(&self, slot: &Slot<V>) -> Result<(V, Vec<Addr>), BtreeError> {
+        let (head, len) = match slot {
+            Slot::Inline(val) => return Ok((val.clone(), Vec::new())),
+            Slot::Overflow { head, len } => (*head, *len),
+        };
+        let space = self.space.lock();
+        let mut blocks = Vec::new();
+        let val = OverflowStored::read_value(head, len, |addr| {
+            blocks.push(addr);
+            match space.overflow.get(&addr) {
+                Some(block) => OverflowStored::decode(block, addr),
+                None => {
+                    OverflowStored::decode(space.store.read(addr, self.block_size as usize)?, addr)
+                }
+            }
+        })?;
+        Ok((val, blocks))
+    }
+
+    fn max_entry_size(&self) -> u64 {
+        max_entry_size(self.block_size, node_overhead::<K, V>())
+    }
+
+    fn has_room(&self, st: &NodeStored<K, Slot<V>>, key: &K, item_size: u64) -> bool {
+        if st.keys.len() as Degree >= self.max_degree {
+            return false;
+        }
+        let mut page = st.page();
+        page.add(key, item_size);
+        page.size() <= self.block_size as u64
+    }
+
+    fn is_overflow(&self, st: &NodeStored<K, Slot<V>>) -> bool {
+        st.keys.len() as Degree > self.max_degree || st.encoded_size() > self.block_size as u64
+    }
+
+    // same bounds as Node::is_drained
+    fn is_drained(&self, st: &NodeStored<K, Slot<V>>) -> bool {
+        let degree = st.keys.len() as Degree;
+        if st.leaf {
+            degree < self.min_degree
+        } else {
+            degree <= self.min_degree
+        }
+    }
+
+    // node stays above the bounds after one entry is removed
+    fn can_lose(&self, st: &NodeStored<K, Slot<V>>, is_root: bool) -> bool {
+        let degree = st.keys.len() as Degree;
+        match (is_root, st.leaf) {
+            (true, true) => true,
+            (true, false) => degree > 2,
+            (false, true) => degree > self.min_degree,
+            (false, false) => degree > self.min_degree + 1,
+        }
+    }
+
+    fn can_merge(&self, left: &NodeStored<K, Slot<V>>, right: &NodeStored<K, Slot<V>>) -> bool {
+        if (left.keys.len() + right.keys.len()) as Degree > self.max_degree {
+            return false;
+        }
+        let mut page = left.page();
+        page.merge(&right.page());
+        page.size() <= self.block_size as u64
+    }
+
+    fn read_leaf(&self, key: Option<&K>) -> Result<ReadLatch<K, V>, BtreeError> {
+        // leftmost leaf without the key
+        let root = self.root.read();
+        let mut node = self.read(*root)?;
+        drop(root);
+        while !node.st.leaf {
+            let child = self.read(node.st.refs[child_index(&node.st, key)])?;
+            // parent is released after the child is latched
+            node = child;
+        }
+        Ok(node)
+    }
+
+    fn write_leaf(&self, key: &K) -> Result<(WriteLatch<K, V>, bool), BtreeError> {
+        // read latches on the way down, write latch on the leaf.
+        // The leaf stays in place while its parent is latched.
+        let root = self.root.read();
+        let node = self.read(*root)?;
+        if node.st.leaf {
+            let addr = node.addr;
+            drop(node);
+            return Ok((self.write(addr)?, true));
+        }
+        drop(root);
+        let mut node = node;
+        loop {
+            let addr = node.st.refs[child_index(&node.st, Some(key))];
+            let child = self.read(addr)?;
+            if child.st.leaf {
+                drop(child);
+                return Ok((self.write(addr)?, false));
+            }
+            node = child;
+        }
+    }
+
+    // Inserts the entry, if the leaf has room for it. Otherwise returns the slot
+    // of the value to insert with the split, it could be spilled already.
+    fn insert_leaf(&self, key: &K, val: V) -> Result<Option<Slot<V>>, BtreeError> {
+        let (mut leaf, _) = self.write_leaf(key)?;
+        let pos = match leaf.st.keys.binary_search(key) {
+            Ok(_) => return Err(BtreeError::DuplicateKey),
+            Err(pos) => pos,
+        };
+        let slot = self.spill(key, val)?;
+        if !self.has_room(&leaf.st, key, self.item_size(key, &slot)?) {
+            return Ok(Some(slot));
+        }
+        leaf.st.keys.insert(pos, key.clone());
+        leaf.st.vals.insert(pos, slot);
+        self.touch(&mut leaf);
+        Ok(None)
+    }
+
+    fn insert_split(&self, key: K, slot: Slot<V>) -> Result<(), BtreeError> {
+        trace!("Shared:insert_split: key={:?}", key);
+        // the spilled value is freed, if the entry is not inserted
+        let spilled = match &slot {
+            Slot::Overflow { head, len } => Some(Slot::Overflow {
+                head: *head,
+                len: *len,
+            }),
+            Slot::Inline(_) => None,
+        };
+        let result = self
+            .item_size(&key, &slot)
+            .and_then(|item_size| self.insert_slot(key, slot, item_size));
+        if let (Err(_), Some(slot)) = (&result, spilled) {
+            self.drop_slot(&slot);
+        }
+        result
+    }
+
+    fn item_size(&self, key: &K, slot: &Slot<V>) -> Result<u64, BtreeError> {
+        Ok(bincode::serialized_size(key)? + bincode::serialized_size(slot)? + PAGE_SLOT_SIZE)
+    }
+
+    fn insert_slot(&self, key: K, slot: Slot<V>, item_size: u64) -> Result<(), BtreeError> {
+        let max_entry_size = self.max_entry_size();
+        let mut root = Some(self.root.write());
+        let mut node = self.write(**root.as_ref().unwrap())?;
+        let mut path: Vec<(WriteLatch<K, V>, usize)> = Vec::new();
+        // ancestors of the node which can take one more entry are released
+        let room = |st: &NodeStored<K, Slot<V>>| {
+            self.has_room(st, &key, if st.leaf { item_size } else { max_entry_size })
+        };
+        if room(&node.st) {
+            root = None;
+        }
+        while !node.st.leaf {
+            let index = child_index(&node.st, Some(&key));
+            let child = self.write(node.st.refs[index])?;
+            path.push((node, index));
+            if room(&child.st) {
+                root = None;
+                path.clear();
+            }
+            node = child;
+        }
+        let pos = match node.st.keys.binary_search(&key) {
+            Ok(_) => return Err(BtreeError::DuplicateKey),
+            Err(pos) => pos,
+        };
+        let mut next = None;
+        let mut reserved = Vec::new();
+        if !room(&node.st) {
+            if let Some(addr) = node.st.next {
+                next = Some(self.write(addr)?);
+            }
+            reserved = self.reserve(path.len() + 1 + root.is_some() as usize)?;
+        }
+
+        // nothing fails from here on
+        node.st.keys.insert(pos, key);
+        node.st.vals.insert(pos, slot);
+        self.touch(&mut node);
+        while self.is_overflow(&node.st) {
+            let mut right = self.split(&mut node, reserved.pop().unwrap());
+            if node.st.leaf {
+                right.st.next = node.st.next;
+                right.st.prev = Some(node.addr);
+                node.st.next = Some(right.addr);
+                if let Some(next) = next.as_mut() {
+                    next.st.prev = Some(right.addr);
+                    self.touch(next);
+                }
+            }
+            let separator = right.st.keys[0].clone();
+            match path.pop() {
+                Some((mut parent, index)) => {
+                    parent.st.keys.insert(index + 1, separator);
+                    parent.st.refs.insert(index + 1, right.addr);
+                    self.touch(&mut parent);
+                    node = parent;
+                }
+                None => {
+                    // root is split, its latch is held as the root had no room
+                    let root = root.as_mut().unwrap();
+                    let mut st = NodeStored::new(false);
+                    st.keys = vec![node.st.keys[0].clone(), separator];
+                    st.refs = vec![node.addr, right.addr];
+                    let new_root = self.create(reserved.pop().unwrap(), st);
+                    debug!("Shared:insert_split: new root={}", new_root.addr);
+                    **root = new_root.addr;
+                    break;
+                }
+            }
+        }
+        self.release(reserved);
+        Ok(())
+    }
+
+    fn split(&self, node: &mut LatchedNode<K, V>, addr: Addr) -> WriteLatch<K, V> {
+        let middle = split_middle(&node.st);
+        let mut st = NodeStored::new(node.st.leaf);
+        st.keys = node.st.keys.split_off(middle);
+        if node.st.leaf {
+            st.vals = node.st.vals.split_off(middle);
+        } else {
+            st.refs = node.st.refs.split_off(middle);
+        }
+        self.touch(node);
+        trace!("Shared:split: addr={}, sibling={}", node.addr, addr);
+        self.create(addr, st)
+    }
+
+    fn remove_leaf(&self, key: &K) -> Result<Option<V>, BtreeError> {
+        // the first key could be a separator in the parents
+        let (mut leaf, is_root) = self.write_leaf(key)?;
+        let pos = match leaf.st.keys.binary_search(key) {
+            Ok(pos) => pos,
+            Err(_) => return Err(BtreeError::KeyNotFound),
+        };
+        if !self.can_lose(&leaf.st, is_root) || (pos == 0 && !is_root) {
+            return Ok(None);
+        }
+        let (val, blocks) = self.read_val(&leaf.st.vals[pos])?;
+        leaf.st.keys.remove(pos);
+        leaf.st.vals.remove(pos);
+        self.touch(&mut leaf);
+        self.release(blocks);
+        Ok(Some(val))
+    }
+
+    fn remove_merge(&self, key: &K) -> Result<V, BtreeError> {
+        trace!("Shared:remove_merge: key={:?}", key);
+        let mut root = Some(self.root.write());
+        let mut node = self.write(**root.as_ref().unwrap())?;
+        let mut path: Vec<Level<K, V>> = Vec::new();
+        // the key is a separator in a latched node, it's replaced by the next one
+        let mut separator = false;
+        if self.can_lose(&node.st, true) {
+            root = None;
+        }
+        while !node.st.leaf {
+            let index = child_index(&node.st, Some(key));
+            separator |= index > 0 && node.st.keys[index] == *key;
+            let addr = node.st.refs[index];
+            let mut child = self.write(addr)?;
+            let safe = self.can_lose(&child.st, false);
+            let mut level = Level {
+                node,
+                index,
+                sibling: None,
+                next: None,
+            };
+            if !safe {
+                let refs = &level.node.st.refs;
+                if index + 1 < refs.len() {
+                    level.sibling = Some(self.write(refs[index + 1])?);
+                } else if index > 0 {
+                    // latches are taken left to right
+                    drop(child);
+                    level.sibling = Some(self.write(refs[index - 1])?);
+                    child = self.write(addr)?;
+                }
+                // leaf after the pair, it's the last one to latch
+                let next = match &level.sibling {
+                    Some(sibling) if index + 1 < refs.len() => sibling.st.next,
+                    _ => child.st.next,
+                };
+                if let Some(next) = next.filter(|_| child.st.leaf) {
+                    level.next = Some(self.write(next)?);
+                }
+            }
+            path.push(level);
+            if safe && !separator {
+                root = None;
+                path.clear();
+            }
+            node = child;
+        }
+        let pos = match node.st.keys.binary_search(key) {
+            Ok(pos) => pos,
+            Err(_) => return Err(BtreeError::KeyNotFound),
+        };
+        let (val, blocks) = self.read_val(&node.st.vals[pos])?;
+
+        // nothing fails from here on
+        node.st.keys.remove(pos);
+        node.st.vals.remove(pos);
+        self.touch(&mut node);
+        self.release(blocks);
+        let mut leaf = true;
+        loop {
+            let mut level = match path.pop() {
+                Some(level) => level,
+                None => {
+                    // only the root is left without the parent, it has one child
+                    if let Some(root) = root.as_mut() {
+                        if **root == node.addr && !node.st.leaf && node.st.refs.len() == 1 {
+                            **root = node.st.refs[0];
+                            debug!("Shared:remove_merge: new root={}", **root);
+                            self.free(&mut node);
+                        }
+                    }
+                    break;
+                }
+            };
+            let mut alive = true;
+            if self.is_drained(&node.st) {
+                alive = self.rebalance(&mut level, &mut node);
+            }
+            if leaf && pos == 0 && alive && !node.st.keys.is_empty() {
+                let min = node.st.keys[0].clone();
+                let parents = std::iter::once(&mut level.node)
+                    .chain(path.iter_mut().map(|level| &mut level.node));
+                for parent in parents {
+                    if let Some(found) = parent.st.keys.iter_mut().find(|k| *k == key) {
+                        *found = min.clone();
+                        self.touch(parent);
+                    }
+                }
+            }
+            leaf = false;
+            node = level.node;
+        }
+        Ok(val)
+    }
+
+    // Merges the drained child with the sibling or moves entries from the sibling.
+    // Returns false when the child is merged to the left sibling and freed.
+    fn rebalance(&self, level: &mut Level<K, V>, child: &mut WriteLatch<K, V>) -> bool {
+        let child_is_left = level.index + 1 < level.node.st.refs.len();
+        let sibling = match level.sibling.as_mut() {
+            Some(sibling) => sibling,
+            None => return true,
+        };
+        let (left, right, left_index) = if child_is_left {
+            (&mut **child, &mut **sibling, level.index)
+        } else {
+            (&mut **sibling, &mut **child, level.index - 1)
+        };
+        let parent = &mut level.node;
+        self.touch(parent);
+        self.touch(left);
+        self.touch(right);
+        if self.can_merge(&left.st, &right.st) {
+            trace!("Shared:rebalance: merge {} to {}", right.addr, left.addr);
+            left.st.keys.append(&mut right.st.keys);
+            left.st.vals.append(&mut right.st.vals);
+            left.st.refs.append(&mut right.st.refs);
+            if left.st.leaf {
+                left.st.next = right.st.next;
+                if let Some(next) = level.next.as_mut() {
+                    next.st.prev = Some(left.addr);
+                    self.touch(next);
+                }
+            }
+            parent.st.keys.remove(left_index + 1);
+            parent.st.refs.remove(left_index + 1);
+            self.free(right);
+            return child_is_left;
+        }
+
+        // take half of the difference, as much as fits to the child
+        let (degree, other) = (child.st.keys.len(), sibling.st.keys.len());
+        let mut count = ((degree + other) / 2).saturating_sub(degree);
+        let child_page = child.st.page();
+        while count > 0 && {
+            let (start, stop) = if child_is_left {
+                (0, count)
+            } else {
+                (other - count, other)
+            };
+            let mut page = child_page.clone();
+            page.merge(&sibling.st.page_part(start, stop));
+            page.size() > self.block_size as u64
+        } {
+            count -= 1;
+        }
+        trace!("Shared:rebalance: take {} from {}", count, sibling.addr);
+        let (left, right) = if child_is_left {
+            (&mut **child, &mut **sibling)
+        } else {
+            (&mut **sibling, &mut **child)
+        };
+        if child_is_left {
+            left.st.keys.extend(right.st.keys.drain(..count));
+            if left.st.leaf {
+                left.st.vals.extend(right.st.vals.drain(..count));
+            } else {
+                left.st.refs.extend(right.st.refs.drain(..count));
+            }
+        } else {
+            let start = other - count;
+            let keys: Vec<K> = left.st.keys.drain(start..).collect();
+            right.st.keys.splice(0..0, keys);
+            if right.st.leaf {
+                let vals: Vec<Slot<V>> = left.st.vals.drain(start..).collect();
+                right.st.vals.splice(0..0, vals);
+            } else {
+                let refs: Vec<Addr> = left.st.refs.drain(start..).collect();
+                right.st.refs.splice(0..0, refs);
+            }
+        }
+        parent.st.keys[left_index + 1] = right.st.keys[0].clone();
+        true
+    }
+
+    fn maintain(&self) -> Result<(), BtreeError> {
+        // flush changed nodes and drop the clean ones over the cache size
+        if self.dirty.load(Ordering::Relaxed) > self.cache_size {
+            return self.flush();
+        }
+        if self.nodes.lock().len() > self.cache_size {
+            let _gate = self.gate.write();
+            self.evict();
+        }
+        Ok(())
+    }
+
+    fn evict(&self) {
+        let mut nodes = self.nodes.lock();
+        let extra = nodes.len().saturating_sub(self.cache_size);
+        let clean: Vec<Addr> = nodes
+            .iter()
+            .filter(|(_, latch)| !latch.read().dirty)
+            .map(|(addr, _)| *addr)
+            .take(extra)
+            .collect();
+        for addr in clean {
+            nodes.remove(&addr);
+        }
+    }
+
+    fn flush(&self) -> Result<(), BtreeError> {
+        // operations are finished, the tree is consistent
+        let _gate = self.gate.write();
+        let root = *self.root.read();
+        let mut blocks = Vec::new();
+        let mut written = Vec::new();
+        // spilled values go with the nodes referring to them
+        for latch in self.nodes.lock().values() {
+            let node = latch.read();
+            if node.dirty {
+                blocks.push((node.addr, node.st.encode()?));
+                written.push(Arc::clone(latch));
+            }
+        }
+        let mut space = self.space.lock();
+        let space = &mut *space;
+        let header = BtreeHeader {
+            magic: HEADER_MAGIC,
+            version: FORMAT_VERSION,
+            root,
+            min_degree: self.min_degree,
+            max_degree: self.max_degree,
+            block_size: self.block_size,
+            free: space.free.last().copied(),
+            multimap: false,
+            options: self.options,
+        };
+        blocks.push((0, header.encode()?));
+        blocks.extend(
+            space
+                .overflow
+                .iter()
+                .map(|(addr, block)| (*addr, block.clone())),
+        );
+        if space.free_dirty {
+            // free blocks are empty leaves linked by the next field
+            for (i, addr) in space.free.iter().enumerate() {
+                let mut st = NodeStored::<K, Slot<V>>::new(true);
+                st.next = i.checked_sub(1).map(|prev| space.free[prev]);
+                blocks.push((*addr, st.encode()?));
+            }
+        }
+        debug!("Shared:flush: blocks={}", blocks.len());
+        space.store.commit(&JournalRecord {
+            file_len: space.len,
+            blocks,
+        })?;
+        for latch in written {
+            latch.write().dirty = false;
+        }
+        space.overflow.clear();
+        space.free_dirty = false;
+        self.dirty.store(0, Ordering::Relaxed);
+        self.evict();
+        Ok(())
+    }
+}
+
+fn child_index<K: Ord, V>(st: &NodeStored<K, V>, key: Option<&K>) -> usize {
+    // the first key of the internal node is not a separator, see Node::find
+    match key {
+        Some(key) => match st.keys[1..].binary_search(key) {
+            Ok(idx) => idx + 1,
+            Err(idx) => idx,
+        },
+        None => 0,
+    }
+}
+
+fn split_middle<K: Key, V: Val>(st: &NodeStored<K, V>) -> usize {
+    // number of entries left in the node, by count for fixed size entries
+    let sizes = st.item_sizes();
+    if sizes.iter().all(|size| *size == sizes[0]) {
+        return sizes.len() / 2;
+    }
+    let total: u64 = sizes.iter().sum();
+    let mut acc = 0;
+    let mut middle = 0;
+    for size in sizes.iter() {
+        if acc + size > total / 2 {
+            break;
+        }
+        acc += size;
+        middle += 1;
+    }
+    middle.clamp(1, sizes.len() - 1)
+}
@@ -0,0 +1,223 @@
+use super::{
+    Addr, Btree, BtreeError, Key, LeafSearch, NodeStored, NodeView, OverflowStored, Slot, Val,
+};
+use log::{error, trace};
+use std::ops::{Bound, RangeBounds};
+use std::rc::Rc;
+
+/// Read-only view of `Btree` pinned to the root it had when created.
+///
+/// While the snapshot is alive, the tree doesn't change the blocks it reads:
+/// changed nodes are copied to free blocks with their parents up to the root,
+/// see `Btree::snapshot`. Only the next/prev links of the leaves are changed in
+/// place, so the snapshot goes to the next leaf through the parents. Blocks the tree
+/// frees meanwhile are kept until the snapshot is dropped, then they go to the free list.
+pub struct Snapshot<K: Key, V: Val> {
+    bt: Btree<K, V>,
+    root: Addr,
+    pin: Option<Rc<u64>>, // epoch of the snapshot, the tree keeps the blocks while it's alive
+}
+
+/// Iterator over the key/value pairs of the snapshot, in key order.
+pub struct SnapshotIter<'a, K: Key, V: Val> {
+    snapshot: &'a Snapshot<K, V>,
+    path: Vec<(Addr, usize)>, // internal nodes down to the leaf, with the child taken
+    leaf: Option<(NodeStored<K, Slot<V>>, usize)>,
+    end: Bound<K>,
+    error: Option<BtreeError>, // yielded once, ends the iteration
+}
+
+impl<K: Key, V: Val> Snapshot<K, V> {
+    pub(super) fn new(bt: Btree<K, V>, root: Addr, pin: Rc<u64>) -> Self {
+        Snapshot {
+            bt,
+            root,
+            pin: Some(pin),
+        }
+    }
+
+    fn read_block<T>(
+        &self,
+        addr: Addr,
+        decode: impl FnOnce(&[u8], Addr) -> Result<T, BtreeError>,
+    ) -> Result<T, BtreeError> {
+        let bti = self.bt.0.borrow();
+        let block_size = bti.header.block_size;
+        let end = addr as u64 + block_size as u64;
+        if addr < block_size || !addr.is_multiple_of(block_size) || end > bti.file_len {
+            return Err(BtreeError::Corrupt(format!("bad block address {}", addr)));
+        }
+        decode(bti.mmap.read(addr as usize, end as usize), addr)
+    }
+
+    fn get_node(&self, addr: Addr) -> Result<NodeStored<K, Slot<V>>, BtreeError> {
+        trace!("Snapshot:get_node: addr={}", addr);
+        self.bt.count(|counters| &counters.node_decodes);
+        self.read_block(addr, NodeStored::decode)
+    }
+
+    fn read_val(&self, slot: &Slot<V>) -> Result<V, BtreeError> {
+        match slot {
+            Slot::Inline(val) => Ok(val.clone()),
+            Slot::Overflow { head, len } => OverflowStored::read_value(*head, *len, |addr| {
+                self.read_block(addr, OverflowStored::decode)
+            }),
+        }
+    }
+
+    fn find_leaf(
+        &self,
+        mut addr: Addr,
+        start: Bound<&K>,
+        path: &mut Vec<(Addr, usize)>,
+    ) -> Result<Addr, BtreeError> {
+        // leftmost leaf of the subtree, where the start of the range could be.
+        // Internal nodes are searched in place, see Btree::descend
+        loop {
+            let child = self.read_block(addr, |buf, addr| {
+                let view = NodeView::<K, Slot<V>>::new(buf, addr)?;
+                if view.leaf {
+                    return Ok(None);
+                }
+                // the lower bound finds the first of the equal keys in multimap
+                let idx = match start {
+                    Bound::Included(key) => view.bound(key, false)?,
+                    Bound::Excluded(key) => view.bound(key, true)?,
+                    Bound::Unbounded => 0,
+                };
+                Ok(Some((idx, view.child(idx)?)))
+            })?;
+            match child {
+                Some((idx, child)) => {
+                    path.push((addr, idx));
+                    addr = child;
+                }
+                None => return Ok(addr),
+            }
+        }
+    }
+
+    fn next_leaf(&self, path: &mut Vec<(Addr, usize)>) -> Result<Option<Addr>, BtreeError> {
+        // through the lowest node with the child after the one taken, see Btree::next_leaf
+        while let Some((addr, idx)) = path.pop() {
+            let child = self.read_block(addr, |buf, addr| {
+                let view = NodeView::<K, Slot<V>>::new(buf, addr)?;
+                if idx + 1 < view.count {
+                    return view.child(idx + 1).map(Some);
+                }
+                Ok(None)
+            })?;
+            if let Some(child) = child {
+                path.push((addr, idx + 1));
+                return self.find_leaf(child, Bound::Unbounded, path).map(Some);
+            }
+        }
+        Ok(None)
+    }
+
+    pub fn find(&self, key: &K) -> Result<V, BtreeError> {
+        trace!("Snapshot:find: key={:?}", key);
+        // leaves are searched in place, see Btree::find. The key could start
+        // at the end of the found leaf, it's the leftmost one
+        let mut path = Vec::new();
+        let mut addr = self.find_leaf(self.root, Bound::Included(key), &mut path)?;
+        loop {
+            let found = self.read_block(addr, |buf, addr| {
+                NodeView::<K, Slot<V>>::new(buf, addr)?.search(key)
+            })?;
+            match found {
+                LeafSearch::Found(slot) => return self.read_val(&slot),
+                LeafSearch::Next(_) => match self.next_leaf(&mut path)? {
+                    Some(next) => addr = next,
+                    None => return Err(BtreeError::KeyNotFound),
+                },
+                LeafSearch::Missing => return Err(BtreeError::KeyNotFound),
+            }
+        }
+    }
+
+    /// Returns iterator over the pairs with keys in the range.
+    /// Leaves are loaded lazily, one by one.
+    pub fn range<R: RangeBounds<K>>(&self, range: R) -> SnapshotIter<'_, K, V> {
+        let mut iter = SnapshotIter {
+            snapshot: self,
+            path: Vec::new(),
+            leaf: None,
+            end: range.end_bound().cloned(),
+            error: None,
+        };
+        match self
+            .find_leaf(self.root, range.start_bound(), &mut iter.path)
+            .and_then(|addr| self.get_node(addr))
+        {
+            Ok(leaf) => {
+                let idx = match range.start_bound() {
+                    Bound::Included(key) => leaf.keys.partition_point(|k| k < key),
+                    Bound::Excluded(key) => leaf.keys.partition_point(|k| k <= key),
+                    Bound::Unbounded => 0,
+                };
+                iter.leaf = Some((leaf, idx));
+            }
+            Err(err) => iter.error = Some(err),
+        }
+        iter
+    }
+
+    pub fn iter(&self) -> SnapshotIter<'_, K, V> {
+        self.range(..)
+    }
+}
+
+impl<K: Key, V: Val> Drop for Snapshot<K, V> {
+    fn drop(&mut self) {
+        // blocks kept for this snapshot only go to the free list
+        self.pin = None;
+        if let Err(err) = self.bt.release_retired() {
+            error!("Snapshot:drop: blocks are not freed, err={}", err);
+        }
+    }
+}
+
+impl<'a, K: Key, V: Val> Iterator for SnapshotIter<'a, K, V> {
+    type Item = Result<(K, V), BtreeError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if let Some(err) = self.error.take() {
+            return Some(Err(err));
+        }
+        loop {
+            let (leaf, idx) = self.leaf.as_mut()?;
+            if *idx < leaf.keys.len() {
+                let key = &leaf.keys[*idx];
+                let after_end = match &self.end {
+                    Bound::Included(end) => key > end,
+                    Bound::Excluded(end) => key >= end,
+                    Bound::Unbounded => false,
+                };
+                if after_end {
+                    self.leaf = None;
+                    return None;
+                }
+                let item = self
+                    .snapshot
+                    .read_val(&leaf.vals[*idx])
+                    .map(|val| (key.clone(), val));
+                // the iteration ends after an error
+                match item {
+                    Ok(_) => *idx += 1,
+                    Err(_) => self.leaf = None,
+                }
+                return Some(item);
+            }
+            self.leaf = None;
+            let next = self
+                .snapshot
+                .next_leaf(&mut self.path)
+                .and_then(|next| next.map(|addr| self.snapshot.get_node(addr)).transpose());
+            match next {
+                Ok(next) => self.leaf = Some((next?, 0)),
+                Err(err) => return Some(Err(err)),
+            }
+        }
+    }
+}
@@ -1,72 +1,514 @@
 pub mod btree {
     #![allow(dead_code)]
     extern crate bincode;
+    extern crate crc32fast;
+    extern crate fs2;
     extern crate log;
-    extern crate lru_cache;
     extern crate memmap;
+    extern crate parking_lot;
     extern crate serde;
-
-    use log::{debug, info, trace};
-    use lru_cache::LruCache;
-    use memmap::{MmapMut, MmapOptions};
+    extern crate serde_json;
+
+    mod cache;
+    mod concurrent;
+    mod snapshot;
+    pub use self::cache::{
+        ArcPolicy, CacheKind, CachePolicy, CacheStats, ClockPolicy, LruPolicy, TwoQPolicy,
+    };
+    pub use self::concurrent::ConcurrentBtree;
+    pub use self::snapshot::{Snapshot, SnapshotIter};
+
+    use fs2::FileExt;
+    use log::{debug, error, info, trace};
+    use memmap::{Mmap, MmapMut, MmapOptions};
+    use serde::de::DeserializeOwned;
     use serde::{Deserialize, Serialize};
-    use std::cell::RefCell;
+    use std::cell::{Cell, RefCell};
     use std::clone::Clone;
-    use std::collections::VecDeque;
-    use std::fmt::Debug;
+    use std::cmp::Ordering;
+    use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet, VecDeque};
     use std::fmt::Write as FmtWrite;
+    use std::fmt::{Debug, Display};
     use std::fs::File;
     use std::fs::OpenOptions;
-    use std::io::Write as IoWrite;
+    use std::io::{self, BufWriter, Read, Seek, SeekFrom, Write};
     use std::mem::size_of;
-    use std::path::Path as FilePath;
-    use std::rc::Rc;
+    use std::ops::{Bound, RangeBounds};
+    use std::path::{Path as FilePath, PathBuf};
+    use std::rc::{Rc, Weak};
+    use std::time::{Duration, Instant};
 
     type Block = u32;
     type Degree = u32;
-    type Key = u32;
-    type Val = u32;
     type Addr = u32;
-    type NodeCache = Rc<RefCell<LruCache<Addr, Node>>>;
+    type NodeCache<K, V> = Rc<RefCell<cache::NodeCache<Node<K, V>>>>;
+    type SplitResult<K, V> = (Option<Addr>, Node<K, V>, IdxSide); // new root, sibling, insert position
+    type LeafRef<K, V> = (Node<K, V>, PathRef<K, V>);
+    type LeafEntry<K, V> = (LeafRef<K, V>, usize);
+
+    const HEADER_MAGIC: u32 = 0x4254_5245;
+    const FORMAT_VERSION: u32 = 7;
+    // crc32 stored after the encoded header and every encoded node
+    const CHECKSUM_SIZE: u64 = 4;
+    // node block is a slotted page: flags u8, entry count u32, next u32, prev u32
+    // (0 when unset, header block is never a node), end of the cells u32, key prefix
+    // length u32, then the key prefix, key and value offset u32 per entry, and the cells
+    // with the encoded keys and values (child address for an internal node).
+    // crc32 follows the cells.
+    const PAGE_HEADER_SIZE: u64 = 21;
+    const PAGE_SLOT_SIZE: u64 = 8;
+    const PAGE_LEAF: u8 = 1;
+    // keys of the leaf are byte strings (String, Vec<u8>), stored without
+    // the encoded length and with the common prefix cut off, see PageSize
+    const PAGE_PREFIXED: u8 = 2;
+    // bincode length of the byte string
+    const STRING_LEN_SIZE: u64 = 8;
+    // the file is mapped with a margin, the mapping is doubled when the file outgrows it
+    const MIN_MAP_SIZE: u64 = 1 << 20;
+    const JOURNAL_MAGIC: u32 = 0x4254_4a52;
+    // journal is dropped after the cache flush, once it grows over this size
+    const JOURNAL_CHECKPOINT_SIZE: u64 = 4 << 20;
+    const DEFAULT_BLOCK_SIZE: Block = 4096;
+    const DEFAULT_ALPHA: u8 = 2;
+    const DEFAULT_CACHE_SIZE: usize = 100;
+
+    /// Requirements for the btree keys: ordered and encodable with bincode.
+    pub trait Key: Serialize + DeserializeOwned + Ord + Clone + Debug {}
+    impl<T: Serialize + DeserializeOwned + Ord + Clone + Debug> Key for T {}
+
+    /// Requirements for the btree values: encodable with bincode.
+    pub trait Val: Serialize + DeserializeOwned + Clone + Debug {}
+    impl<T: Serialize + DeserializeOwned + Clone + Debug> Val for T {}
+
+    /// Prefix of the keys, see `Btree::prefix`. Keys starting with the prefix
+    /// are Equal to it, the others are Less or Greater as they go before or after
+    /// all of them in the key order. Composite keys are prefixed by the first fields.
+    pub trait KeyPrefix<P: ?Sized> {
+        fn cmp_prefix(&self, prefix: &P) -> Ordering;
+    }
+
+    impl KeyPrefix<str> for String {
+        fn cmp_prefix(&self, prefix: &str) -> Ordering {
+            if self.starts_with(prefix) {
+                return Ordering::Equal;
+            }
+            self.as_str().cmp(prefix)
+        }
+    }
+
+    impl KeyPrefix<[u8]> for Vec<u8> {
+        fn cmp_prefix(&self, prefix: &[u8]) -> Ordering {
+            if self.starts_with(prefix) {
+                return Ordering::Equal;
+            }
+            self.as_slice().cmp(prefix)
+        }
+    }
+
+    impl<A: Ord, B> KeyPrefix<A> for (A, B) {
+        fn cmp_prefix(&self, prefix: &A) -> Ordering {
+            self.0.cmp(prefix)
+        }
+    }
+
+    impl<A: Ord, B, C> KeyPrefix<A> for (A, B, C) {
+        fn cmp_prefix(&self, prefix: &A) -> Ordering {
+            self.0.cmp(prefix)
+        }
+    }
+
+    impl<A: Ord, B: Ord, C> KeyPrefix<(A, B)> for (A, B, C) {
+        fn cmp_prefix(&self, prefix: &(A, B)) -> Ordering {
+            (&self.0, &self.1).cmp(&(&prefix.0, &prefix.1))
+        }
+    }
+
+    #[derive(Debug)]
+    pub enum BtreeError {
+        KeyNotFound,
+        DuplicateKey,
+        EntryTooLarge, // entry doesn't fit to the node
+        Io(io::Error),
+        Corrupt(String),
+        FileFull,           // file outgrows the block address space
+        Unsorted,           // bulk_load input is not in key order
+        BadFillFactor,      // bulk_load fill factor is not in (0, 1]
+        BadOptions(String), // settings the tree can't be created or opened with
+        HeaderMismatch(String),
+        Locked,        // index is opened by another writer, or by readers for a writer
+        ReadOnly,      // change of the index opened read-only
+        SnapshotAlive, // compact would move the blocks a live snapshot reads
+    }
+
+    /// When the written blocks reach the disk.
+    #[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+    pub enum Durability {
+        Sync, // every commit syncs the journal and the changed blocks
+        // commits are synced in batches, once `ops` are committed or `interval_ms`
+        // passed since the last sync, checked on commit and by Btree::sync_if_due
+        GroupCommit { ops: u32, interval_ms: u32 },
+        Async, // OS writes the pages back, a power loss can lose or tear the last commits
+    }
+
+    /// How the file grows when no free block is left.
+    #[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+    pub enum Growth {
+        Blocks(u32),  // by the number of blocks
+        Percent(u32), // by the percent of the file size, at least one block
+    }
+
+    /// Settings of the index, built with the chained setters.
+    ///
+    /// `Btree::create_with` stores them in the header, unset ones take the defaults.
+    /// `Btree::open` reads them back: the cache, durability and growth set here
+    /// override the stored ones for this open only, the block size, alpha and
+    /// multimap set here must match the stored ones, otherwise it's `HeaderMismatch`.
+    #[derive(Debug, Default, Clone, PartialEq)]
+    pub struct BtreeOptions {
+        pub block_size: Option<Block>,
+        pub alpha: Option<u8>, // nodes are filled to 1/alpha at least
+        pub multimap: Option<bool>,
+        pub cache_size: Option<usize>, // in nodes
+        pub cache_policy: Option<CacheKind>,
+        pub durability: Option<Durability>,
+        pub growth: Option<Growth>,
+    }
+
+    impl BtreeOptions {
+        pub fn new() -> Self {
+            BtreeOptions::default()
+        }
+
+        pub fn block_size(mut self, block_size: Block) -> Self {
+            self.block_size = Some(block_size);
+            self
+        }
+
+        pub fn alpha(mut self, alpha: u8) -> Self {
+            self.alpha = Some(alpha);
+            self
+        }
+
+        pub fn multimap(mut self, multimap: bool) -> Self {
+            self.multimap = Some(multimap);
+            self
+        }
+
+        pub fn cache_size(mut self, cache_size: usize) -> Self {
+            self.cache_size = Some(cache_size);
+            self
+        }
+
+        pub fn cache_policy(mut self, cache_policy: CacheKind) -> Self {
+            self.cache_policy = Some(cache_policy);
+            self
+        }
+
+        pub fn durability(mut self, durability: Durability) -> Self {
+            self.durability = Some(durability);
+            self
+        }
+
+        pub fn growth(mut self, growth: Growth) -> Self {
+            self.growth = Some(growth);
+            self
+        }
+
+        // settings stored by create, the unset ones are the defaults
+        fn to_stored(&self) -> StoredOptions {
+            StoredOptions {
+                alpha: self.alpha.unwrap_or(DEFAULT_ALPHA),
+                cache_size: self.cache_size.unwrap_or(DEFAULT_CACHE_SIZE) as u64,
+                cache_policy: self.cache_policy.unwrap_or(CacheKind::Lru),
+                durability: self.durability.unwrap_or(Durability::Sync),
+                growth: self.growth.unwrap_or(Growth::Blocks(1)),
+            }
+        }
+
+        // settings of the new tree, rejected before anything is written
+        fn check<K: Key, V: Val>(&self) -> Result<(), BtreeError> {
+            let bad = |msg: String| Err(BtreeError::BadOptions(msg));
+            match self.alpha {
+                Some(alpha) if alpha < 2 => {
+                    return bad(format!("alpha={}, must be 2 or more", alpha))
+                }
+                _ => {}
+            }
+            let block_size = self.block_size.unwrap_or(DEFAULT_BLOCK_SIZE);
+            let min = min_block_size::<K, V>();
+            if (block_size as u64) < min {
+                return bad(format!(
+                    "block_size={}, must be {} or more",
+                    block_size, min
+                ));
+            }
+            self.check_growth()
+        }
+
+        fn check_growth(&self) -> Result<(), BtreeError> {
+            // file must grow by one block at least
+            match self.growth {
+                Some(growth @ Growth::Blocks(0)) | Some(growth @ Growth::Percent(0)) => Err(
+                    BtreeError::BadOptions(format!("growth={:?}, must be above 0", growth)),
+                ),
+                _ => Ok(()),
+            }
+        }
+
+        // settings of the open: the stored ones with the overrides
+        fn resolve(&self, header: &BtreeHeader) -> Result<StoredOptions, BtreeError> {
+            self.check_growth()?;
+            let stored = &header.options;
+            let mismatch = |name: &str, stored: &dyn Debug, requested: &dyn Debug| {
+                Err(BtreeError::HeaderMismatch(format!(
+                    "{}={:?}, requested={:?}",
+                    name, stored, requested
+                )))
+            };
+            match self.block_size {
+                Some(block_size) if block_size != header.block_size => {
+                    return mismatch("block_size", &header.block_size, &block_size)
+                }
+                _ => {}
+            }
+            match self.alpha {
+                Some(alpha) if alpha != stored.alpha => {
+                    return mismatch("alpha", &stored.alpha, &alpha)
+                }
+                _ => {}
+            }
+            match self.multimap {
+                Some(multimap) if multimap != header.multimap => {
+                    return mismatch("multimap", &header.multimap, &multimap)
+                }
+                _ => {}
+            }
+            Ok(StoredOptions {
+                alpha: stored.alpha,
+                cache_size: self
+                    .cache_size
+                    .map_or(stored.cache_size, |size| size as u64),
+                cache_policy: self.cache_policy.unwrap_or(stored.cache_policy),
+                durability: self.durability.unwrap_or(stored.durability),
+                growth: self.growth.unwrap_or(stored.growth),
+            })
+        }
+    }
 
-    pub struct Btree(Rc<RefCell<BtreeInner>>);
-    struct Node(Rc<RefCell<NodeInner>>);
+    pub struct Btree<K: Key, V: Val>(Rc<RefCell<BtreeInner<K, V>>>);
+    struct Node<K: Key, V: Val>(Rc<RefCell<NodeInner<K, V>>>);
 
     #[derive(Debug)]
-    struct NodeInner {
-        st: NodeStored,
+    struct NodeInner<K: Key, V: Val> {
+        st: NodeStored<K, Slot<V>>,
         addr: Addr,
-        bt: Btree,
+        bt: Weak<RefCell<BtreeInner<K, V>>>, // cached nodes don't keep the tree alive
     }
 
-    #[derive(Debug, Serialize, Deserialize)]
-    struct NodeStored {
+    #[derive(Debug)]
+    struct NodeStored<K, V> {
+        leaf: bool,
+        keys: Vec<K>,
+        vals: Vec<V>,    // values, leaf node only
+        refs: Vec<Addr>, // addresses of the children, internal node only
+        next: Option<Addr>,
+        prev: Option<Addr>,
+    }
+
+    // node read in place from the block, keys and values are decoded on access,
+    // see PAGE_HEADER_SIZE for the layout
+    struct NodeView<'a, K, V> {
+        buf: &'a [u8],
+        addr: Addr,
         leaf: bool,
-        keys: Vec<Key>,
-        vals: Vec<Val>,
+        count: usize,
+        prefix: Option<&'a [u8]>, // common prefix of the byte string keys
+        slots: usize,             // start of the slots
+        end: usize,               // end of the cells
+        _marker: std::marker::PhantomData<(K, V)>,
+    }
+
+    // size of the page with the entries added so far, see NodeStored::encode.
+    // Leaf keys are compressed, when all of them are byte strings.
+    #[derive(Debug, Clone)]
+    struct PageSize {
+        leaf: bool,
+        count: u64,
+        plain: u64, // sizes of the entries encoded as they are, with the slots
+        strings: bool,
+        prefix: Vec<u8>,
+    }
+
+    // value of the leaf entry: stored in the leaf or spilled to the overflow blocks,
+    // when the entry doesn't fit to the leaf
+    #[derive(Clone, Serialize, Deserialize)]
+    enum Slot<V> {
+        Inline(V),
+        Overflow { head: Addr, len: u64 }, // first block and the encoded value size
+    }
+
+    // part of the spilled value, followed by crc32 like the nodes.
+    // Blocks of the value are linked by the next field.
+    #[derive(Debug, Serialize, Deserialize)]
+    struct OverflowStored {
         next: Option<Addr>,
+        data: Vec<u8>,
+    }
+
+    // value slot of the node: user value for a leaf, child address otherwise
+    #[derive(Debug, Clone)]
+    enum Item<V> {
+        Val(Slot<V>),
+        Ref(Addr),
     }
 
-    #[derive(Clone, Debug)]
-    struct Path(Rc<RefCell<PathInner>>);
+    #[derive(Debug)]
+    struct Path<K: Key, V: Val>(Rc<RefCell<PathInner<K, V>>>);
 
     #[derive(Debug)]
-    struct PathInner {
+    struct PathInner<K: Key, V: Val> {
         steps: Vec<PathStep>,
-        bt: Btree,
+        bt: Btree<K, V>,
+        cache: NodeCache<K, V>, // nodes of the steps are pinned there while the path lives
     }
 
-    #[derive(Debug, Clone)]
-    struct PathRef {
+    #[derive(Debug)]
+    struct PathRef<K: Key, V: Val> {
         index: usize,
-        path: Path,
+        path: Path<K, V>,
     }
 
-    pub struct BtreeInner {
+    pub struct BtreeInner<K: Key, V: Val> {
         header: BtreeHeader,
-        cache: NodeCache,
-        mmap: MmapMut,
+        options: StoredOptions, // settings of this open, see BtreeOptions
+        cache: NodeCache<K, V>,
+        mmap: Mapping,
+        fd: File,
+        file_len: u64,            // size of the index file
+        journal: Option<Journal>, // none for the read-only open
+        _lock: Option<File>,      // advisory lock, released on drop
+        pending: Option<Pending<K, V>>,
+        error: Option<BtreeError>, // write failed during the operation in progress
+        snapshots: Snapshots,
+        counters: Counters,
+        unsynced: Unsynced,
+    }
+
+    // copy-on-write state of the live snapshots, see Btree::snapshot
+    #[derive(Default)]
+    struct Snapshots {
+        live: Vec<Weak<u64>>,      // epochs of the live snapshots
+        epoch: u64,                // snapshots taken
+        fresh: HashSet<Addr>,      // blocks allocated since the last snapshot, changed in place
+        retired: Vec<(u64, Addr)>, // blocks freed by the tree at the epoch, snapshots still read them
+    }
+
+    // written since the last sync, see Durability
+    struct Unsynced {
+        range: Option<(usize, usize)>, // start and end of the written bytes
+        ops: u32,                      // operations committed
+        since: Instant,                // time of the last sync
+    }
+
+    impl Unsynced {
+        fn new() -> Self {
+            Unsynced {
+                range: None,
+                ops: 0,
+                since: Instant::now(),
+            }
+        }
+    }
+
+    impl Snapshots {
+        // epoch of the oldest live snapshot, the dropped ones are forgotten
+        fn oldest(&mut self) -> Option<u64> {
+            self.live.retain(|pin| pin.strong_count() > 0);
+            if self.live.is_empty() {
+                self.fresh.clear();
+            }
+            self.live
+                .iter()
+                .filter_map(Weak::upgrade)
+                .map(|epoch| *epoch)
+                .min()
+        }
+
+        // block of the tree, which a live snapshot reads as well
+        fn is_shared(&mut self, addr: Addr) -> bool {
+            self.oldest().is_some() && !self.fresh.contains(&addr)
+        }
+
+        fn mark_fresh(&mut self, addr: Addr) {
+            if self.oldest().is_some() {
+                self.fresh.insert(addr);
+            }
+        }
+
+        // retired blocks no live snapshot reads, the ones taken later never saw them
+        fn releasable(&mut self) -> Vec<Addr> {
+            let oldest = self.oldest();
+            self.retired
+                .iter()
+                .filter(|(epoch, _)| oldest.is_none_or(|oldest| *epoch < oldest))
+                .map(|(_, addr)| *addr)
+                .collect()
+        }
+    }
+
+    // events since the tree is created or loaded, see Btree::stats
+    #[derive(Debug, Default)]
+    struct Counters {
+        node_reads: Cell<u64>,
+        node_decodes: Cell<u64>,
+        node_writes: Cell<u64>,
+        splits: Cell<u64>,
+        merges: Cell<u64>,
+        rebalances: Cell<u64>,
+        syncs: Cell<u64>,
+        commits: Cell<u64>, // operations committed or aborted, cursors check it for changes
+    }
+
+    // mapping of the index file. Read-only one can't be written, the blocks of
+    // the journal records the writer left are read from the journal.
+    // Blocks written while the journal has unsynced records are held
+    // and read from memory, they reach the mapping after the journal sync.
+    enum Mapping {
+        Write {
+            mmap: MmapMut,
+            held: HashMap<Addr, Vec<u8>>,
+        },
+        Read {
+            mmap: Mmap,
+            file_len: u64, // the file itself, reads beyond it are cut off
+            journal: HashMap<Addr, Vec<u8>>,
+        },
+    }
+
+    // Redo journal, stored next to the index file.
+    // Every committed operation is appended as a record with the images
+    // of all blocks it changed. Blocks are written to the index file only
+    // after the record is synced, so an interrupted operation never reaches it.
+    // On load committed records are replayed and a torn tail is dropped.
+    struct Journal {
         fd: File,
+        len: u64,
+        synced: u64, // length known to be on the disk
+        sync: bool,  // records are synced on append
+    }
+
+    #[derive(Debug, Serialize, Deserialize)]
+    struct JournalRecord {
+        file_len: u64,
+        blocks: Vec<(Addr, Vec<u8>)>,
+    }
+
+    // state of the operation in progress
+    struct Pending<K: Key, V: Val> {
+        dirty: BTreeMap<Addr, Node<K, V>>, // nodes changed by the operation
+        overflow: BTreeMap<Addr, Vec<u8>>, // overflow blocks written by the operation
+        retired: Vec<Addr>, // blocks freed by the operation, live snapshots read them
+        file_len: u64,      // file size after the operation
     }
 
     #[derive(Debug, Copy, Clone)]
@@ -82,22 +524,72 @@ pub mod btree {
         node: StepInfo,
     }
 
+    // where compact updates the address of the moved block
+    #[derive(Debug, Copy, Clone)]
+    enum BlockOwner {
+        Root,
+        Child(StepInfo), // node is the child of the internal node
+        Value(StepInfo), // first overflow block of the leaf value
+        Chain(Addr),     // overflow block is the next one of this block
+    }
+
     #[derive(Debug)]
-    enum InsertTarget {
-        Ref(PathRef),
-        RefNodeAddr((PathRef, Addr)),
+    enum InsertTarget<K: Key, V: Val> {
+        Ref(PathRef<K, V>),
+        RefNodeAddr((PathRef<K, V>, Addr)),
     }
 
+    // stored in the first block, followed by crc32 of the encoded header
     #[derive(Debug, Serialize, Deserialize)]
     struct BtreeHeader {
+        magic: u32,
+        version: u32,
         root: Addr,
         min_degree: Degree,
         max_degree: Degree,
         block_size: Block,
+        free: Option<Addr>, // head of the free blocks list, linked by the next field
+        multimap: bool,     // equal keys are allowed
+        options: StoredOptions,
+    }
+
+    // settings the index is created with, see BtreeOptions
+    #[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+    struct StoredOptions {
+        alpha: u8,
+        cache_size: u64,
+        cache_policy: CacheKind,
+        durability: Durability,
+        growth: Growth,
+    }
+
+    // writes the nodes of a new tree level by level, in address order,
+    // overflow blocks of the values go right before their leaf
+    struct BulkLoader<K, V> {
+        out: BufWriter<File>,
+        pos: u64, // write position of out
+        next_addr: u64,
+        entry_limit: u64, // larger leaf entries are spilled
+        block_size: Block,
+        min_degree: Degree,
+        max_degree: Degree,
+        fill_degree: Degree, // node is closed at this degree
+        fill_size: u64,      // or when the next entry doesn't fit to this size
+        _marker: std::marker::PhantomData<(K, V)>,
+    }
+
+    struct TaskManager<K: Key, V: Val> {
+        deq: VecDeque<Task<K, V>>,
+        // internal nodes outgrown the block with a longer separator,
+        // they are split after the other tasks, see update_util
+        oversized: Vec<Node<K, V>>,
     }
 
-    struct TaskManager {
-        deq: VecDeque<Task>,
+    // entry of the key searched in the leaf read in place
+    enum LeafSearch<V> {
+        Found(V),
+        Missing,
+        Next(Addr), // all keys are less, equal keys of multimap could start in the next leaf
     }
 
     enum IdxSide {
@@ -105,19 +597,75 @@ pub mod btree {
         Right(usize),
     }
 
-    impl Clone for Btree {
+    impl<K: Key, V: Val> Clone for Btree<K, V> {
+        fn clone(&self) -> Self {
+            Self(Rc::clone(&self.0))
+        }
+    }
+
+    impl<K: Key, V: Val> Clone for Node<K, V> {
         fn clone(&self) -> Self {
             Self(Rc::clone(&self.0))
         }
     }
 
-    impl Clone for Node {
+    impl<K: Key, V: Val> Clone for Path<K, V> {
         fn clone(&self) -> Self {
             Self(Rc::clone(&self.0))
         }
     }
 
-    impl Debug for Btree {
+    impl<K: Key, V: Val> Clone for PathRef<K, V> {
+        fn clone(&self) -> Self {
+            PathRef::new(&self.path, self.index)
+        }
+    }
+
+    impl Display for BtreeError {
+        fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+            match self {
+                BtreeError::KeyNotFound => write!(f, "key not found"),
+                BtreeError::DuplicateKey => write!(f, "duplicate key"),
+                BtreeError::EntryTooLarge => write!(f, "entry is too large for the block"),
+                BtreeError::Io(err) => write!(f, "io error: {}", err),
+                BtreeError::Corrupt(msg) => write!(f, "corrupted index: {}", msg),
+                BtreeError::FileFull => write!(f, "index file is full"),
+                BtreeError::Unsorted => write!(f, "keys are not sorted"),
+                BtreeError::BadFillFactor => write!(f, "fill factor must be in (0, 1]"),
+                BtreeError::BadOptions(msg) => write!(f, "bad options: {}", msg),
+                BtreeError::HeaderMismatch(msg) => write!(f, "header mismatch: {}", msg),
+                BtreeError::Locked => write!(f, "index is locked by another process"),
+                BtreeError::ReadOnly => write!(f, "index is opened read-only"),
+                BtreeError::SnapshotAlive => write!(f, "index has live snapshots"),
+            }
+        }
+    }
+
+    impl std::error::Error for BtreeError {
+        fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+            match self {
+                BtreeError::Io(err) => Some(err),
+                _ => None,
+            }
+        }
+    }
+
+    impl From<io::Error> for BtreeError {
+        fn from(err: io::Error) -> Self {
+            BtreeError::Io(err)
+        }
+    }
+
+    impl From<bincode::Error> for BtreeError {
+        fn from(err: bincode::Error) -> Self {
+            match *err {
+                bincode::ErrorKind::Io(err) => BtreeError::Io(err),
+                err => BtreeError::Corrupt(err.to_string()),
+            }
+        }
+    }
+
+    impl<K: Key, V: Val> Debug for Btree<K, V> {
         fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
             write!(
                 f,
@@ -129,23 +677,23 @@ pub mod btree {
         }
     }
 
-    impl PathRef {
-        fn new(path: &Path, index: usize) -> Self {
+    impl<K: Key, V: Val> PathRef<K, V> {
+        fn new(path: &Path<K, V>, index: usize) -> Self {
             PathRef {
                 path: path.clone(),
                 index,
             }
         }
 
-        fn bt(&self) -> Btree {
+        fn bt(&self) -> Btree<K, V> {
             self.path.bt()
         }
 
-        fn tail(path: &Path) -> Self {
+        fn tail(path: &Path<K, V>) -> Self {
             PathRef::new(path, path.len() - 1)
         }
 
-        fn parent_ref(&self) -> Option<PathRef> {
+        fn parent_ref(&self) -> Option<PathRef<K, V>> {
             if self.index == 0 {
                 None
             } else {
@@ -162,99 +710,48 @@ pub mod btree {
         }
 
         fn node_idx(&self) -> Option<usize> {
-            let parent_ref = self.parent_ref();
-            if parent_ref.is_some() {
-                Some(parent_ref.unwrap().get_step().node_idx())
-            } else {
-                None
-            }
+            self.parent_ref()
+                .map(|parent_ref| parent_ref.get_step().node_idx())
         }
 
-        fn node(&self) -> Node {
+        fn node(&self) -> Result<Node<K, V>, BtreeError> {
             self.path.bt().get_node(self.node_addr())
         }
 
         fn left_sibling_addr(&self) -> Option<Addr> {
-            let parent_ref = self.parent_ref();
-            if parent_ref.is_some() {
-                let step = parent_ref.unwrap().get_step();
-                if step.left.is_some() {
-                    Some(step.left_addr())
-                } else {
-                    None
-                }
-            } else {
-                None
-            }
+            let step = self.parent_ref()?.get_step();
+            step.left.map(|_| step.left_addr())
         }
 
         fn left_sibling_idx(&self) -> Option<usize> {
-            let parent_ref = self.parent_ref();
-            if parent_ref.is_some() {
-                let step = parent_ref.unwrap().get_step();
-                if step.left.is_some() {
-                    Some(step.left_idx())
-                } else {
-                    None
-                }
-            } else {
-                None
-            }
+            let step = self.parent_ref()?.get_step();
+            step.left.map(|_| step.left_idx())
         }
 
-        fn left_sibling(&self) -> Option<Node> {
-            let addr = self.left_sibling_addr();
-            if addr.is_some() {
-                Some(self.path.bt().get_node(addr.unwrap()))
-            } else {
-                None
-            }
+        fn left_sibling(&self) -> Result<Option<Node<K, V>>, BtreeError> {
+            self.left_sibling_addr()
+                .map(|addr| self.path.bt().get_node(addr))
+                .transpose()
         }
 
         fn right_sibling_idx(&self) -> Option<usize> {
-            let parent_ref = self.parent_ref();
-            if parent_ref.is_some() {
-                let step = parent_ref.unwrap().get_step();
-                if step.right.is_some() {
-                    Some(step.right_idx())
-                } else {
-                    None
-                }
-            } else {
-                None
-            }
+            let step = self.parent_ref()?.get_step();
+            step.right.map(|_| step.right_idx())
         }
 
         fn right_sibling_addr(&self) -> Option<Addr> {
-            let parent_ref = self.parent_ref();
-            if parent_ref.is_some() {
-                let step = parent_ref.unwrap().get_step();
-                if step.right.is_some() {
-                    Some(step.right_addr())
-                } else {
-                    None
-                }
-            } else {
-                None
-            }
+            let step = self.parent_ref()?.get_step();
+            step.right.map(|_| step.right_addr())
         }
 
-        fn right_sibling(&self) -> Option<Node> {
-            let addr = self.right_sibling_addr();
-            if addr.is_some() {
-                Some(self.path.bt().get_node(addr.unwrap()))
-            } else {
-                None
-            }
+        fn right_sibling(&self) -> Result<Option<Node<K, V>>, BtreeError> {
+            self.right_sibling_addr()
+                .map(|addr| self.path.bt().get_node(addr))
+                .transpose()
         }
 
-        fn parent(&self) -> Option<Node> {
-            let pref = self.parent_ref();
-            if pref.is_some() {
-                Some(pref.unwrap().node())
-            } else {
-                None
-            }
+        fn parent(&self) -> Result<Option<Node<K, V>>, BtreeError> {
+            self.parent_ref().map(|pref| pref.node()).transpose()
         }
 
         fn top(&self) -> bool {
@@ -266,11 +763,16 @@ pub mod btree {
         }
     }
 
-    impl Path {
-        fn new(steps: Vec<PathStep>, bt: &Btree) -> Self {
+    impl<K: Key, V: Val> Path<K, V> {
+        fn new(steps: Vec<PathStep>, bt: &Btree<K, V>) -> Self {
+            let cache = Rc::clone(&bt.0.borrow().cache);
+            for step in steps.iter() {
+                cache.borrow_mut().pin(step.node.addr);
+            }
             Path(Rc::new(RefCell::new(PathInner {
                 steps,
                 bt: bt.clone(),
+                cache,
             })))
         }
 
@@ -282,102 +784,708 @@ pub mod btree {
             self.0.borrow().steps.len()
         }
 
-        fn bt(&self) -> Btree {
+        fn bt(&self) -> Btree<K, V> {
             self.0.borrow().bt.clone()
         }
     }
 
-    impl Debug for Node {
+    impl<K: Key, V: Val> Drop for PathInner<K, V> {
+        fn drop(&mut self) {
+            let mut cache = self.cache.borrow_mut();
+            for step in self.steps.iter() {
+                cache.unpin(step.node.addr);
+            }
+        }
+    }
+
+    impl<K: Key, V: Val> Debug for Node<K, V> {
         fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+            let inner = self.0.borrow();
             write!(
                 f,
-                "Node A={}, R=({}), L=({}), keys={:?}, vals={:?}, N:{:?}",
+                "Node A={}, R=({}), L=({}), keys={:?}, vals=",
                 self.addr(),
                 if self.is_root() { "+" } else { "-" },
                 if self.is_leaf() { "+" } else { "-" },
-                self.0.borrow().st.keys,
-                self.0.borrow().st.vals,
-                self.0.borrow().st.next,
-            )
+                inner.st.keys,
+            )?;
+            if inner.st.leaf {
+                write!(f, "{:?}", inner.st.vals)?;
+            } else {
+                write!(f, "{:?}", inner.st.refs)?;
+            }
+            write!(f, ", N:{:?}", inner.st.next)
         }
     }
 
-    impl Node {
-        fn node_builder(bt: Btree, leaf: bool) -> Self {
-            let addr = bt.expand_file();
-            let node = Self(Rc::new(RefCell::new(NodeInner {
-                st: NodeStored {
-                    leaf,
-                    next: None,
-                    keys: Vec::new(),
-                    vals: Vec::new(),
-                },
-                addr: addr,
-                bt,
-            })));
-            node.flush();
-            node
+    impl<K, V> NodeStored<K, V> {
+        fn new(leaf: bool) -> Self {
+            NodeStored {
+                leaf,
+                next: None,
+                prev: None,
+                keys: Vec::new(),
+                vals: Vec::new(),
+                refs: Vec::new(),
+            }
         }
+    }
 
-        fn new(bt: &Btree) -> Self {
-            debug!("Node::new: created");
-            Self::node_builder(bt.clone(), false)
+    impl<K: Serialize + DeserializeOwned, V: Serialize + DeserializeOwned> NodeStored<K, V> {
+        fn encode(&self) -> Result<Vec<u8>, BtreeError> {
+            let count = self.keys.len();
+            let keys = self
+                .keys
+                .iter()
+                .map(bincode::serialize)
+                .collect::<Result<Vec<_>, _>>()?;
+            let mut page = PageSize::new(self.leaf);
+            for key in keys.iter() {
+                page.add_encoded(key, 0);
+            }
+            let prefix = if page.prefixed() {
+                &page.prefix[..]
+            } else {
+                &[]
+            };
+            let mut slots = Vec::with_capacity(count * PAGE_SLOT_SIZE as usize);
+            let mut cells = Vec::new();
+            let base = PAGE_HEADER_SIZE as usize + prefix.len() + count * PAGE_SLOT_SIZE as usize;
+            for (i, key) in keys.iter().enumerate() {
+                slots.extend_from_slice(&((base + cells.len()) as u32).to_le_bytes());
+                if page.prefixed() {
+                    cells.extend_from_slice(&key[STRING_LEN_SIZE as usize + prefix.len()..]);
+                } else {
+                    cells.extend_from_slice(key);
+                }
+                slots.extend_from_slice(&((base + cells.len()) as u32).to_le_bytes());
+                if self.leaf {
+                    bincode::serialize_into(&mut cells, &self.vals[i])?;
+                } else {
+                    cells.extend_from_slice(&self.refs[i].to_le_bytes());
+                }
+            }
+            let end = base + cells.len();
+            let mut buf = Vec::with_capacity(end + CHECKSUM_SIZE as usize);
+            let mut flags = 0;
+            if self.leaf {
+                flags |= PAGE_LEAF;
+            }
+            if page.prefixed() {
+                flags |= PAGE_PREFIXED;
+            }
+            buf.push(flags);
+            buf.extend_from_slice(&(count as u32).to_le_bytes());
+            buf.extend_from_slice(&self.next.unwrap_or(0).to_le_bytes());
+            buf.extend_from_slice(&self.prev.unwrap_or(0).to_le_bytes());
+            buf.extend_from_slice(&(end as u32).to_le_bytes());
+            buf.extend_from_slice(&(prefix.len() as u32).to_le_bytes());
+            buf.extend_from_slice(prefix);
+            buf.extend_from_slice(&slots);
+            buf.extend_from_slice(&cells);
+            debug_assert_eq!(buf.len() as u64 + CHECKSUM_SIZE, self.encoded_size());
+            let crc = crc32fast::hash(&buf);
+            buf.extend_from_slice(&crc.to_le_bytes());
+            Ok(buf)
+        }
+
+        fn decode(buf: &[u8], addr: Addr) -> Result<Self, BtreeError> {
+            NodeView::new(buf, addr)?.to_stored()
+        }
+
+        fn encoded_size(&self) -> u64 {
+            // size of the stored node with the checksum
+            self.page().size()
+        }
+
+        fn entry_size(&self, index: usize) -> u64 {
+            // encoded key and value with the slot of the entry, before the compression
+            let key_size = bincode::serialized_size(&self.keys[index]).unwrap();
+            if self.leaf {
+                key_size + bincode::serialized_size(&self.vals[index]).unwrap() + PAGE_SLOT_SIZE
+            } else {
+                key_size + size_of::<Addr>() as u64 + PAGE_SLOT_SIZE
+            }
         }
 
-        fn new_leaf(bt: &Btree) -> Self {
-            debug!("Node::new_leaf: created");
-            Self::node_builder(bt.clone(), true)
+        fn entry_sizes(&self) -> Vec<u64> {
+            (0..self.keys.len()).map(|i| self.entry_size(i)).collect()
         }
 
-        fn new_sibling(node: &Node) -> Self {
-            if node.is_leaf() {
-                Node::new_leaf(&node.bt())
-            } else {
-                Node::new(&node.bt())
-            }
+        fn item_sizes(&self) -> Vec<u64> {
+            // sizes of the entries in the node, the rest of the size is fixed
+            let page = self.page();
+            self.entry_sizes()
+                .into_iter()
+                .map(|size| page.entry_size(size))
+                .collect()
         }
 
-        fn new_root(bt: &Btree) -> Self {
-            let new_root = Node::new(bt);
-            new_root.bt().set_root(new_root.addr());
-            new_root
+        fn page(&self) -> PageSize {
+            self.page_part(0, self.keys.len())
         }
 
-        fn bt(&self) -> Btree {
-            self.0.borrow().bt.clone()
+        fn page_part(&self, start: usize, stop: usize) -> PageSize {
+            let mut page = PageSize::new(self.leaf);
+            let mut key = Vec::new();
+            for i in start..stop {
+                if !page.strings {
+                    page.add(&self.keys[i], self.entry_size(i));
+                    continue;
+                }
+                key.clear();
+                bincode::serialize_into(&mut key, &self.keys[i]).unwrap();
+                let val_size = if self.leaf {
+                    bincode::serialized_size(&self.vals[i]).unwrap()
+                } else {
+                    size_of::<Addr>() as u64
+                };
+                page.add_encoded(&key, key.len() as u64 + val_size + PAGE_SLOT_SIZE);
+            }
+            page
         }
+    }
 
-        fn split(&self, index: usize) -> (Option<Addr>, Node, IdxSide) {
-            let sibling = Node::new_sibling(self);
+    impl PageSize {
+        fn new(leaf: bool) -> Self {
+            PageSize {
+                leaf,
+                count: 0,
+                plain: 0,
+                strings: leaf,
+                prefix: Vec::new(),
+            }
+        }
 
-            // compute split index and insertion index
-            let middle = ((self.degree() + 1) / 2) as usize;
-            let (cut_idx, idx) = if index < middle {
-                (middle - 1, IdxSide::Left(index))
+        fn add<K: Serialize>(&mut self, key: &K, entry_size: u64) {
+            if self.strings {
+                self.add_encoded(&bincode::serialize(key).unwrap(), entry_size);
             } else {
-                (middle, IdxSide::Right(index - middle))
-            };
-
-            // move part of elements to the new node (sibling)
-            sibling.append_n_from(self, cut_idx, self.degree() as usize);
-
-            if self.is_leaf() {
-                // update next ref
-                sibling.set_next(self.next());
-                self.set_next(Some(sibling.addr()));
+                self.count += 1;
+                self.plain += entry_size;
             }
+        }
 
-            // if there is no parent - create one
-            let mut new_root = None;
-            if self.is_root() {
-                new_root = Some(Node::new_root(&self.bt()).addr());
+        fn add_encoded(&mut self, key: &[u8], entry_size: u64) {
+            if self.strings {
+                match string_content(key) {
+                    Some(content) if self.count == 0 => self.prefix = content.to_vec(),
+                    Some(content) => self.prefix.truncate(common_prefix(&self.prefix, content)),
+                    None => self.strings = false,
+                }
             }
-
-            (new_root, sibling, idx)
+            self.count += 1;
+            self.plain += entry_size;
         }
 
-        fn set_addr(&self, addr: Addr) {
-            trace!("Node::set_addr: old={:?}, new={:?}", self.addr(), addr);
+        fn merge(&mut self, other: &PageSize) {
+            debug_assert_eq!(self.leaf, other.leaf);
+            if other.count == 0 {
+                return;
+            }
+            if self.count == 0 {
+                *self = other.clone();
+                return;
+            }
+            self.strings &= other.strings;
+            if self.strings {
+                self.prefix
+                    .truncate(common_prefix(&self.prefix, &other.prefix));
+            }
+            self.count += other.count;
+            self.plain += other.plain;
+        }
+
+        fn prefixed(&self) -> bool {
+            self.strings && self.count > 0
+        }
+
+        fn size(&self) -> u64 {
+            let fixed = PAGE_HEADER_SIZE + CHECKSUM_SIZE;
+            if self.prefixed() {
+                // the prefix is stored once, lengths aren't stored
+                let prefix = self.prefix.len() as u64;
+                fixed + prefix + self.plain - self.count * (STRING_LEN_SIZE + prefix)
+            } else {
+                fixed + self.plain
+            }
+        }
+
+        fn entry_size(&self, entry_size: u64) -> u64 {
+            if self.prefixed() {
+                entry_size - STRING_LEN_SIZE - self.prefix.len() as u64
+            } else {
+                entry_size
+            }
+        }
+    }
+
+    // content of the encoded byte string, None for the other types.
+    // bincode encodes String and Vec<u8> as the length followed by the bytes.
+    fn string_content(encoded: &[u8]) -> Option<&[u8]> {
+        let content = encoded.get(STRING_LEN_SIZE as usize..)?;
+        (u64_at(encoded, 0) == content.len() as u64).then_some(content)
+    }
+
+    fn common_prefix(a: &[u8], b: &[u8]) -> usize {
+        a.iter().zip(b).take_while(|(a, b)| a == b).count()
+    }
+
+    // shortest key greater than the left one and not greater than the right one.
+    // Only byte string keys are shortened, to a prefix of the right key,
+    // the order of the candidates is checked with Ord of the key type.
+    fn separator<K: Key>(left: &K, right: &K) -> K {
+        let (encoded_left, encoded_right) =
+            match (bincode::serialize(left), bincode::serialize(right)) {
+                (Ok(left), Ok(right)) => (left, right),
+                _ => return right.clone(),
+            };
+        if let (Some(left_content), Some(right_content)) = (
+            string_content(&encoded_left),
+            string_content(&encoded_right),
+        ) {
+            for len in common_prefix(left_content, right_content) + 1..right_content.len() {
+                let mut encoded = (len as u64).to_le_bytes().to_vec();
+                encoded.extend_from_slice(&right_content[..len]);
+                // a cut in the middle of a utf-8 char doesn't decode
+                if let Ok(key) = bincode::deserialize::<K>(&encoded) {
+                    if *left < key && key <= *right {
+                        return key;
+                    }
+                }
+            }
+        }
+        right.clone()
+    }
+
+    impl<'a, K: DeserializeOwned, V: DeserializeOwned> NodeView<'a, K, V> {
+        fn new(buf: &'a [u8], addr: Addr) -> Result<Self, BtreeError> {
+            let truncated = || BtreeError::Corrupt(format!("node is truncated at {}", addr));
+            let header = buf.get(..PAGE_HEADER_SIZE as usize).ok_or_else(truncated)?;
+            let flags = header[0];
+            if flags & !(PAGE_LEAF | PAGE_PREFIXED) != 0 || flags == PAGE_PREFIXED {
+                return Err(BtreeError::Corrupt(format!("bad node flags at {}", addr)));
+            }
+            let count = u32_at(header, 1) as usize;
+            let end = u32_at(header, 13) as usize;
+            let slots = PAGE_HEADER_SIZE as usize + u32_at(header, 17) as usize;
+            if (end as u64) < slots as u64 + count as u64 * PAGE_SLOT_SIZE {
+                return Err(BtreeError::Corrupt(format!("bad node size at {}", addr)));
+            }
+            let crc = buf
+                .get(end..end + CHECKSUM_SIZE as usize)
+                .ok_or_else(truncated)?;
+            if crc32fast::hash(&buf[..end]).to_le_bytes() != crc {
+                return Err(BtreeError::Corrupt(format!(
+                    "node checksum mismatch at {}",
+                    addr
+                )));
+            }
+            let prefix =
+                (flags & PAGE_PREFIXED != 0).then(|| &buf[PAGE_HEADER_SIZE as usize..slots]);
+            Ok(NodeView {
+                buf,
+                addr,
+                leaf: flags & PAGE_LEAF != 0,
+                count,
+                prefix,
+                slots,
+                end,
+                _marker: std::marker::PhantomData,
+            })
+        }
+
+        fn link(&self, offset: usize) -> Option<Addr> {
+            Some(u32_at(self.buf, offset)).filter(|&addr| addr != 0)
+        }
+
+        fn next(&self) -> Option<Addr> {
+            self.link(5)
+        }
+
+        fn prev(&self) -> Option<Addr> {
+            self.link(9)
+        }
+
+        fn size(&self) -> u64 {
+            self.end as u64 + CHECKSUM_SIZE
+        }
+
+        fn cell(&self, index: usize) -> Result<(&'a [u8], &'a [u8]), BtreeError> {
+            // stored key and value of the entry, value ends where the next key starts
+            let slot = self.slots + index * PAGE_SLOT_SIZE as usize;
+            let key_start = u32_at(self.buf, slot) as usize;
+            let val_start = u32_at(self.buf, slot + 4) as usize;
+            let val_end = if index + 1 < self.count {
+                u32_at(self.buf, slot + PAGE_SLOT_SIZE as usize) as usize
+            } else {
+                self.end
+            };
+            let cells = self.slots + self.count * PAGE_SLOT_SIZE as usize;
+            if cells > key_start
+                || key_start > val_start
+                || val_start > val_end
+                || val_end > self.end
+            {
+                return Err(BtreeError::Corrupt(format!(
+                    "bad slot {} of node at {}",
+                    index, self.addr
+                )));
+            }
+            Ok((
+                &self.buf[key_start..val_start],
+                &self.buf[val_start..val_end],
+            ))
+        }
+
+        fn key(&self, index: usize) -> Result<K, BtreeError> {
+            let key = self.cell(index)?.0;
+            match self.prefix {
+                Some(prefix) => {
+                    // the byte string is read in parts, without joining them
+                    let len = ((prefix.len() + key.len()) as u64).to_le_bytes();
+                    let reader = (&len[..]).chain(prefix).chain(key);
+                    Ok(bincode::deserialize_from(reader)?)
+                }
+                None => Ok(bincode::deserialize(key)?),
+            }
+        }
+        fn val(&self, index: usize) -> Result<V, BtreeError> {
+            debug_assert!(self.leaf);
+            Ok(bincode::deserialize(self.cell(index)?.1)?)
+        }
+
+        fn child(&self, index: usize) -> Result<Addr, BtreeError> {
+            debug_assert!(!self.leaf);
+            let val = self.cell(index)?.1;
+            if val.len() != size_of::<Addr>() {
+                return Err(BtreeError::Corrupt(format!(
+                    "bad child {} of node at {}",
+                    index, self.addr
+                )));
+            }
+            Ok(u32_at(val, 0))
+        }
+
+        fn bound(&self, key: &K, upper: bool) -> Result<usize, BtreeError>
+        where
+            K: Ord,
+        {
+            // same as Node::bound
+            if upper {
+                self.bound_by(&|k| k <= key)
+            } else {
+                self.bound_by(&|k| k < key)
+            }
+        }
+
+        fn bound_by(&self, before: &dyn Fn(&K) -> bool) -> Result<usize, BtreeError> {
+            // same as Node::bound_by, keys are decoded one by one while searching
+            let first = if self.leaf { 0 } else { 1 };
+            let (mut low, mut high) = (first, self.count.max(first));
+            while low < high {
+                let mid = low + (high - low) / 2;
+                let probe = self.key(mid)?;
+                if before(&probe) {
+                    low = mid + 1;
+                } else {
+                    high = mid;
+                }
+            }
+            Ok(low - first)
+        }
+
+        fn search(&self, key: &K) -> Result<LeafSearch<V>, BtreeError>
+        where
+            K: Ord,
+        {
+            // value of the first entry of the key, only it is decoded
+            let idx = self.bound(key, false)?;
+            if idx == self.count {
+                return Ok(self.next().map_or(LeafSearch::Missing, LeafSearch::Next));
+            }
+            if self.key(idx)? != *key {
+                return Ok(LeafSearch::Missing);
+            }
+            Ok(LeafSearch::Found(self.val(idx)?))
+        }
+
+        fn path_step(&self, index: usize) -> Result<PathStep, BtreeError> {
+            // same as Node::path_step
+            let sibling = |index: usize| self.child(index).map(|addr| (index, addr));
+            let left = index.checked_sub(1).map(sibling).transpose()?;
+            let right = if index + 1 < self.count {
+                Some(sibling(index + 1)?)
+            } else {
+                None
+            };
+            Ok(PathStep::new(left, right, (index, self.addr)))
+        }
+
+        fn to_stored(&self) -> Result<NodeStored<K, V>, BtreeError> {
+            let mut st = NodeStored::new(self.leaf);
+            st.next = self.next();
+            st.prev = self.prev();
+            for i in 0..self.count {
+                st.keys.push(self.key(i)?);
+                if self.leaf {
+                    st.vals.push(self.val(i)?);
+                } else {
+                    st.refs.push(self.child(i)?);
+                }
+            }
+            Ok(st)
+        }
+    }
+
+    fn u32_at(buf: &[u8], offset: usize) -> u32 {
+        let mut bytes = [0; 4];
+        bytes.copy_from_slice(&buf[offset..offset + 4]);
+        u32::from_le_bytes(bytes)
+    }
+
+    fn u64_at(buf: &[u8], offset: usize) -> u64 {
+        let mut bytes = [0; 8];
+        bytes.copy_from_slice(&buf[offset..offset + 8]);
+        u64::from_le_bytes(bytes)
+    }
+
+    impl<V: Debug> Debug for Slot<V> {
+        fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+            // dumps show the inline values as they are
+            match self {
+                Slot::Inline(val) => val.fmt(f),
+                Slot::Overflow { head, len } => write!(f, "Overflow({}, {})", head, len),
+            }
+        }
+    }
+
+    impl<V: Val> Slot<V> {
+        // encoded size of the reference to the spilled value
+        fn overflow_size() -> u64 {
+            bincode::serialized_size(&Slot::<V>::Overflow { head: 0, len: 0 }).unwrap()
+        }
+
+        fn head(&self) -> Option<Addr> {
+            match self {
+                Slot::Overflow { head, .. } => Some(*head),
+                Slot::Inline(_) => None,
+            }
+        }
+    }
+
+    impl OverflowStored {
+        fn encode(&self) -> Result<Vec<u8>, BtreeError> {
+            let mut buf = bincode::serialize(self)?;
+            let crc = crc32fast::hash(&buf);
+            buf.extend_from_slice(&crc.to_le_bytes());
+            Ok(buf)
+        }
+
+        fn decode(buf: &[u8], addr: Addr) -> Result<Self, BtreeError> {
+            let st: Self = bincode::deserialize(buf)?;
+            let len = bincode::serialized_size(&st)? as usize;
+            let crc = buf.get(len..len + CHECKSUM_SIZE as usize).ok_or_else(|| {
+                BtreeError::Corrupt(format!("overflow block is truncated at {}", addr))
+            })?;
+            if crc32fast::hash(&buf[..len]).to_le_bytes() != crc {
+                return Err(BtreeError::Corrupt(format!(
+                    "overflow block checksum mismatch at {}",
+                    addr
+                )));
+            }
+            Ok(st)
+        }
+
+        fn capacity(block_size: Block) -> usize {
+            // data bytes of one block, the rest is the next link, data length and checksum
+            let empty = OverflowStored {
+                next: Some(0),
+                data: Vec::new(),
+            };
+            (block_size as u64 - bincode::serialized_size(&empty).unwrap() - CHECKSUM_SIZE) as usize
+        }
+
+        fn blocks(len: usize, block_size: Block) -> usize {
+            len.div_ceil(Self::capacity(block_size))
+        }
+
+        // encoded blocks of the value, one per address, see OverflowStored::blocks
+        fn chain(
+            data: &[u8],
+            addrs: &[Addr],
+            block_size: Block,
+        ) -> Result<Vec<Vec<u8>>, BtreeError> {
+            debug_assert_eq!(addrs.len(), Self::blocks(data.len(), block_size));
+            data.chunks(Self::capacity(block_size))
+                .enumerate()
+                .map(|(i, data)| {
+                    let st = OverflowStored {
+                        next: addrs.get(i + 1).copied(),
+                        data: data.to_vec(),
+                    };
+                    st.encode()
+                })
+                .collect()
+        }
+
+        // reads the chain of the value, blocks are loaded by get
+        fn read_value<V: Val>(
+            head: Addr,
+            len: u64,
+            mut get: impl FnMut(Addr) -> Result<OverflowStored, BtreeError>,
+        ) -> Result<V, BtreeError> {
+            let mut data = Vec::with_capacity(len as usize);
+            let mut next = Some(head);
+            while let Some(addr) = next {
+                let block = get(addr)?;
+                data.extend_from_slice(&block.data);
+                // a looped or a broken chain is longer than the value
+                if block.data.is_empty() || data.len() as u64 > len {
+                    return Err(BtreeError::Corrupt(format!(
+                        "overflow chain is broken at {}",
+                        addr
+                    )));
+                }
+                next = block.next;
+            }
+            if data.len() as u64 != len {
+                return Err(BtreeError::Corrupt(format!(
+                    "overflow chain is truncated at {}",
+                    head
+                )));
+            }
+            Ok(bincode::deserialize(&data)?)
+        }
+    }
+
+    impl<K: Key, V: Val> Node<K, V> {
+        fn node_builder(bt: Btree<K, V>, leaf: bool) -> Result<Self, BtreeError> {
+            let addr = bt.alloc_block()?;
+            let node = Self(Rc::new(RefCell::new(NodeInner {
+                st: NodeStored::new(leaf),
+                addr,
+                bt: Rc::downgrade(&bt.0),
+            })));
+            node.flush();
+            Ok(node)
+        }
+
+        fn new(bt: &Btree<K, V>) -> Result<Self, BtreeError> {
+            debug!("Node::new: created");
+            Self::node_builder(bt.clone(), false)
+        }
+
+        fn new_leaf(bt: &Btree<K, V>) -> Result<Self, BtreeError> {
+            debug!("Node::new_leaf: created");
+            Self::node_builder(bt.clone(), true)
+        }
+
+        fn new_sibling(node: &Node<K, V>) -> Result<Self, BtreeError> {
+            if node.is_leaf() {
+                Node::new_leaf(&node.bt())
+            } else {
+                Node::new(&node.bt())
+            }
+        }
+
+        fn new_root(bt: &Btree<K, V>) -> Result<Self, BtreeError> {
+            let new_root = Node::new(bt)?;
+            new_root.bt().set_root(new_root.addr())?;
+            Ok(new_root)
+        }
+
+        fn bt(&self) -> Btree<K, V> {
+            Btree(
+                self.0
+                    .borrow()
+                    .bt
+                    .upgrade()
+                    .expect("node outlived its tree"),
+            )
+        }
+
+        fn split(
+            &self,
+            index: usize,
+            key: &K,
+            item_size: u64,
+        ) -> Result<SplitResult<K, V>, BtreeError> {
+            let sibling = Node::new_sibling(self)?;
+
+            // compute split index and insertion index
+            let middle = self.split_middle(index, key, item_size);
+            let (cut_idx, idx) = if index < middle {
+                (middle - 1, IdxSide::Left(index))
+            } else {
+                (middle, IdxSide::Right(index - middle))
+            };
+
+            // move part of elements to the new node (sibling)
+            sibling.append_n_from(self, cut_idx, self.degree() as usize);
+
+            if self.is_leaf() {
+                // update next/prev refs
+                sibling.set_next(self.next());
+                sibling.set_prev(Some(self.addr()));
+                if let Some(next) = self.next() {
+                    self.bt().get_node(next)?.set_prev(Some(sibling.addr()));
+                }
+                self.set_next(Some(sibling.addr()));
+            }
+
+            // if there is no parent - create one
+            let mut new_root = None;
+            if self.is_root() {
+                new_root = Some(Node::new_root(&self.bt())?.addr());
+            }
+
+            Ok((new_root, sibling, idx))
+        }
+
+        fn split_middle(&self, index: usize, key: &K, item_size: u64) -> usize {
+            // number of elements (the new one included) left in the first half.
+            // fixed size elements are split by count, variable length ones
+            // by the encoded size, so both halves have room for the new element.
+            // Sizes are taken with the key compression of the node and the new element,
+            // a half compresses at least as well.
+            let (mut sizes, item_size) = {
+                let st = &self.0.borrow().st;
+                let mut page = st.page();
+                page.add(key, item_size);
+                let sizes: Vec<u64> = st
+                    .entry_sizes()
+                    .into_iter()
+                    .map(|size| page.entry_size(size))
+                    .collect();
+                (sizes, page.entry_size(item_size))
+            };
+            if sizes.iter().all(|size| *size == item_size) {
+                return self.degree().div_ceil(2) as usize;
+            }
+            sizes.insert(index, item_size);
+            let total: u64 = sizes.iter().sum();
+            let mut acc = 0;
+            let mut middle = 0;
+            for size in sizes.iter() {
+                if acc + size > total / 2 {
+                    break;
+                }
+                acc += size;
+                middle += 1;
+            }
+            // both halves keep at least one of the old elements
+            let lo = if index == 0 { 2 } else { 1 };
+            let hi = if index < self.degree() as usize {
+                self.degree() as usize
+            } else {
+                self.degree() as usize - 1
+            };
+            middle.clamp(lo, hi)
+        }
+
+        fn set_addr(&self, addr: Addr) {
+            trace!("Node::set_addr: old={:?}, new={:?}", self.addr(), addr);
             self.0.borrow_mut().addr = addr;
             self.flush();
         }
@@ -393,7 +1501,7 @@ pub mod btree {
             self.flush();
         }
 
-        fn set_next_from(&self, other: &Node) {
+        fn set_next_from(&self, other: &Node<K, V>) -> Result<(), BtreeError> {
             debug_assert!(self.is_leaf());
             trace!(
                 "Node::set_next: old={:?}, new={:?}",
@@ -401,7 +1509,11 @@ pub mod btree {
                 other.next()
             );
             self.set_next(other.next());
+            if let Some(next) = other.next() {
+                self.bt().get_node(next)?.set_prev(Some(self.addr()));
+            }
             self.flush();
+            Ok(())
         }
 
         fn next(&self) -> Option<Addr> {
@@ -409,6 +1521,22 @@ pub mod btree {
             self.0.borrow().st.next
         }
 
+        fn set_prev(&self, addr: Option<Addr>) {
+            debug_assert!(self.is_leaf());
+            trace!(
+                "Node::set_prev: old={:?}, new={:?}",
+                self.0.borrow().st.prev,
+                addr
+            );
+            self.0.borrow_mut().st.prev = addr;
+            self.flush();
+        }
+
+        fn prev(&self) -> Option<Addr> {
+            debug_assert!(self.is_leaf());
+            self.0.borrow().st.prev
+        }
+
         fn is_leaf(&self) -> bool {
             self.0.borrow().st.leaf
         }
@@ -419,16 +1547,13 @@ pub mod btree {
                 self,
                 other
             );
-            self.0
-                .borrow_mut()
-                .st
-                .keys
-                .append(&mut other.0.borrow_mut().st.keys);
-            self.0
-                .borrow_mut()
-                .st
-                .vals
-                .append(&mut other.0.borrow_mut().st.vals);
+            {
+                let mut self_inner = self.0.borrow_mut();
+                let mut other_inner = other.0.borrow_mut();
+                self_inner.st.keys.append(&mut other_inner.st.keys);
+                self_inner.st.vals.append(&mut other_inner.st.vals);
+                self_inner.st.refs.append(&mut other_inner.st.refs);
+            }
             self.flush();
             trace!("Node::append_from: done self={:?}, other={:?}", self, other);
         }
@@ -441,16 +1566,26 @@ pub mod btree {
                 start,
                 stop
             );
-            self.0
-                .borrow_mut()
-                .st
-                .keys
-                .extend(other.0.borrow_mut().st.keys.drain(start..stop));
-            self.0
-                .borrow_mut()
-                .st
-                .vals
-                .extend(other.0.borrow_mut().st.vals.drain(start..stop));
+            {
+                let mut self_inner = self.0.borrow_mut();
+                let mut other_inner = other.0.borrow_mut();
+                let leaf = other_inner.st.leaf;
+                self_inner
+                    .st
+                    .keys
+                    .extend(other_inner.st.keys.drain(start..stop));
+                if leaf {
+                    self_inner
+                        .st
+                        .vals
+                        .extend(other_inner.st.vals.drain(start..stop));
+                } else {
+                    self_inner
+                        .st
+                        .refs
+                        .extend(other_inner.st.refs.drain(start..stop));
+                }
+            }
             self.flush();
             other.flush();
             trace!(
@@ -470,11 +1605,22 @@ pub mod btree {
             );
             {
                 let mut node_inner = self.0.borrow_mut();
-                for key in other.0.borrow_mut().st.keys.drain(start..stop).rev() {
-                    node_inner.st.keys.insert(0, key);
-                }
-                for val in other.0.borrow_mut().st.vals.drain(start..stop).rev() {
-                    node_inner.st.vals.insert(0, val);
+                let mut other_inner = other.0.borrow_mut();
+                let leaf = other_inner.st.leaf;
+                node_inner
+                    .st
+                    .keys
+                    .splice(0..0, other_inner.st.keys.drain(start..stop));
+                if leaf {
+                    node_inner
+                        .st
+                        .vals
+                        .splice(0..0, other_inner.st.vals.drain(start..stop));
+                } else {
+                    node_inner
+                        .st
+                        .refs
+                        .splice(0..0, other_inner.st.refs.drain(start..stop));
                 }
             }
             self.flush();
@@ -482,10 +1628,10 @@ pub mod btree {
         }
 
         fn degree(&self) -> Degree {
-            self.0.borrow().st.vals.len() as Degree
+            self.0.borrow().st.keys.len() as Degree
         }
 
-        fn min_key(&self) -> Key {
+        fn min_key(&self) -> K {
             if self.is_leaf() {
                 self.get_key(0)
             } else {
@@ -493,22 +1639,19 @@ pub mod btree {
             }
         }
 
-        fn fist_child_addr(&self) -> Val {
+        fn fist_child_addr(&self) -> Addr {
             debug_assert!(!self.is_leaf());
-            self.get_val(0)
+            self.get_ref(0)
         }
 
-        fn child_min_key(&self) -> Key {
+        fn child_min_key(&self) -> K {
             debug_assert!(!self.is_leaf());
             self.get_key(0)
         }
 
         fn is_drained(&self) -> bool {
             if self.is_root() {
-                if self.is_empty() {
-                    return true;
-                }
-                return false;
+                return self.is_empty();
             }
 
             let min_degree = self.bt().min_degree();
@@ -523,6 +1666,20 @@ pub mod btree {
             self.degree() == self.bt().max_degree()
         }
 
+        fn has_room(&self, key: &K, item_size: u64) -> bool {
+            // the new key may shorten the common prefix of the leaf keys
+            if self.is_full() {
+                return false;
+            }
+            let mut page = self.0.borrow().st.page();
+            page.add(key, item_size);
+            page.size() <= self.bt().block_size() as u64
+        }
+
+        fn encoded_size(&self) -> u64 {
+            self.0.borrow().st.encoded_size()
+        }
+
         fn is_empty(&self) -> bool {
             if self.is_leaf() {
                 self.degree() == 0
@@ -532,7 +1689,18 @@ pub mod btree {
         }
 
         fn can_merge(&self, other: &Self) -> bool {
-            self.degree() + other.degree() <= self.bt().max_degree()
+            if self.degree() + other.degree() > self.bt().max_degree() {
+                return false;
+            }
+            let mut page = self.0.borrow().st.page();
+            page.merge(&other.0.borrow().st.page());
+            page.size() <= self.bt().block_size() as u64
+        }
+
+        fn can_take(&self, other: &Self, start: usize, stop: usize) -> bool {
+            let mut page = self.0.borrow().st.page();
+            page.merge(&other.0.borrow().st.page_part(start, stop));
+            page.size() <= self.bt().block_size() as u64
         }
 
         fn addr(&self) -> Addr {
@@ -543,16 +1711,24 @@ pub mod btree {
             self.addr() == self.bt().root()
         }
 
-        fn remove(&self, index: usize) -> (Key, Val) {
+        fn remove(&self, index: usize) -> (K, Item<V>) {
             debug!("Node::remove: index={}", index);
-            let key = self.0.borrow_mut().st.keys.remove(index);
-            let val = self.0.borrow_mut().st.vals.remove(index);
+            let (key, item) = {
+                let mut inner = self.0.borrow_mut();
+                let key = inner.st.keys.remove(index);
+                let item = if inner.st.leaf {
+                    Item::Val(inner.st.vals.remove(index))
+                } else {
+                    Item::Ref(inner.st.refs.remove(index))
+                };
+                (key, item)
+            };
             self.flush();
-            (key, val)
+            (key, item)
         }
 
-        fn find(&self, key: Key) -> Result<usize, usize> {
-            debug!("Node::find: key={}", key);
+        fn find(&self, key: &K) -> Result<usize, usize> {
+            debug!("Node::find: key={:?}", key);
             let node_internal = self.0.borrow();
             let keys = if self.is_leaf() {
                 node_internal.st.keys.as_slice()
@@ -560,7 +1736,7 @@ pub mod btree {
                 &node_internal.st.keys[1..]
             };
 
-            match keys.binary_search(&key) {
+            match keys.binary_search(key) {
                 Ok(idx) => {
                     if self.is_leaf() {
                         Ok(idx)
@@ -578,125 +1754,343 @@ pub mod btree {
             }
         }
 
-        fn insert(&self, index: usize, key: Key, val: Val) {
+        fn insert(&self, index: usize, key: K, item: Item<V>) {
             info!(
-                "Node::insert: index={}, key={}, val={}, len={}",
+                "Node::insert: index={}, key={:?}, item={:?}, len={}",
                 index,
                 key,
-                val,
-                self.0.borrow_mut().st.keys.len()
+                item,
+                self.0.borrow().st.keys.len()
             );
-            self.0.borrow_mut().st.keys.insert(index, key);
-            self.0.borrow_mut().st.vals.insert(index, val);
+            {
+                let mut inner = self.0.borrow_mut();
+                inner.st.keys.insert(index, key);
+                match item {
+                    Item::Val(val) => inner.st.vals.insert(index, val),
+                    Item::Ref(addr) => inner.st.refs.insert(index, addr),
+                }
+            }
             self.flush();
         }
 
-        fn update_key(&self, index: usize, new_key: Key) -> Key {
-            trace!("Node::update_key: index={}, new_key={}", index, new_key);
-            let old_key = self.get_key(index);
-            self.0.borrow_mut().st.keys[index] = new_key;
+        fn update_key(&self, index: usize, new_key: K) -> K {
+            trace!("Node::update_key: index={}, new_key={:?}", index, new_key);
+            let old_key = std::mem::replace(&mut self.0.borrow_mut().st.keys[index], new_key);
             self.flush();
             old_key
         }
 
-        fn update_val(&self, index: usize, new_val: Val) -> Val {
-            trace!("Node::update_val: index={}, new_val={}", index, new_val);
-            let old_val = self.get_val(index);
-            self.0.borrow_mut().st.vals[index] = new_val;
+        fn update_val(&self, index: usize, new_val: Slot<V>) -> Slot<V> {
+            trace!("Node::update_val: index={}, new_val={:?}", index, new_val);
+            let old_val = std::mem::replace(&mut self.0.borrow_mut().st.vals[index], new_val);
             self.flush();
             old_val
         }
 
+        fn update_ref(&self, index: usize, new_addr: Addr) -> Addr {
+            trace!("Node::update_ref: index={}, new_addr={}", index, new_addr);
+            let old_addr = std::mem::replace(&mut self.0.borrow_mut().st.refs[index], new_addr);
+            self.flush();
+            old_addr
+        }
+
         fn flush(&self) {
+            // write errors are reported when the operation is committed
             trace!("Node:flush: self={:?}", self);
-            if self.bt().cache_cap() != 0 {
-                if self.bt().cache_get(self.addr()).is_some() {
-                    return;
-                }
+            let bt = self.bt();
+            bt.mark_dirty(self);
+            let result = if bt.cache_cap() != 0 {
+                // written back when evicted or on the cache flush
+                bt.cache_put(self, true)
+            } else {
+                bt.flush_node(self)
+            };
+            if let Err(err) = result {
+                bt.set_error(err);
+            }
+        }
 
-                let result = self.bt().cache_put(&self);
-                if result.is_none() {
-                    return;
-                }
+        fn get_refs(&self) -> Vec<Addr> {
+            self.0.borrow().st.refs.clone()
+        }
 
-                self.bt().flush_node(&result.unwrap());
-            } else {
-                self.bt().flush_node(&self);
+        fn search(&self, key: &K) -> LeafSearch<Slot<V>> {
+            // same as NodeView::search
+            let idx = self.bound(key, false);
+            if idx == self.degree() as usize {
+                return self.next().map_or(LeafSearch::Missing, LeafSearch::Next);
+            }
+            if self.get_key(idx) != *key {
+                return LeafSearch::Missing;
             }
+            LeafSearch::Found(self.0.borrow().st.vals[idx].clone())
         }
 
-        fn get_vals(&self) -> Vec<Val> {
-            self.0.borrow().st.vals.clone()
+        fn get_val(&self, index: usize) -> Result<V, BtreeError> {
+            let slot = self.0.borrow().st.vals[index].clone();
+            self.bt().read_val(slot)
         }
 
-        fn get_val(&self, index: usize) -> Val {
-            self.0.borrow().st.vals[index]
+        fn get_ref(&self, index: usize) -> Addr {
+            self.0.borrow().st.refs[index]
         }
 
-        fn get_key(&self, index: usize) -> Key {
-            self.0.borrow().st.keys[index]
+        fn get_key(&self, index: usize) -> K {
+            self.0.borrow().st.keys[index].clone()
         }
 
-        fn find_next_node(&self, key: Key) -> (Addr, PathStep) {
-            debug_assert!(!self.is_leaf());
-            trace!("Node:find_next_node: key={}", key);
-            let index = match self.find(key) {
-                Ok(idx) => idx,
-                Err(idx) => idx - 1,
+        fn bound(&self, key: &K, upper: bool) -> usize {
+            // leaf: position of the first key greater than (upper) or not less than the key,
+            // internal: index of the child, where such a key could be.
+            // Bounds differ only if the tree has equal keys, see multimap.
+            if upper {
+                self.bound_by(&|k| k <= key)
+            } else {
+                self.bound_by(&|k| k < key)
+            }
+        }
+
+        fn bound_by(&self, before: &dyn Fn(&K) -> bool) -> usize {
+            // same as bound for the first key, which is not `before` the bound,
+            // `before` holds for the keys up to some position and not after it
+            let inner = self.0.borrow();
+            let keys = if self.is_leaf() {
+                &inner.st.keys[..]
+            } else {
+                &inner.st.keys[1..]
             };
+            keys.partition_point(|k| before(k))
+        }
+
+        fn find_next_node(&self, before: &dyn Fn(&K) -> bool) -> (Addr, PathStep) {
+            debug_assert!(!self.is_leaf());
+            let index = self.bound_by(before);
+            (self.get_ref(index), self.path_step(index))
+        }
 
-            let next_node_addr = self.get_val(index);
-            let step;
+        fn path_step(&self, index: usize) -> PathStep {
             if index == 0 {
-                step = PathStep::new(
+                PathStep::new(
                     None,
-                    Some((index + 1, self.get_val(index + 1))),
+                    Some((index + 1, self.get_ref(index + 1))),
                     (index, self.addr()),
                 )
             } else if self.degree() as usize - 1 > index {
-                step = PathStep::new(
-                    Some((index - 1, self.get_val(index - 1))),
-                    Some((index + 1, self.get_val(index + 1))),
+                PathStep::new(
+                    Some((index - 1, self.get_ref(index - 1))),
+                    Some((index + 1, self.get_ref(index + 1))),
                     (index, self.addr()),
                 )
             } else {
-                step = PathStep::new(
-                    Some((index - 1, self.get_val(index - 1))),
+                PathStep::new(
+                    Some((index - 1, self.get_ref(index - 1))),
                     None,
                     (index, self.addr()),
                 )
             }
-            return (next_node_addr, step);
         }
     }
 
-    impl TaskManager {
-        fn new() -> Self {
-            trace!("TaskManager:new: called");
-            Self {
-                deq: VecDeque::new(),
+    impl<V: Val> Item<V> {
+        fn encoded_size(&self) -> u64 {
+            match self {
+                Item::Val(val) => bincode::serialized_size(val).unwrap(),
+                Item::Ref(_) => size_of::<Addr>() as u64,
             }
         }
+    }
 
-        fn add_insert(&mut self, target: InsertTarget, index: usize, key: Key, val: Val) {
-            trace!(
-                "TaskManager:add_insert: target={:?}, index={}, key={}, val={}",
-                target,
-                index,
-                key,
-                val,
-            );
-            self.deq.push_back(Task::Insert {
-                target,
-                index,
-                key,
-                val,
-            });
+    impl<K: Key, V: Val> BulkLoader<K, V> {
+        // Writes one level, returns the first key and the address of each node.
+        // The last node is merged with or takes entries from the previous one,
+        // so the level has no drained nodes.
+        fn level<I: Iterator<Item = Result<(K, Item<V>), BtreeError>>>(
+            &mut self,
+            leaf: bool,
+            items: I,
+        ) -> Result<Vec<(K, Addr)>, BtreeError> {
+            let mut parents = Vec::new();
+            let mut prev: Option<(NodeStored<K, Slot<V>>, Addr)> = None;
+            let mut node = NodeStored::new(leaf);
+            let mut page = PageSize::new(leaf);
+            for item in items {
+                let (key, item) = item?;
+                let item = match item {
+                    Item::Val(slot) => Item::Val(self.spill(&key, slot)?),
+                    item => item,
+                };
+                let item_size =
+                    bincode::serialized_size(&key)? + item.encoded_size() + PAGE_SLOT_SIZE;
+                let mut next_page = page.clone();
+                next_page.add(&key, item_size);
+                let size = next_page.size();
+                let degree = node.keys.len() as Degree;
+                // low fill factor doesn't make nodes drained
+                if degree >= 2
+                    && (degree >= self.fill_degree
+                        || size > self.block_size as u64
+                        || (degree > self.min_degree && size > self.fill_size))
+                {
+                    // address of the closed node is known, the previous one can be written
+                    let addr = self.alloc();
+                    if let Some((mut prev, prev_addr)) = prev.take() {
+                        if leaf {
+                            prev.next = Some(addr);
+                            node.prev = Some(prev_addr);
+                        }
+                        self.write(&prev, prev_addr, &mut parents)?;
+                    }
+                    prev = Some((node, addr));
+                    node = NodeStored::new(leaf);
+                    next_page = PageSize::new(leaf);
+                    next_page.add(&key, item_size);
+                }
+                page = next_page;
+                node.keys.push(key);
+                match item {
+                    Item::Val(val) => node.vals.push(val),
+                    Item::Ref(addr) => node.refs.push(addr),
+                }
+            }
+
+            let (mut prev, prev_addr) = match prev {
+                Some(prev) => prev,
+                None => {
+                    // the only node is the root, it could be empty
+                    let addr = self.alloc();
+                    self.write(&node, addr, &mut parents)?;
+                    return Ok(parents);
+                }
+            };
+            let needed = if leaf {
+                self.min_degree
+            } else {
+                self.min_degree + 1
+            } as usize;
+            if node.keys.len() < needed {
+                let mut merged = prev.page();
+                merged.merge(&page);
+                if ((prev.keys.len() + node.keys.len()) as Degree) <= self.max_degree
+                    && merged.size() <= self.block_size as u64
+                {
+                    prev.keys.append(&mut node.keys);
+                    prev.vals.append(&mut node.vals);
+                    prev.refs.append(&mut node.refs);
+                    self.write(&prev, prev_addr, &mut parents)?;
+                    return Ok(parents);
+                }
+                let sizes = prev.entry_sizes();
+                while node.keys.len() < needed && prev.keys.len() > needed {
+                    let last = prev.keys.len() - 1;
+                    page.add(&prev.keys[last], sizes[last]);
+                    if page.size() > self.block_size as u64 {
+                        break;
+                    }
+                    node.keys.insert(0, prev.keys.pop().unwrap());
+                    if leaf {
+                        node.vals.insert(0, prev.vals.pop().unwrap());
+                    } else {
+                        node.refs.insert(0, prev.refs.pop().unwrap());
+                    }
+                }
+            }
+            let addr = self.alloc();
+            if leaf {
+                prev.next = Some(addr);
+                node.prev = Some(prev_addr);
+            }
+            self.write(&prev, prev_addr, &mut parents)?;
+            self.write(&node, addr, &mut parents)?;
+            Ok(parents)
+        }
+
+        fn alloc(&mut self) -> Addr {
+            let addr = self.next_addr as Addr;
+            self.next_addr += self.block_size as u64;
+            addr
+        }
+
+        fn write(
+            &mut self,
+            st: &NodeStored<K, Slot<V>>,
+            addr: Addr,
+            parents: &mut Vec<(K, Addr)>,
+        ) -> Result<(), BtreeError> {
+            self.write_block(addr, st.encode()?)?;
+            if let Some(key) = st.keys.first() {
+                parents.push((key.clone(), addr));
+            }
+            Ok(())
+        }
+
+        fn write_block(&mut self, addr: Addr, mut block: Vec<u8>) -> Result<(), BtreeError> {
+            // blocks are mostly written in address order, right after the previous one.
+            // A node is written after the overflow blocks of the next node, then out seeks.
+            if addr as u64 + self.block_size as u64 > Addr::MAX as u64 + 1 {
+                return Err(BtreeError::FileFull);
+            }
+            if self.pos != addr as u64 {
+                self.out.seek(SeekFrom::Start(addr as u64))?;
+            }
+            block.resize(self.block_size as usize, 0);
+            self.out.write_all(&block)?;
+            self.pos = addr as u64 + self.block_size as u64;
+            Ok(())
+        }
+
+        fn spill(&mut self, key: &K, slot: Slot<V>) -> Result<Slot<V>, BtreeError> {
+            // same as Btree::spill, the blocks are written at once
+            if bincode::serialized_size(key)? + bincode::serialized_size(&slot)? + PAGE_SLOT_SIZE
+                <= self.entry_limit
+            {
+                return Ok(slot);
+            }
+            let data = match &slot {
+                Slot::Inline(val) => bincode::serialize(val)?,
+                Slot::Overflow { .. } => return Ok(slot),
+            };
+            let addrs: Vec<Addr> = (0..OverflowStored::blocks(data.len(), self.block_size))
+                .map(|_| self.alloc())
+                .collect();
+            let blocks = OverflowStored::chain(&data, &addrs, self.block_size)?;
+            for (addr, block) in addrs.iter().zip(blocks) {
+                self.write_block(*addr, block)?;
+            }
+            Ok(Slot::Overflow {
+                head: addrs[0],
+                len: data.len() as u64,
+            })
+        }
+    }
+
+    impl<K: Key, V: Val> TaskManager<K, V> {
+        fn new() -> Self {
+            trace!("TaskManager:new: called");
+            Self {
+                deq: VecDeque::new(),
+                oversized: Vec::new(),
+            }
+        }
+
+        fn add_insert(&mut self, target: InsertTarget<K, V>, index: usize, key: K, item: Item<V>) {
+            trace!(
+                "TaskManager:add_insert: target={:?}, index={}, key={:?}, item={:?}",
+                target,
+                index,
+                key,
+                item,
+            );
+            self.deq.push_back(Task::Insert {
+                target,
+                index,
+                key,
+                item,
+            });
         }
 
-        fn add_update(&mut self, pref: PathRef, index: usize, new_key: Key) {
+        fn add_update(&mut self, pref: PathRef<K, V>, index: usize, new_key: K) {
             trace!(
-                "TaskManager:add_update: pref={:?}, index={}, new_key={}",
+                "TaskManager:add_update: pref={:?}, index={}, new_key={:?}",
                 pref,
                 index,
                 new_key,
@@ -708,93 +2102,115 @@ pub mod btree {
             });
         }
 
-        fn add_remove(&mut self, pref: PathRef, index: usize) {
+        fn add_remove(&mut self, pref: PathRef<K, V>, index: usize) {
             trace!("TaskManager:add_remove: pref={:?}, index={}", pref, index,);
             self.deq.push_back(Task::Remove { pref, index });
         }
 
-        fn add_rebalance(&mut self, pref: PathRef) {
+        fn add_rebalance(&mut self, pref: PathRef<K, V>) {
             trace!("TaskManager:add_rebelance: pref={:?}", pref);
             self.deq.push_back(Task::Rebalance { pref });
         }
 
-        fn add_split(&mut self, pref: PathRef, index: usize, key: Key, val: Val) {
+        fn add_split(&mut self, pref: PathRef<K, V>, index: usize, key: K, item: Item<V>) {
             trace!(
-                "TaskManager:add_split: pref={:?}, index={}, key={}, val={}",
+                "TaskManager:add_split: pref={:?}, index={}, key={:?}, item={:?}",
                 pref,
                 index,
                 key,
-                val,
+                item,
             );
             self.deq.push_back(Task::Split {
                 pref,
                 index,
                 key,
-                val,
+                item,
             });
         }
 
-        fn run(&mut self) {
-            debug_assert!(self.deq.len() > 0);
+        fn run(&mut self) -> Result<(), BtreeError> {
+            debug_assert!(!self.deq.is_empty());
             trace!("TaskManager:run: called");
 
-            while self.deq.len() > 0 {
-                match self.deq.pop_front().unwrap() {
+            loop {
+                let task = match self.deq.pop_front() {
+                    Some(task) => task,
+                    None => match self.split_oversized()? {
+                        Some(task) => task,
+                        None => break,
+                    },
+                };
+                match task {
                     Task::Insert {
                         target,
                         index,
                         key,
-                        val,
-                    } => self.insert_util(target, index, key, val),
+                        item,
+                    } => self.insert_util(target, index, key, item),
                     Task::Rebalance { pref } => self.rebalance_util(pref),
                     Task::Remove { pref, index } => self.remove_util(pref, index),
                     Task::Split {
                         pref,
                         index,
                         key,
-                        val,
-                    } => self.split_util(pref, index, key, val),
+                        item,
+                    } => self.split_util(pref, index, key, item),
                     Task::Update {
                         pref,
                         index,
                         new_key,
                     } => self.update_util(pref, index, new_key),
-                }
+                }?;
             }
+            Ok(())
         }
 
-        fn insert_util(&mut self, target: InsertTarget, index: usize, key: Key, val: Key) {
+        fn insert_util(
+            &mut self,
+            target: InsertTarget<K, V>,
+            index: usize,
+            key: K,
+            item: Item<V>,
+        ) -> Result<(), BtreeError> {
             debug!(
-                "TaskManager:insert_util: target={:?}, i={}, k={}, v={}",
-                target, index, key, val
+                "TaskManager:insert_util: target={:?}, i={}, k={:?}, v={:?}",
+                target, index, key, item
             );
 
             let (pref, node) = match target {
-                InsertTarget::Ref(pref) => (pref.clone(), pref.node()),
-                InsertTarget::RefNodeAddr((pref, addr)) => (pref.clone(), pref.bt().get_node(addr)),
+                InsertTarget::Ref(pref) => (pref.clone(), pref.node()?),
+                InsertTarget::RefNodeAddr((pref, addr)) => {
+                    (pref.clone(), pref.bt().get_node(addr)?)
+                }
             };
 
-            if node.is_full() {
-                self.add_split(pref, index, key, val);
-                return;
-            } else {
-                node.insert(index, key, val);
+            // the new value goes to the overflow blocks, if the entry is too large
+            let item = match item {
+                Item::Val(slot) => Item::Val(pref.bt().spill(&key, slot)?),
+                item => item,
+            };
+            let item_size = bincode::serialized_size(&key)? + item.encoded_size() + PAGE_SLOT_SIZE;
+            if !node.has_room(&key, item_size) {
+                self.add_split(pref, index, key, item);
+                return Ok(());
             }
+            node.insert(index, key.clone(), item);
 
             if index != 0 || pref.top() || node.is_root() || pref.node_addr() != node.addr() {
                 trace!("TaskManager:insert_util: done, plain insert, short path");
-                return;
+                return Ok(());
             }
 
             // we update min_key and should update parent
             // leftmost node of the tree. special case.
             self.add_update(pref.parent_ref().unwrap(), pref.node_idx().unwrap(), key);
             trace!("TaskManager:insert_util: done, long path");
+            Ok(())
         }
 
-        fn rebalance_util(&mut self, pref: PathRef) {
+        fn rebalance_util(&mut self, pref: PathRef<K, V>) -> Result<(), BtreeError> {
             trace!("TaskManager:rebalance_util: pref={:?}", pref);
-            let mut node = pref.node();
+            let node = pref.node()?;
             let from_right = pref.right_sibling_addr().is_some();
             let sibling_idx = if from_right {
                 pref.right_sibling_idx().unwrap()
@@ -802,15 +2218,15 @@ pub mod btree {
                 pref.left_sibling_idx().unwrap()
             };
 
-            let mut sibling: Node;
-            if from_right {
-                sibling = pref.right_sibling().unwrap();
+            let sibling = if from_right {
+                pref.right_sibling()?.unwrap()
             } else {
-                sibling = pref.left_sibling().unwrap();
-            }
+                pref.left_sibling()?.unwrap()
+            };
 
             if node.can_merge(&sibling) {
                 // merge
+                pref.bt().count(|counters| &counters.merges);
                 if from_right {
                     if node.is_leaf() && node.is_empty() {
                         self.add_update(
@@ -819,18 +2235,20 @@ pub mod btree {
                             sibling.min_key(),
                         );
                     }
-                    node.append_from(&mut sibling);
+                    node.append_from(&sibling);
                     if node.is_leaf() {
-                        node.set_next_from(&sibling);
+                        node.set_next_from(&sibling)?;
                     }
+                    pref.bt().free_node(&sibling)?;
                 } else {
                     if node.is_leaf() && sibling.is_empty() {
                         self.add_update(pref.parent_ref().unwrap(), sibling_idx, sibling.min_key());
                     }
-                    sibling.append_from(&mut node);
+                    sibling.append_from(&node);
                     if node.is_leaf() {
-                        sibling.set_next_from(&node);
+                        sibling.set_next_from(&node)?;
                     }
+                    pref.bt().free_node(&node)?;
                 }
                 let parent_index = if from_right {
                     pref.right_sibling_idx().unwrap()
@@ -840,19 +2258,35 @@ pub mod btree {
                 self.add_remove(pref.parent_ref().unwrap(), parent_index);
             } else {
                 // rebalance
-                let num_taken = (node.degree() + sibling.degree()) / 2 - node.degree();
+                pref.bt().count(|counters| &counters.rebalances);
+                let mut num_taken =
+                    ((node.degree() + sibling.degree()) / 2).saturating_sub(node.degree());
+                // variable length elements: take only as much as fits to the node
+                while num_taken > 0 && {
+                    let (start, stop) = if from_right {
+                        (0, num_taken as usize)
+                    } else {
+                        (
+                            (sibling.degree() - num_taken) as usize,
+                            sibling.degree() as usize,
+                        )
+                    };
+                    !node.can_take(&sibling, start, stop)
+                } {
+                    num_taken -= 1;
+                }
                 if from_right {
                     // remove from middle/end.
                     // underflow/rebalance needed. Remove key, rebalance from right sibling. No merge.
                     let start = 0;
                     let stop = num_taken as usize;
-                    node.append_n_from(&mut sibling, start, stop);
+                    node.append_n_from(&sibling, start, stop);
                 } else {
                     // remove from middle/end.
                     // underflow/rebalance needed. Remove key, rebalance from left sibling. No merge.
                     let start = (sibling.degree() - num_taken) as usize;
                     let stop = sibling.degree() as usize;
-                    node.push_front_n_from(&mut sibling, start, stop);
+                    node.push_front_n_from(&sibling, start, stop);
                 }
 
                 let node = if from_right { sibling } else { node };
@@ -868,27 +2302,31 @@ pub mod btree {
                 };
                 self.add_update(pref.parent_ref().unwrap(), parent_index, new_min_key);
             }
+            Ok(())
         }
 
-        fn remove_util(&mut self, pref: PathRef, index: usize) {
+        fn remove_util(&mut self, pref: PathRef<K, V>, index: usize) -> Result<(), BtreeError> {
             trace!("TaskManager:remove_util: pref={:?}, index={}", pref, index);
-            let node = pref.node();
-            debug_assert!(!(!node.is_leaf() && index == 0));
+            let node = pref.node()?;
+            debug_assert!(node.is_leaf() || index != 0);
 
             // simple remove from the middle/end.
             // no underflow/rebalance, no min_key change.
-            let (_, _) = node.remove(index);
+            if let (_, Item::Val(slot)) = node.remove(index) {
+                // the value is gone with the entry
+                pref.bt().free_overflow(&slot)?;
+            }
 
             if node.is_drained() && node.is_root() && !node.is_leaf() {
                 // underflow - rebalance needed.
                 // Remove key, merge with LEFT/RIGHT sibling.
                 // Remove min_key from parent. Parrent merge needed (remove root as case).
-                pref.bt().set_root(node.fist_child_addr());
-                return;
+                pref.bt().set_root(node.fist_child_addr())?;
+                return pref.bt().free_node(&node);
             }
 
             if node.is_root() {
-                return;
+                return Ok(());
             }
 
             // remove min_key from leaf.
@@ -916,126 +2354,186 @@ pub mod btree {
                 self.add_rebalance(pref);
             }
             // happy path. No rebalance/merge/update needed.
+            Ok(())
         }
 
-        fn split_util(&mut self, pref: PathRef, index: usize, key: Key, val: Val) {
+        fn split_util(
+            &mut self,
+            pref: PathRef<K, V>,
+            index: usize,
+            key: K,
+            item: Item<V>,
+        ) -> Result<(), BtreeError> {
             trace!(
-                "TaskManager:split_util: pref={:?}, index={}, key={}, val={}",
+                "TaskManager:split_util: pref={:?}, index={}, key={:?}, item={:?}",
                 pref,
                 index,
                 key,
-                val,
+                item,
             );
 
-            let node = pref.node();
-            let (new_root_addr, sibling, direction) = node.split(index);
+            let node = pref.node()?;
+            let item_size = bincode::serialized_size(&key)? + item.encoded_size() + PAGE_SLOT_SIZE;
+            let (new_root_addr, sibling, direction) = node.split(index, &key, item_size)?;
+            pref.bt().count(|counters| &counters.splits);
 
             // return control back to insert
             match direction {
                 IdxSide::Left(idx) => {
-                    self.add_insert(InsertTarget::Ref(pref.clone()), idx, key, val);
+                    self.add_insert(InsertTarget::Ref(pref.clone()), idx, key.clone(), item);
                 }
                 IdxSide::Right(idx) => {
                     self.add_insert(
                         InsertTarget::RefNodeAddr((pref.clone(), sibling.addr())),
                         idx,
-                        key,
-                        val,
+                        key.clone(),
+                        item,
                     );
                 }
             }
 
-            if new_root_addr.is_some() {
+            if let Some(new_root_addr) = new_root_addr {
                 let key = match direction {
-                    IdxSide::Left(0) => key,
+                    IdxSide::Left(0) => key.clone(),
                     _ => node.min_key(),
                 };
                 // insert to the new_root min_key from node
                 self.add_insert(
-                    InsertTarget::RefNodeAddr((pref.clone(), *new_root_addr.as_ref().unwrap())),
+                    InsertTarget::RefNodeAddr((pref.clone(), new_root_addr)),
                     0,
                     key,
-                    node.addr(),
+                    Item::Ref(node.addr()),
                 );
             }
 
-            let parent_target = if new_root_addr.is_some() {
-                InsertTarget::RefNodeAddr((pref.clone(), *new_root_addr.as_ref().unwrap()))
-            } else {
-                InsertTarget::Ref(pref.parent_ref().unwrap())
+            let parent_target = match new_root_addr {
+                Some(new_root_addr) => InsertTarget::RefNodeAddr((pref.clone(), new_root_addr)),
+                None => InsertTarget::Ref(pref.parent_ref().unwrap()),
             };
             let parent_sibling_idx = if new_root_addr.is_some() {
                 1
             } else {
                 pref.node_idx().unwrap() + 1
             };
-            let sibling_min_key = if !sibling.is_leaf() {
-                match direction {
-                    IdxSide::Right(0) => key,
-                    _ => sibling.child_min_key(),
-                }
+            let left_max_key = match direction {
+                IdxSide::Left(idx) if idx == node.degree() as usize => key.clone(),
+                _ => node.get_key(node.degree() as usize - 1),
+            };
+            let sibling_min_key = match direction {
+                IdxSide::Right(0) => key,
+                _ if !sibling.is_leaf() => sibling.child_min_key(),
+                _ => sibling.min_key(),
+            };
+            // leaves only need a separator that tells them apart
+            let sibling_min_key = if sibling.is_leaf() {
+                separator(&left_max_key, &sibling_min_key)
             } else {
-                match direction {
-                    IdxSide::Right(0) => key,
-                    _ => sibling.min_key(),
-                }
+                sibling_min_key
             };
 
             self.add_insert(
                 parent_target,
                 parent_sibling_idx,
                 sibling_min_key,
-                sibling.addr(),
+                Item::Ref(sibling.addr()),
             );
+            Ok(())
         }
 
-        fn update_util(&mut self, pref: PathRef, index: usize, new_key: Addr) {
+        fn update_util(
+            &mut self,
+            pref: PathRef<K, V>,
+            index: usize,
+            new_key: K,
+        ) -> Result<(), BtreeError> {
             trace!(
-                "TaskManager:update_util: pref={:?}, index={}, new_key={}",
+                "TaskManager:update_util: pref={:?}, index={}, new_key={:?}",
                 pref,
                 index,
                 new_key,
             );
-            let mut node = pref.node();
-            let old_key = node.update_key(index, new_key);
+            let node = pref.node()?;
+            let old_key = node.update_key(index, new_key.clone());
+            self.check_size(node);
             let mut pref = pref.parent_ref();
-            while pref.is_some() {
-                let rf = pref.unwrap();
+            while let Some(rf) = pref {
                 let ps = rf.get_step();
-                node = rf.node();
+                let node = rf.node()?;
                 if node.get_key(ps.node_idx()) == old_key {
-                    node.update_key(ps.node_idx(), new_key);
+                    node.update_key(ps.node_idx(), new_key.clone());
+                    self.check_size(node);
                 }
                 pref = rf.parent_ref();
             }
+            Ok(())
+        }
+
+        fn check_size(&mut self, node: Node<K, V>) {
+            // shortened separators may be replaced by longer keys,
+            // the node doesn't fit to the block then
+            if node.encoded_size() > node.bt().block_size() as u64 {
+                self.oversized.push(node);
+            }
+        }
+
+        fn split_oversized(&mut self) -> Result<Option<Task<K, V>>, BtreeError> {
+            // the last entry is inserted back with the split of the node,
+            // the path is found again, the tree could change since the update
+            while let Some(node) = self.oversized.pop() {
+                let bt = node.bt();
+                let node = bt.get_node(node.addr())?;
+                if node.is_leaf() || node.encoded_size() <= bt.block_size() as u64 {
+                    continue;
+                }
+                let (_, leaf_pref) = bt.find_leaf(&node.get_key(1))?;
+                let steps = leaf_pref.path.0.borrow().steps.clone();
+                let index = match steps
+                    .iter()
+                    .position(|step| step.node_addr() == node.addr())
+                {
+                    Some(index) => index,
+                    None => continue,
+                };
+                trace!("TaskManager:split_oversized: node={:?}", node);
+                let pref = PathRef::new(&leaf_pref.path, index);
+                let last = node.degree() as usize - 1;
+                let (key, item) = node.remove(last);
+                return Ok(Some(Task::Split {
+                    pref,
+                    index: last,
+                    key,
+                    item,
+                }));
+            }
+            Ok(None)
         }
     }
 
     #[derive(Debug)]
-    enum Task {
+    enum Task<K: Key, V: Val> {
         Insert {
-            target: InsertTarget,
+            target: InsertTarget<K, V>,
             index: usize,
-            key: Key,
-            val: Val,
+            key: K,
+            item: Item<V>,
         },
         Update {
-            pref: PathRef,
+            pref: PathRef<K, V>,
             index: usize,
-            new_key: Key,
+            new_key: K,
         },
         Remove {
-            pref: PathRef,
+            pref: PathRef<K, V>,
             index: usize,
         },
         Rebalance {
-            pref: PathRef,
+            pref: PathRef<K, V>,
         },
         Split {
-            pref: PathRef,
+            pref: PathRef<K, V>,
             index: usize,
-            key: Key,
-            val: Val,
+            key: K,
+            item: Item<V>,
         },
     }
 
@@ -1052,20 +2550,8 @@ pub mod btree {
                 node
             );
             PathStep {
-                left: match left {
-                    Some((idx, addr)) => Some(StepInfo {
-                        index: idx,
-                        addr: addr,
-                    }),
-                    None => None,
-                },
-                right: match right {
-                    Some((idx, addr)) => Some(StepInfo {
-                        index: idx,
-                        addr: addr,
-                    }),
-                    None => None,
-                },
+                left: left.map(|(index, addr)| StepInfo { index, addr }),
+                right: right.map(|(index, addr)| StepInfo { index, addr }),
                 node: StepInfo {
                     index: node.0,
                     addr: node.1,
@@ -1098,418 +2584,3525 @@ pub mod btree {
         }
     }
 
-    impl Btree {
-        pub fn new(
-            path: &FilePath,
-            block_size: Block,
-            alpha: u8,
-            max_file_size: Block,
-            cache_size: usize,
-        ) -> Self {
-            trace!(
-                "Btree:new: path={:?}, block_size={}, alpha={}, max_file_size={}, cache_size={}",
-                path,
-                block_size,
-                alpha,
-                max_file_size,
-                cache_size,
-            );
+    impl BtreeHeader {
+        fn encode(&self) -> Result<Vec<u8>, BtreeError> {
+            let mut buf = bincode::serialize(self)?;
+            let crc = crc32fast::hash(&buf);
+            buf.extend_from_slice(&crc.to_le_bytes());
+            Ok(buf)
+        }
+
+        fn check_format(buf: &[u8]) -> Result<(), BtreeError> {
+            // magic and version go first and never change
+            let not_index = || BtreeError::HeaderMismatch("not a btree index file".to_string());
+            let (magic, version): (u32, u32) =
+                bincode::deserialize(buf).map_err(|_| not_index())?;
+            if magic != HEADER_MAGIC {
+                return Err(not_index());
+            }
+            if version != FORMAT_VERSION {
+                return Err(BtreeError::HeaderMismatch(format!(
+                    "format version={}, supported={}",
+                    version, FORMAT_VERSION
+                )));
+            }
+            Ok(())
+        }
+
+        fn decode(buf: &[u8]) -> Result<Self, BtreeError> {
+            BtreeHeader::check_format(buf)?;
+            let header: BtreeHeader = bincode::deserialize(buf)?;
+            let len = bincode::serialized_size(&header)? as usize;
+            let crc = buf
+                .get(len..len + CHECKSUM_SIZE as usize)
+                .ok_or_else(|| BtreeError::Corrupt("header is truncated".to_string()))?;
+            if crc32fast::hash(&buf[..len]).to_le_bytes() != crc {
+                return Err(BtreeError::Corrupt("header checksum mismatch".to_string()));
+            }
+            Ok(header)
+        }
+    }
+
+    impl Journal {
+        fn path(path: &FilePath) -> PathBuf {
+            let mut path = path.as_os_str().to_owned();
+            path.push(".journal");
+            path.into()
+        }
+
+        fn open(path: &FilePath, truncate: bool) -> io::Result<Self> {
             let fd = OpenOptions::new()
                 .read(true)
                 .write(true)
                 .create(true)
-                .open(path)
-                .unwrap();
-            let max_degree = get_max_degree(block_size);
-            let min_degree = get_min_degree(max_degree, alpha);
-            let header = BtreeHeader {
-                root: block_size, // addr of the root node
-                block_size,
-                min_degree,
-                max_degree,
-            };
-
-            let mmap = unsafe {
-                MmapOptions::new()
-                    .len(block_size as usize * max_file_size as usize)
-                    .map_mut(&fd)
-                    .unwrap()
-            };
-            let bti = BtreeInner {
-                header,
-                cache: Rc::new(RefCell::new(LruCache::new(cache_size))),
+                .truncate(truncate)
+                .open(Journal::path(path))?;
+            let len = fd.metadata()?.len();
+            Ok(Journal {
                 fd,
-                mmap,
+                len,
+                synced: 0,
+                sync: true,
+            })
+        }
+
+        fn append(&mut self, record: &JournalRecord) -> Result<(), BtreeError> {
+            // magic, payload length, payload, crc of the payload
+            let payload = bincode::serialize(record)?;
+            let mut buf = Vec::with_capacity(payload.len() + 16);
+            buf.extend_from_slice(&JOURNAL_MAGIC.to_le_bytes());
+            buf.extend_from_slice(&(payload.len() as u64).to_le_bytes());
+            buf.extend_from_slice(&payload);
+            buf.extend_from_slice(&crc32fast::hash(&payload).to_le_bytes());
+            self.fd.seek(SeekFrom::Start(self.len))?;
+            self.fd.write_all(&buf)?;
+            self.len += buf.len() as u64;
+            if self.sync {
+                self.sync_data()?;
+            }
+            trace!(
+                "Journal:append: blocks={}, len={}",
+                record.blocks.len(),
+                self.len
+            );
+            Ok(())
+        }
+
+        fn read_records(path: &FilePath) -> io::Result<Vec<JournalRecord>> {
+            // journal of the read-only open is read without write permission
+            let mut fd = match File::open(Journal::path(path)) {
+                Ok(fd) => fd,
+                Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(Vec::new()),
+                Err(err) => return Err(err),
             };
+            let mut buf = Vec::new();
+            fd.read_to_end(&mut buf)?;
+            Ok(Journal::decode_all(&buf))
+        }
 
-            let bt = Btree(Rc::new(RefCell::new(bti)));
-            // Allocate first block for the btree struct (header)
-            bt.expand_file();
-            bt.flush();
-            // Allocate new space in file for the root node.
-            let _ = Node::new_leaf(&bt);
-            bt
+        fn records(&mut self) -> io::Result<Vec<JournalRecord>> {
+            let mut buf = Vec::new();
+            self.fd.seek(SeekFrom::Start(0))?;
+            self.fd.read_to_end(&mut buf)?;
+            Ok(Journal::decode_all(&buf))
         }
 
-        fn get_file_size(&self) -> u64 {
-            self.0.borrow().fd.metadata().unwrap().len()
+        fn decode_all(buf: &[u8]) -> Vec<JournalRecord> {
+            let mut records = Vec::new();
+            let mut pos = 0;
+            while let Some((record, len)) = Journal::decode(&buf[pos..]) {
+                records.push(record);
+                pos += len;
+            }
+            if pos != buf.len() {
+                info!(
+                    "Journal:records: torn tail dropped, len={}",
+                    buf.len() - pos
+                );
+            }
+            records
         }
 
-        fn cache_get(&self, addr: Addr) -> Option<Node> {
-            match self.0.borrow().cache.borrow_mut().get_mut(&addr) {
-                Some(node) => Some(node.clone()),
-                None => None,
+        fn decode(buf: &[u8]) -> Option<(JournalRecord, usize)> {
+            if buf.len() < 12 || buf[..4] != JOURNAL_MAGIC.to_le_bytes() {
+                return None;
+            }
+            let mut len = [0; 8];
+            len.copy_from_slice(&buf[4..12]);
+            let end = 12usize.checked_add(u64::from_le_bytes(len) as usize)?;
+            if buf.len() < end.checked_add(4)? {
+                return None;
+            }
+            let payload = &buf[12..end];
+            if crc32fast::hash(payload).to_le_bytes() != buf[end..end + 4] {
+                return None;
             }
+            let record = bincode::deserialize(payload).ok()?;
+            Some((record, end + 4))
         }
 
-        fn set_cache_cap(&self, new_cap: usize) -> usize {
-            let old_cap = self.0.borrow().cache.borrow().capacity();
-            self.0.borrow().cache.borrow_mut().set_capacity(new_cap);
-            old_cap
+        fn sync_data(&mut self) -> io::Result<()> {
+            self.fd.sync_data()?;
+            self.synced = self.len;
+            Ok(())
         }
 
-        fn cache_cap(&self) -> usize {
-            self.0.borrow().cache.borrow().capacity()
+        fn is_synced(&self) -> bool {
+            self.synced == self.len
         }
 
-        fn cache_is_full(&self) -> bool {
-            self.0.borrow().cache.borrow().len() == self.cache_cap()
+        fn truncate(&mut self) -> io::Result<()> {
+            self.fd.set_len(0)?;
+            self.fd.sync_data()?;
+            self.len = 0;
+            self.synced = 0;
+            Ok(())
         }
+    }
 
-        fn cache_put(&self, node: &Node) -> Option<Node> {
-            trace!("Btree:cache_put: node={:?}", node);
-            let result;
+    impl Mapping {
+        fn writable(mmap: MmapMut) -> Self {
+            Mapping::Write {
+                mmap,
+                held: HashMap::new(),
+            }
+        }
 
-            if self.cache_is_full() {
-                let (_, old_node) = self.0.borrow().cache.borrow_mut().remove_lru().unwrap();
-                result = Some(old_node);
-            } else {
-                result = None;
+        fn len(&self) -> usize {
+            match self {
+                Mapping::Write { mmap, .. } => mmap.len(),
+                Mapping::Read { mmap, .. } => mmap.len(),
+            }
+        }
+
+        fn read(&self, start: usize, end: usize) -> &[u8] {
+            match self {
+                Mapping::Write { mmap, held } => match held.get(&(start as Addr)) {
+                    Some(block) => &block[..(end - start).min(block.len())],
+                    None => &mmap[start..end],
+                },
+                Mapping::Read {
+                    mmap,
+                    file_len,
+                    journal,
+                } => match journal.get(&(start as Addr)) {
+                    Some(block) => &block[..(end - start).min(block.len())],
+                    None => {
+                        let file_len = *file_len as usize;
+                        &mmap[start.min(file_len)..end.min(file_len)]
+                    }
+                },
             }
-            self.0
-                .borrow_mut()
-                .cache
-                .borrow_mut()
-                .insert(node.addr(), node.clone());
-            result
         }
 
-        pub fn flush_cache(&self) {
-            let cache = Rc::clone(&self.0.borrow().cache);
-            for (_, node) in cache.borrow().iter() {
-                self.flush_node(node);
+        fn write(&mut self, start: usize, data: &[u8]) -> Result<(), BtreeError> {
+            match self {
+                Mapping::Write { mmap, held } => {
+                    // the newer image replaces the held one
+                    held.remove(&(start as Addr));
+                    mmap[start..start + data.len()].copy_from_slice(data);
+                    Ok(())
+                }
+                Mapping::Read { .. } => Err(BtreeError::ReadOnly),
             }
         }
 
-        fn flush_node(&self, node: &Node) {
-            trace!("Btree:flush_node: node={:?}", node);
-            let se = bincode::serialize(&node.0.borrow().st).unwrap();
-            let addr = node.addr() as usize;
-            let range = addr..(addr + se.len());
+        fn hold(&mut self, start: usize, data: &[u8]) -> Result<(), BtreeError> {
+            match self {
+                Mapping::Write { held, .. } => {
+                    held.insert(start as Addr, data.to_vec());
+                    Ok(())
+                }
+                Mapping::Read { .. } => Err(BtreeError::ReadOnly),
+            }
+        }
 
-            self.0.borrow_mut().mmap.as_mut()[range].copy_from_slice(&se);
-            self.0
-                .borrow_mut()
-                .mmap
-                .flush_range(addr, se.len())
-                .unwrap();
+        // writes the held blocks to the mapping, returns the written range
+        fn release(&mut self) -> Option<(usize, usize)> {
+            let (mmap, held) = match self {
+                Mapping::Write { mmap, held } => (mmap, held),
+                Mapping::Read { .. } => return None,
+            };
+            let mut range: Option<(usize, usize)> = None;
+            for (addr, block) in held.drain() {
+                let (start, end) = (addr as usize, addr as usize + block.len());
+                mmap[start..end].copy_from_slice(&block);
+                let range = range.get_or_insert((start, end));
+                *range = (range.0.min(start), range.1.max(end));
+            }
+            range
         }
 
-        fn flush(&self) {
-            trace!("Btree:flush: called");
-            let se = bincode::serialize(&self.0.borrow_mut().header).unwrap();
-            self.0.borrow_mut().mmap.as_mut()[..se.len()].copy_from_slice(&se);
-            self.0.borrow_mut().mmap.flush_range(0, se.len()).unwrap();
+        fn remap(&mut self, new: MmapMut) {
+            match self {
+                Mapping::Write { mmap, .. } => *mmap = new,
+                Mapping::Read { .. } => *self = Mapping::writable(new),
+            }
+        }
+
+        fn flush(&self) -> io::Result<()> {
+            match self {
+                Mapping::Write { mmap, .. } => mmap.flush(),
+                Mapping::Read { .. } => Ok(()),
+            }
+        }
+
+        fn flush_range(&self, start: usize, len: usize) -> io::Result<()> {
+            match self {
+                Mapping::Write { mmap, .. } => mmap.flush_range(start, len),
+                Mapping::Read { .. } => Ok(()),
+            }
         }
+    }
 
-        pub fn load(
+    impl<K: Key, V: Val> Btree<K, V> {
+        pub fn new(
             path: &FilePath,
             block_size: Block,
-            max_file_size: Block,
+            alpha: u8,
             cache_size: usize,
-        ) -> Self {
-            trace!(
-                "Btree:load: path={:?}, block_size={}, max_file_size={}",
-                path,
-                block_size,
-                max_file_size
-            );
-            let fd = std::fs::OpenOptions::new()
+        ) -> Result<Self, BtreeError> {
+            let options = BtreeOptions::new()
+                .block_size(block_size)
+                .alpha(alpha)
+                .cache_size(cache_size);
+            Self::create_with(path, &options)
+        }
+
+        /// Creates the tree, which keeps several values per key.
+        /// `insert` adds the entry after the equal keys instead of `DuplicateKey`,
+        /// `find`, `update` and `remove` act on the first value of the key.
+        pub fn new_multimap(
+            path: &FilePath,
+            block_size: Block,
+            alpha: u8,
+            cache_size: usize,
+        ) -> Result<Self, BtreeError> {
+            let options = BtreeOptions::new()
+                .block_size(block_size)
+                .alpha(alpha)
+                .cache_size(cache_size)
+                .multimap(true);
+            Self::create_with(path, &options)
+        }
+
+        /// Creates the tree with the options, they are stored in the header.
+        pub fn create_with(path: &FilePath, options: &BtreeOptions) -> Result<Self, BtreeError> {
+            trace!("Btree:create_with: path={:?}, options={:?}", path, options);
+            options.check::<K, V>()?;
+            // the file of a running reader or writer is not truncated
+            let lock = lock_file(path, true)?;
+            let fd = OpenOptions::new()
                 .read(true)
                 .write(true)
-                .create(false)
-                .open(path)
-                .unwrap();
-            let mmap = unsafe {
-                MmapOptions::new()
-                    .len(block_size as usize * max_file_size as usize)
-                    .map_mut(&fd)
-                    .unwrap()
+                .create(true)
+                .truncate(true)
+                .open(path)?;
+            let block_size = options.block_size.unwrap_or(DEFAULT_BLOCK_SIZE);
+            let stored = options.to_stored();
+            let max_degree = get_max_degree::<K, V>(block_size);
+            let min_degree = get_min_degree(max_degree, stored.alpha);
+            let header = BtreeHeader {
+                magic: HEADER_MAGIC,
+                version: FORMAT_VERSION,
+                root: block_size, // addr of the root node
+                block_size,
+                min_degree,
+                max_degree,
+                free: None,
+                multimap: options.multimap.unwrap_or(false),
+                options: stored,
             };
-            let header: BtreeHeader = bincode::deserialize(mmap.as_ref()).unwrap();
-            debug!("load: BtreeHeader loaded={:?}", &header);
-            debug_assert!(header.block_size == block_size);
+
+            let mmap = Mapping::writable(map_file(&fd, 0)?);
+            let cache = cache::NodeCache::new(stored.cache_size as usize, stored.cache_policy);
+            let mut journal = Journal::open(path, true)?;
+            journal.sync = stored.durability == Durability::Sync;
             let bti = BtreeInner {
-                cache: Rc::new(RefCell::new(LruCache::new(cache_size))),
                 header,
-                mmap,
+                options: stored,
+                cache: Rc::new(RefCell::new(cache)),
                 fd,
+                file_len: 0,
+                mmap,
+                journal: Some(journal),
+                _lock: lock,
+                pending: None,
+                error: None,
+                snapshots: Snapshots::default(),
+                counters: Counters::default(),
+                unsynced: Unsynced::new(),
             };
-            Btree(Rc::new(RefCell::new(bti)))
-        }
 
-        fn block_size(&self) -> Block {
-            self.0.borrow().header.block_size
+            let bt = Btree(Rc::new(RefCell::new(bti)));
+            // Allocate first block for the btree struct (header)
+            bt.expand_file()?;
+            bt.flush()?;
+            // Allocate new space in file for the root node.
+            bt.atomic(|| Node::new_leaf(&bt).map(|_| ()))?;
+            Ok(bt)
         }
 
-        fn expand_file(&self) -> Addr {
-            // expand file by 1 block and return address of new block
-            let block_size = self.block_size() as u64;
-            let fd = &mut self.0.borrow_mut().fd;
-            let addr = fd.metadata().unwrap().len();
-            trace!("Btree:expand_file: len before expand={}", addr);
-            fd.set_len(addr + block_size).unwrap();
-            addr as Addr
+        /// Builds the tree from pairs sorted by key, bottom-up in one pass.
+        /// Nodes are filled to `fill_factor` (0 < f <= 1) of the block and `max_degree`,
+        /// so there is room for inserts without a split.
+        pub fn bulk_load<I: IntoIterator<Item = (K, V)>>(
+            path: &FilePath,
+            pairs: I,
+            fill_factor: f64,
+        ) -> Result<Self, BtreeError> {
+            Self::bulk_load_with(path, &BtreeOptions::new(), pairs, fill_factor)
         }
 
-        fn get_node(&self, addr: Addr) -> Node {
-            debug_assert!(addr >= self.block_size() && addr % self.block_size() == 0);
-            trace!("Btree:get_node: addr={}", addr);
-
-            match self.cache_get(addr) {
-                Some(node) => {
-                    trace!("get_node: done from cache, loaded={:?}", node);
-                    node
+        /// Same as `bulk_load` with the options, they are stored in the header.
+        /// Equal keys are loaded in the given order into the multimap tree.
+        pub fn bulk_load_with<I: IntoIterator<Item = (K, V)>>(
+            path: &FilePath,
+            options: &BtreeOptions,
+            pairs: I,
+            fill_factor: f64,
+        ) -> Result<Self, BtreeError> {
+            trace!(
+                "Btree:bulk_load_with: path={:?}, options={:?}, fill_factor={}",
+                path,
+                options,
+                fill_factor,
+            );
+            // NaN fails both comparisons
+            if !(fill_factor > 0.0 && fill_factor <= 1.0) {
+                return Err(BtreeError::BadFillFactor);
+            }
+            options.check::<K, V>()?;
+            let block_size = options.block_size.unwrap_or(DEFAULT_BLOCK_SIZE);
+            let multimap = options.multimap.unwrap_or(false);
+            let stored = options.to_stored();
+            // kept until the tree is opened on the loaded file
+            let lock = lock_file(path, true)?;
+            let mut fd = OpenOptions::new()
+                .read(true)
+                .write(true)
+                .create(true)
+                .truncate(true)
+                .open(path)?;
+            // journal of the previous file must not be replayed
+            Journal::open(path, true)?;
+            let max_degree = get_max_degree::<K, V>(block_size);
+            let min_degree = get_min_degree(max_degree, stored.alpha);
+            let overhead = node_overhead::<K, V>();
+            let entry_limit = max_entry_size(block_size, overhead);
+            let mut loader = BulkLoader::<K, V> {
+                out: BufWriter::new(fd.try_clone()?),
+                pos: 0,
+                next_addr: block_size as u64, // header goes first
+                entry_limit,
+                block_size,
+                min_degree,
+                max_degree,
+                fill_degree: ((max_degree as f64 * fill_factor).ceil() as Degree)
+                    .clamp((min_degree + 1).min(max_degree), max_degree),
+                fill_size: (block_size as f64 * fill_factor) as u64,
+                _marker: std::marker::PhantomData,
+            };
+            // empty header until the tree is written, such file is not loaded
+            loader.write_block(0, Vec::new())?;
+
+            let mut last: Option<K> = None;
+            let leaves = pairs.into_iter().map(|(key, val)| {
+                if let Some(last) = &last {
+                    if key == *last && !multimap {
+                        return Err(BtreeError::DuplicateKey);
+                    } else if key < *last {
+                        return Err(BtreeError::Unsorted);
+                    }
                 }
-                None => {
-                    let bti = self.0.borrow();
-                    let block = &bti.mmap.as_ref()[(addr as usize)..];
-                    let st: NodeStored = bincode::deserialize(&block).unwrap();
-                    let node_inner = NodeInner {
-                        st,
-                        addr,
-                        bt: self.clone(),
-                    };
-                    trace!("get_node: done from storage, loaded={:?}", node_inner);
-                    Node(Rc::new(RefCell::new(node_inner)))
+                // large values are spilled, see Btree::check_entry_size
+                let entry_size = bincode::serialized_size(&key)?
+                    + Slot::<V>::overflow_size().max(size_of::<Addr>() as u64)
+                    + PAGE_SLOT_SIZE;
+                if entry_size > entry_limit {
+                    return Err(BtreeError::EntryTooLarge);
                 }
+                last = Some(key.clone());
+                Ok((key, Item::Val(Slot::Inline(val))))
+            });
+            let mut level = loader.level(true, leaves)?;
+            let mut depth = 1;
+            while level.len() > 1 {
+                let refs = level
+                    .into_iter()
+                    .map(|(key, addr)| Ok((key, Item::Ref(addr))));
+                level = loader.level(false, refs)?;
+                depth += 1;
             }
-        }
+            loader.out.flush()?;
+            drop(loader.out);
+            debug!(
+                "Btree:bulk_load: depth={}, blocks={}",
+                depth,
+                loader.next_addr / block_size as u64
+            );
 
-        fn root(&self) -> Addr {
-            self.0.borrow().header.root
+            let header = BtreeHeader {
+                magic: HEADER_MAGIC,
+                version: FORMAT_VERSION,
+                // empty root leaf has no key, it goes right after the header
+                root: level.first().map_or(block_size, |(_, addr)| *addr),
+                block_size,
+                min_degree,
+                max_degree,
+                free: None,
+                multimap,
+                options: stored,
+            };
+            // nodes are on disk before the header points to them
+            fd.sync_data()?;
+            fd.seek(SeekFrom::Start(0))?;
+            fd.write_all(&header.encode()?)?;
+            fd.sync_data()?;
+            drop(fd);
+            Btree::open_locked(path, &BtreeOptions::new(), lock)
         }
+    }
 
-        fn set_root(&self, addr: Addr) {
-            trace!("Btree:set_root: addr={}", addr);
-            self.0.borrow_mut().header.root = addr;
-            self.flush();
+    impl<K: Key, V: Val> Btree<K, V> {
+        fn get_file_size(&self) -> u64 {
+            let bti = self.0.borrow();
+            match &bti.pending {
+                Some(pending) => pending.file_len,
+                None => bti.file_len,
+            }
         }
 
-        fn find_leaf(&self, key: Key) -> (Node, PathRef) {
-            trace!("Btree:find_leaf: key={}", key);
+        fn set_file_size(&self, len: u64) -> Result<(), BtreeError> {
+            trace!("Btree:set_file_size: len={}", len);
+            // blocks are addressed by u32 offsets
+            if len > Addr::MAX as u64 + 1 {
+                return Err(BtreeError::FileFull);
+            }
+            if self.0.borrow().pending.is_none() && len < self.0.borrow().file_len {
+                // blocks cut off could be used by the tree the index file has,
+                // it's replaced by the journal records only after they are synced
+                self.sync_journal()?;
+            }
+            let mut bti = self.0.borrow_mut();
+            match &mut bti.pending {
+                Some(pending) => pending.file_len = len,
+                None => {
+                    bti.fd.set_len(len)?;
+                    bti.file_len = len;
+                    if len > bti.mmap.len() as u64 {
+                        let mmap = map_file(&bti.fd, len)?;
+                        bti.mmap.remap(mmap);
+                        debug!("Btree:set_file_size: remapped, len={}", bti.mmap.len());
+                    }
+                }
+            }
+            Ok(())
+        }
+
+        fn begin(&self) {
+            trace!("Btree:begin: called");
+            debug_assert!(self.0.borrow().pending.is_none());
+            let file_len = self.get_file_size();
+            let mut bti = self.0.borrow_mut();
+            bti.error = None;
+            bti.pending = Some(Pending {
+                dirty: BTreeMap::new(),
+                overflow: BTreeMap::new(),
+                retired: Vec::new(),
+                file_len,
+            });
+        }
+
+        fn commit(&self) -> Result<(), BtreeError> {
+            if let Some(err) = self.0.borrow_mut().error.take() {
+                return Err(err);
+            }
+            let released = self.copy_on_write()?;
+            let pending = self.0.borrow_mut().pending.take().unwrap();
+            // header goes with every record, it's small
+            let mut blocks = vec![(0, self.0.borrow().header.encode()?)];
+            // then the overflow blocks, they are not cached
+            blocks.extend(pending.overflow);
+            let overflow = blocks.len() - 1;
+            let mut nodes = Vec::new();
+            for (addr, node) in pending.dirty.into_iter() {
+                // skip stale entries of the moved nodes and the blocks kept for snapshots
+                if node.addr() == addr && !pending.retired.contains(&addr) {
+                    blocks.push((addr, node.0.borrow().st.encode()?));
+                    nodes.push(node);
+                }
+            }
+            for addr in pending.retired.iter() {
+                self.0.borrow().cache.borrow_mut().remove(*addr);
+            }
+            trace!("Btree:commit: blocks={}", blocks.len());
+            let record = JournalRecord {
+                file_len: pending.file_len,
+                blocks,
+            };
+            match &mut self.0.borrow_mut().journal {
+                Some(journal) => journal.append(&record)?,
+                None => return Err(BtreeError::ReadOnly),
+            }
+
+            // operation is committed, the rest is recovered from the journal on failure
+            self.apply(&record, &nodes, overflow).or_else(|err| {
+                error!("Btree:commit: apply failed, err={}", err);
+                self.abort()
+            })?;
+            {
+                let snapshots = &mut self.0.borrow_mut().snapshots;
+                let epoch = snapshots.epoch;
+                snapshots
+                    .retired
+                    .retain(|(_, addr)| !released.contains(addr));
+                let retired = pending.retired.iter().map(|addr| (epoch, *addr));
+                snapshots.retired.extend(retired);
+            }
+            self.count(|counters| &counters.commits);
+            self.0.borrow_mut().unsynced.ops += 1;
+            self.sync_if_due()?;
+            Ok(())
+        }
+
+        /// Syncs the committed operations once the batch of `Durability::GroupCommit`
+        /// is due: `ops` are committed or `interval_ms` passed since the last sync.
+        /// Commits check it themselves, but there is no background thread: the tree
+        /// idle after a burst stays unsynced until the caller drives this, e.g.
+        /// from its event loop or timer every `interval_ms`. Returns true when synced.
+        pub fn sync_if_due(&self) -> Result<bool, BtreeError> {
+            let due = {
+                let bti = self.0.borrow();
+                let unsynced = &bti.unsynced;
+                unsynced.ops > 0
+                    && match bti.options.durability {
+                        Durability::Sync => true,
+                        Durability::GroupCommit { ops, interval_ms } => {
+                            unsynced.ops >= ops
+                                || unsynced.since.elapsed()
+                                    >= Duration::from_millis(interval_ms as u64)
+                        }
+                        Durability::Async => false,
+                    }
+            };
+            if due {
+                self.sync()?;
+            }
+            Ok(due)
+        }
+
+        /// Syncs the journal and the blocks written since the last sync to the disk,
+        /// committed operations survive a power loss once it returns.
+        pub fn sync(&self) -> Result<(), BtreeError> {
+            trace!("Btree:sync: called");
+            debug_assert!(self.0.borrow().pending.is_none());
+            // journal goes first, it recovers the blocks torn by a power loss
+            self.sync_journal()?;
+            let bti = &mut *self.0.borrow_mut();
+            if let Some((start, end)) = bti.unsynced.range.take() {
+                // file could be cut off by compact since written
+                let end = end.min(bti.file_len as usize);
+                if start < end {
+                    bti.mmap.flush_range(start, end - start)?;
+                }
+            }
+            bti.unsynced = Unsynced::new();
+            let syncs = &bti.counters.syncs;
+            syncs.set(syncs.get() + 1);
+            Ok(())
+        }
+
+        fn sync_journal(&self) -> Result<(), BtreeError> {
+            // blocks held until the journal has their records go to the index file
+            let bti = &mut *self.0.borrow_mut();
+            match &mut bti.journal {
+                Some(journal) if !journal.is_synced() => journal.sync_data()?,
+                _ => {}
+            }
+            if let Some((start, end)) = bti.mmap.release() {
+                trace!("Btree:sync_journal: released start={}, end={}", start, end);
+                let range = bti.unsynced.range.get_or_insert((start, end));
+                *range = (range.0.min(start), range.1.max(end));
+            }
+            Ok(())
+        }
+
+        fn apply(
+            &self,
+            record: &JournalRecord,
+            nodes: &[Node<K, V>],
+            overflow: usize,
+        ) -> Result<(), BtreeError> {
+            // file size, header, overflow blocks and nodes not kept by the cache
+            if self.get_file_size() != record.file_len {
+                self.set_file_size(record.file_len)?;
+            }
+            self.flush()?;
+            for (addr, block) in record.blocks[1..=overflow].iter() {
+                self.write_block(*addr, block)?;
+            }
+            for node in nodes.iter() {
+                if !self.cache_contains(node.addr()) {
+                    self.flush_node(node)?;
+                    self.0.borrow().cache.borrow_mut().set_clean(node.addr());
+                }
+            }
+            let journal_len = self
+                .0
+                .borrow()
+                .journal
+                .as_ref()
+                .map_or(0, |journal| journal.len);
+            if journal_len > JOURNAL_CHECKPOINT_SIZE {
+                self.flush_cache()?;
+            }
+            Ok(())
+        }
+
+        /// Returns read-only view of the tree pinned to the current root.
+        /// Later changes of the tree are not seen through it: while any snapshot
+        /// is alive, the tree writes the changed nodes to the free blocks instead
+        /// of their own ones and publishes the new root. Blocks the snapshots read are
+        /// freed once the snapshots, which could reach them, are dropped.
+        /// The cache is flushed first, this syncs and truncates the journal.
+        pub fn snapshot(&self) -> Result<Snapshot<K, V>, BtreeError> {
+            debug!("Btree:snapshot: root={}", self.root());
+            // nodes kept by the cache go to the file, snapshot reads from there
+            self.flush_cache()?;
+            let bti = &mut *self.0.borrow_mut();
+            let snapshots = &mut bti.snapshots;
+            snapshots.oldest();
+            snapshots.epoch += 1;
+            // every block of the tree is shared with the snapshot now
+            snapshots.fresh.clear();
+            let pin = Rc::new(snapshots.epoch);
+            snapshots.live.push(Rc::downgrade(&pin));
+            Ok(Snapshot::new(self.clone(), bti.header.root, pin))
+        }
+
+        pub(super) fn release_retired(&self) -> Result<(), BtreeError> {
+            // blocks of the dropped snapshots are freed by an empty operation,
+            // unless an operation in progress does it on commit
+            let due = match self.0.try_borrow_mut() {
+                Ok(mut bti) => {
+                    bti.pending.is_none()
+                        && bti.journal.is_some()
+                        && !bti.snapshots.releasable().is_empty()
+                }
+                Err(_) => false,
+            };
+            if due {
+                self.atomic(|| Ok(()))?;
+            }
+            Ok(())
+        }
+
+        fn copy_on_write(&self) -> Result<Vec<Addr>, BtreeError> {
+            // Blocks no live snapshot reads go to the free list. Changed nodes,
+            // which a snapshot reads, are moved to free blocks with their parents
+            // up to the root, the old blocks are retired. Returns the freed blocks.
+            let released = self.0.borrow_mut().snapshots.releasable();
+            for addr in released.iter() {
+                trace!("Btree:copy_on_write: released addr={}", addr);
+                self.push_free(&Node(Rc::new(RefCell::new(NodeInner {
+                    st: NodeStored::new(true),
+                    addr: *addr,
+                    bt: Rc::downgrade(&self.0),
+                }))))?;
+            }
+            let changed: Vec<Node<K, V>> = {
+                let bti = &mut *self.0.borrow_mut();
+                let pending = bti.pending.as_ref().unwrap();
+                let snapshots = &mut bti.snapshots;
+                pending
+                    .dirty
+                    .iter()
+                    .filter(|(addr, node)| {
+                        node.addr() == **addr
+                            && !pending.retired.contains(addr)
+                            && snapshots.is_shared(**addr)
+                    })
+                    .map(|(_, node)| node.clone())
+                    .collect()
+            };
+            // links of the leaves are changed in place, snapshots don't follow them
+            let mut owners = BTreeMap::new();
+            for node in changed {
+                if !self.is_rewritten(&node)? {
+                    continue;
+                }
+                let addr = node.addr();
+                if addr == self.root() {
+                    owners.insert(addr, BlockOwner::Root);
+                    continue;
+                }
+                let chain = self.path_to(addr)?;
+                owners.insert(addr, BlockOwner::Child(chain[chain.len() - 1]));
+                for (depth, step) in chain.iter().enumerate() {
+                    if !self.0.borrow_mut().snapshots.is_shared(step.addr) {
+                        continue;
+                    }
+                    let owner = match depth {
+                        0 => BlockOwner::Root,
+                        _ => BlockOwner::Child(chain[depth - 1]),
+                    };
+                    owners.insert(step.addr, owner);
+                }
+            }
+            let mut moved = HashMap::new();
+            for (from, owner) in owners {
+                let to = self.alloc_block()?;
+                moved.insert(from, to);
+                self.move_block(from, to, owner, &moved)?;
+                self.0
+                    .borrow_mut()
+                    .pending
+                    .as_mut()
+                    .unwrap()
+                    .retired
+                    .push(from);
+            }
+            Ok(released)
+        }
+
+        fn is_rewritten(&self, node: &Node<K, V>) -> Result<bool, BtreeError> {
+            // node differs from its stored block by more than the leaf links
+            let mut stored = self.view(node.addr(), |view| view.to_stored())?;
+            let st = &node.0.borrow().st;
+            stored.next = st.next;
+            stored.prev = st.prev;
+            Ok(stored.encode()? != st.encode()?)
+        }
+
+        fn path_to(&self, addr: Addr) -> Result<Vec<StepInfo>, BtreeError> {
+            // internal nodes from the root down to the node with the index of the child
+            // taken, the node is found by its smallest key
+            let key = self.first_key(addr)?;
+            let mut chain = Vec::new();
+            if !self.find_path(self.root(), &key, addr, &mut chain)? {
+                return Err(BtreeError::Corrupt(format!(
+                    "node {} is not reachable from the root",
+                    addr
+                )));
+            }
+            Ok(chain)
+        }
+
+        fn first_key(&self, mut addr: Addr) -> Result<K, BtreeError> {
+            // internal nodes don't keep the smallest key of the subtree, it's in the leaf
+            loop {
+                let (key, child) = match self.peek_node(addr) {
+                    Some(node) if node.is_leaf() => {
+                        (node.0.borrow().st.keys.first().cloned(), None)
+                    }
+                    Some(node) => (None, Some(node.get_ref(0))),
+                    None => self.view(addr, |view| match (view.leaf, view.count) {
+                        (true, 0) => Ok((None, None)),
+                        (true, _) => Ok((Some(view.key(0)?), None)),
+                        (false, _) => Ok((None, Some(view.child(0)?))),
+                    })?,
+                };
+                match (key, child) {
+                    (Some(key), _) => return Ok(key),
+                    (None, Some(child)) => addr = child,
+                    (None, None) => {
+                        return Err(BtreeError::Corrupt(format!("leaf {} is empty", addr)))
+                    }
+                }
+            }
+        }
+
+        fn find_path(
+            &self,
+            addr: Addr,
+            key: &K,
+            target: Addr,
+            chain: &mut Vec<StepInfo>,
+        ) -> Result<bool, BtreeError> {
+            // children, where the key could be: equal keys of multimap span several
+            let children: Vec<(usize, Addr)> = match self.peek_node(addr) {
+                Some(node) if node.is_leaf() => return Ok(false),
+                Some(node) => (node.bound(key, false)..=node.bound(key, true))
+                    .map(|index| (index, node.get_ref(index)))
+                    .collect(),
+                None => self.view(addr, |view| {
+                    if view.leaf {
+                        return Ok(Vec::new());
+                    }
+                    (view.bound(key, false)?..=view.bound(key, true)?)
+                        .map(|index| Ok((index, view.child(index)?)))
+                        .collect()
+                })?,
+            };
+            for (index, child) in children {
+                chain.push(StepInfo { index, addr });
+                if child == target || self.find_path(child, key, target, chain)? {
+                    return Ok(true);
+                }
+                chain.pop();
+            }
+            Ok(false)
+        }
+
+        fn abort(&self) -> Result<(), BtreeError> {
+            // drop all changes of the operation. Committed state is
+            // the index file with the journal applied on top of it.
+            debug!("Btree:abort: called");
+            {
+                let bti = &mut *self.0.borrow_mut();
+                bti.pending = None;
+                bti.error = None;
+                bti.cache.borrow_mut().clear();
+            }
+            self.replay_journal()?;
+            let header = self.read_header()?;
+            self.0.borrow_mut().header = header;
+            // nodes are loaded again, the cursors must not keep the old ones
+            self.count(|counters| &counters.commits);
+            Ok(())
+        }
+
+        fn atomic<T>(&self, op: impl FnOnce() -> Result<T, BtreeError>) -> Result<T, BtreeError> {
+            if self.is_read_only() {
+                return Err(BtreeError::ReadOnly);
+            }
+            self.begin();
+            let result = op();
+            let result = match result {
+                Ok(result) => self.commit().map(|_| result),
+                Err(err) => Err(err),
+            };
+            if let Err(err) = &result {
+                debug!("Btree:atomic: operation failed, err={}", err);
+                if self.0.borrow().pending.is_some() {
+                    self.abort()?;
+                }
+            }
+            result
+        }
+
+        fn set_error(&self, err: BtreeError) {
+            let mut bti = self.0.borrow_mut();
+            if bti.error.is_none() {
+                bti.error = Some(err);
+            }
+        }
+
+        fn mark_dirty(&self, node: &Node<K, V>) {
+            if let Some(pending) = &mut self.0.borrow_mut().pending {
+                pending.dirty.insert(node.addr(), node.clone());
+            }
+        }
+
+        fn get_dirty(&self, addr: Addr) -> Option<Node<K, V>> {
+            let bti = self.0.borrow();
+            bti.pending.as_ref()?.dirty.get(&addr).cloned()
+        }
+
+        fn replay_journal(&self) -> Result<(), BtreeError> {
+            let records = match &mut self.0.borrow_mut().journal {
+                Some(journal) => journal.records()?,
+                None => return Ok(()),
+            };
+            debug!("Btree:replay_journal: records={}", records.len());
+            // records left by a crash could be not on the disk yet
+            self.sync_journal()?;
+            for record in records.iter() {
+                self.set_file_size(record.file_len)?;
+                for (addr, block) in record.blocks.iter() {
+                    self.write_block(*addr, block)?;
+                }
+            }
+            let mut bti = self.0.borrow_mut();
+            bti.mmap.flush()?;
+            if let Some(journal) = &mut bti.journal {
+                journal.truncate()?;
+            }
+            Ok(())
+        }
+
+        fn read_header(&self) -> Result<BtreeHeader, BtreeError> {
+            let bti = self.0.borrow();
+            if bti.file_len == 0 {
+                return Err(BtreeError::Corrupt("empty index file".to_string()));
+            }
+            let len = (bti.file_len as usize).min(bti.mmap.len());
+            BtreeHeader::decode(bti.mmap.read(0, len))
+        }
+
+        fn cache_get(&self, addr: Addr) -> Option<Node<K, V>> {
+            self.0.borrow().cache.borrow_mut().get(addr)
+        }
+
+        fn cache_contains(&self, addr: Addr) -> bool {
+            self.0.borrow().cache.borrow().contains(addr)
+        }
+
+        fn set_cache_cap(&self, new_cap: usize) -> Result<usize, BtreeError> {
+            let old_cap = self.cache_cap();
+            let evicted = self.0.borrow().cache.borrow_mut().set_capacity(new_cap);
+            self.write_back(&evicted)?;
+            Ok(old_cap)
+        }
+
+        fn cache_cap(&self) -> usize {
+            self.0.borrow().cache.borrow().capacity()
+        }
+
+        fn cache_put(&self, node: &Node<K, V>, dirty: bool) -> Result<(), BtreeError> {
+            trace!("Btree:cache_put: node={:?}, dirty={}", node, dirty);
+            let evicted = self
+                .0
+                .borrow()
+                .cache
+                .borrow_mut()
+                .put(node.addr(), node.clone(), dirty);
+            self.write_back(&evicted)
+        }
+
+        fn write_back(&self, nodes: &[Node<K, V>]) -> Result<(), BtreeError> {
+            // only the changed nodes are written, clean ones are in the file already
+            for node in nodes.iter() {
+                if self.get_dirty(node.addr()).is_some() {
+                    // changed by the operation in progress, written on commit
+                    continue;
+                }
+                self.flush_node(node)?;
+                self.0.borrow().cache.borrow_mut().record_write_back();
+            }
+            Ok(())
+        }
+
+        /// Replaces the replacement policy of the node cache, cached nodes are kept.
+        pub fn set_cache_policy<P: CachePolicy + 'static>(&self, policy: P) {
+            debug!("Btree:set_cache_policy: called");
+            self.0
+                .borrow()
+                .cache
+                .borrow_mut()
+                .set_policy(Box::new(policy));
+        }
+
+        /// Returns the counters of the node cache.
+        pub fn cache_stats(&self) -> CacheStats {
+            self.0.borrow().cache.borrow().stats()
+        }
+
+        pub fn flush_cache(&self) -> Result<(), BtreeError> {
+            debug_assert!(self.0.borrow().pending.is_none());
+            // journal is truncated below, its records must be on the disk first
+            self.sync_journal()?;
+            let dirty = self.0.borrow().cache.borrow().dirty();
+            for (addr, node) in dirty.iter() {
+                self.write_back(std::slice::from_ref(node))?;
+                self.0.borrow().cache.borrow_mut().set_clean(*addr);
+            }
+            // everything is in the index file now, journal is not needed anymore
+            let mut bti = self.0.borrow_mut();
+            bti.mmap.flush()?;
+            if let Some(journal) = &mut bti.journal {
+                journal.truncate()?;
+            }
+            bti.unsynced = Unsynced::new();
+            Ok(())
+        }
+
+        fn flush_node(&self, node: &Node<K, V>) -> Result<(), BtreeError> {
+            trace!("Btree:flush_node: node={:?}", node);
+            if self.get_dirty(node.addr()).is_some() {
+                // changed by the operation in progress, written on commit
+                return Ok(());
+            }
+            let se = node.0.borrow().st.encode()?;
+            debug_assert!(se.len() <= self.block_size() as usize);
+            self.count(|counters| &counters.node_writes);
+            self.write_block(node.addr(), &se)
+        }
+
+        fn flush(&self) -> Result<(), BtreeError> {
+            trace!("Btree:flush: called");
+            if self.0.borrow().pending.is_some() {
+                // header is written on commit
+                return Ok(());
+            }
+            let se = self.0.borrow().header.encode()?;
+            self.write_block(0, &se)
+        }
+
+        fn write_block(&self, addr: Addr, data: &[u8]) -> Result<(), BtreeError> {
+            let addr = addr as usize;
+            let mut bti = self.0.borrow_mut();
+            if (addr + data.len()) as u64 > bti.file_len {
+                return Err(BtreeError::Corrupt(format!(
+                    "write beyond the file, addr={}",
+                    addr
+                )));
+            }
+            let held = bti
+                .journal
+                .as_ref()
+                .is_some_and(|journal| !journal.is_synced());
+            if held {
+                // index file gets it after the journal sync, see sync_journal
+                return bti.mmap.hold(addr, data);
+            }
+            bti.mmap.write(addr, data)?;
+            // synced once the operation is committed, see Durability
+            let end = addr + data.len();
+            let range = bti.unsynced.range.get_or_insert((addr, end));
+            *range = (range.0.min(addr), range.1.max(end));
+            Ok(())
+        }
+
+        /// Opens the tree with the cache size given, the other settings are stored.
+        pub fn load(path: &FilePath, cache_size: usize) -> Result<Self, BtreeError> {
+            Btree::open(path, &BtreeOptions::new().cache_size(cache_size))
+        }
+
+        /// Opens the tree with the settings it's created with and the overrides
+        /// of `options`, see `BtreeOptions`. The index is locked for this writer,
+        /// other writers and readers get `Locked` until the tree is dropped.
+        pub fn open(path: &FilePath, options: &BtreeOptions) -> Result<Self, BtreeError> {
+            trace!("Btree:open: path={:?}, options={:?}", path, options);
+            let lock = lock_file(path, true)?;
+            Btree::open_locked(path, options, lock)
+        }
+
+        fn open_locked(
+            path: &FilePath,
+            options: &BtreeOptions,
+            lock: Option<File>,
+        ) -> Result<Self, BtreeError> {
+            let fd = std::fs::OpenOptions::new()
+                .read(true)
+                .write(true)
+                .create(false)
+                .open(path)?;
+            let file_len = fd.metadata()?.len();
+            let mmap = map_file(&fd, file_len)?;
+            // don't touch the journal of a foreign file
+            BtreeHeader::check_format(&mmap.as_ref()[..(file_len as usize).min(mmap.len())])?;
+            let journal = Journal::open(path, false)?;
+            let bt = Btree::build(Mapping::writable(mmap), fd, file_len, Some(journal), lock);
+            // finish operations interrupted by a crash, header could be changed
+            bt.replay_journal()?;
+            bt.init(options)?;
+            Ok(bt)
+        }
+
+        /// Opens the tree for reading, see `open` for the `options`. The file is mapped
+        /// without write permission, changes fail with `ReadOnly`. Readers share
+        /// the lock, a writer gets `Locked` while any of them is open. Operations
+        /// the last writer left in the journal are read from the journal.
+        pub fn open_read_only(path: &FilePath, options: &BtreeOptions) -> Result<Self, BtreeError> {
+            trace!(
+                "Btree:open_read_only: path={:?}, options={:?}",
+                path,
+                options
+            );
+            let lock = lock_file(path, false)?;
+            let fd = std::fs::OpenOptions::new().read(true).open(path)?;
+            let file_len = fd.metadata()?.len();
+            let mut map_len = MIN_MAP_SIZE;
+            while map_len < file_len {
+                map_len *= 2;
+            }
+            let mmap = unsafe { MmapOptions::new().len(map_len as usize).map(&fd)? };
+            BtreeHeader::check_format(&mmap.as_ref()[..file_len as usize])?;
+            // journal is replayed in memory, the last record has the file size
+            let mut journal = HashMap::new();
+            let mut len = file_len;
+            for record in Journal::read_records(path)? {
+                len = record.file_len;
+                journal.extend(record.blocks);
+            }
+            debug!(
+                "Btree:open_read_only: file_len={}, len={}, journal blocks={}",
+                file_len,
+                len,
+                journal.len()
+            );
+            let mmap = Mapping::Read {
+                mmap,
+                file_len,
+                journal,
+            };
+            let bt = Btree::build(mmap, fd, len, None, lock);
+            bt.init(options)?;
+            Ok(bt)
+        }
+
+        fn build(
+            mmap: Mapping,
+            fd: File,
+            file_len: u64,
+            journal: Option<Journal>,
+            lock: Option<File>,
+        ) -> Self {
+            // real header is read by init
+            let stored = BtreeOptions::new().cache_size(0).to_stored();
+            let header = BtreeHeader {
+                magic: HEADER_MAGIC,
+                version: FORMAT_VERSION,
+                root: 0,
+                min_degree: 0,
+                max_degree: 0,
+                block_size: 0,
+                free: None,
+                multimap: false,
+                options: stored,
+            };
+            let bti = BtreeInner {
+                cache: Rc::new(RefCell::new(cache::NodeCache::new(0, stored.cache_policy))),
+                header,
+                options: stored,
+                mmap,
+                fd,
+                file_len,
+                journal,
+                _lock: lock,
+                pending: None,
+                error: None,
+                snapshots: Snapshots::default(),
+                counters: Counters::default(),
+                unsynced: Unsynced::new(),
+            };
+            Btree(Rc::new(RefCell::new(bti)))
+        }
+
+        fn init(&self, options: &BtreeOptions) -> Result<(), BtreeError> {
+            let header = self.read_header()?;
+            debug!("init: BtreeHeader loaded={:?}", &header);
+            let resolved = options.resolve(&header)?;
+            {
+                let mut bti = self.0.borrow_mut();
+                bti.header = header;
+                bti.options = resolved;
+                if let Some(journal) = &mut bti.journal {
+                    journal.sync = resolved.durability == Durability::Sync;
+                }
+                bti.unsynced = Unsynced::new();
+                bti.cache
+                    .borrow_mut()
+                    .set_policy(resolved.cache_policy.policy());
+            }
+            // cache is empty, nothing is evicted
+            self.set_cache_cap(resolved.cache_size as usize)?;
+            Ok(())
+        }
+
+        /// Index is opened by `open_read_only`.
+        pub fn is_read_only(&self) -> bool {
+            self.0.borrow().journal.is_none()
+        }
+
+        /// Returns the settings of this open: the stored ones with the overrides.
+        pub fn options(&self) -> BtreeOptions {
+            let bti = self.0.borrow();
+            let options = &bti.options;
+            BtreeOptions {
+                block_size: Some(bti.header.block_size),
+                alpha: Some(options.alpha),
+                multimap: Some(bti.header.multimap),
+                cache_size: Some(options.cache_size as usize),
+                cache_policy: Some(options.cache_policy),
+                durability: Some(options.durability),
+                growth: Some(options.growth),
+            }
+        }
+
+        fn block_size(&self) -> Block {
+            self.0.borrow().header.block_size
+        }
+
+        fn node_overhead(&self) -> u64 {
+            node_overhead::<K, V>()
+        }
+
+        fn expand_file(&self) -> Result<Addr, BtreeError> {
+            // expand file by 1 block and return address of new block
+            let block_size = self.block_size() as u64;
+            let addr = self.get_file_size();
+            trace!("Btree:expand_file: len before expand={}", addr);
+            self.set_file_size(addr + block_size)?;
+            Ok(addr as Addr)
+        }
+
+        fn grow_file(&self) -> Result<Addr, BtreeError> {
+            // grow file by the growth setting, first new block is returned
+            // and the rest goes to the free list
+            let block_size = self.block_size() as u64;
+            let len = self.get_file_size();
+            let count = match self.0.borrow().options.growth {
+                Growth::Blocks(count) => count as u64,
+                Growth::Percent(percent) => len / block_size * percent as u64 / 100,
+            };
+            // the last block of the address space is the limit
+            let count = count
+                .min((Addr::MAX as u64 + 1).saturating_sub(len) / block_size)
+                .max(1);
+            trace!("Btree:grow_file: len={}, blocks={}", len, count);
+            let addr = self.expand_file()?;
+            if count == 1 {
+                return Ok(addr);
+            }
+            self.set_file_size(len + count * block_size)?;
+            for i in (1..count).rev() {
+                let node = Node(Rc::new(RefCell::new(NodeInner {
+                    st: NodeStored::new(true),
+                    addr: (len + i * block_size) as Addr,
+                    bt: Rc::downgrade(&self.0),
+                })));
+                let next = self.0.borrow_mut().header.free.replace(node.addr());
+                node.0.borrow_mut().st.next = next;
+                self.0.borrow_mut().snapshots.mark_fresh(node.addr());
+                self.mark_dirty(&node);
+                self.flush_node(&node)?;
+            }
+            self.flush()?;
+            Ok(addr)
+        }
+
+        fn alloc_block(&self) -> Result<Addr, BtreeError> {
+            // reuse freed block if any, otherwise grow the file.
+            // No snapshot reads the block, it's changed in place
+            let free = self.0.borrow().header.free;
+            let addr = match free {
+                Some(addr) => {
+                    let next = self.get_node(addr)?.next();
+                    trace!(
+                        "Btree:alloc_block: reused addr={}, next free={:?}",
+                        addr,
+                        next
+                    );
+                    self.0.borrow_mut().header.free = next;
+                    self.flush()?;
+                    addr
+                }
+                None => self.grow_file()?,
+            };
+            self.0.borrow_mut().snapshots.mark_fresh(addr);
+            Ok(addr)
+        }
+
+        fn free_node(&self, node: &Node<K, V>) -> Result<(), BtreeError> {
+            // node is not reachable anymore, push its block to the free list.
+            // Block a live snapshot reads is kept as it is, see copy_on_write
+            let addr = node.addr();
+            {
+                let bti = &mut *self.0.borrow_mut();
+                if bti.snapshots.is_shared(addr) {
+                    trace!("Btree:free_node: retired addr={}", addr);
+                    bti.cache.borrow_mut().remove(addr);
+                    if let Some(pending) = bti.pending.as_mut() {
+                        pending.dirty.remove(&addr);
+                        pending.retired.push(addr);
+                    }
+                    return Ok(());
+                }
+            }
+            self.push_free(node)
+        }
+
+        fn push_free(&self, node: &Node<K, V>) -> Result<(), BtreeError> {
+            trace!("Btree:push_free: node={:?}", node);
+            let addr = node.addr();
+            self.0.borrow_mut().snapshots.mark_fresh(addr);
+            self.0.borrow().cache.borrow_mut().remove(addr);
+            let next = self.0.borrow_mut().header.free.replace(addr);
+            {
+                let st = &mut node.0.borrow_mut().st;
+                *st = NodeStored::new(true);
+                st.next = next;
+            }
+            self.mark_dirty(node);
+            self.flush_node(node)?;
+            self.flush()
+        }
+
+        fn peek_node(&self, addr: Addr) -> Option<Node<K, V>> {
+            // node changed since written, the cache policy and counters are not touched
+            let cached = self.0.borrow().cache.borrow().peek(addr);
+            cached.or_else(|| self.get_dirty(addr))
+        }
+
+        fn get_node(&self, addr: Addr) -> Result<Node<K, V>, BtreeError> {
+            trace!("Btree:get_node: addr={}", addr);
+
+            if let Some(node) = self.cache_get(addr) {
+                trace!("get_node: done from cache, loaded={:?}", node);
+                return Ok(node);
+            }
+            if let Some(node) = self.get_dirty(addr) {
+                trace!("get_node: done from operation, loaded={:?}", node);
+                return Ok(node);
+            }
+            let st = self.view(addr, |view| view.to_stored())?;
+            self.count(|counters| &counters.node_decodes);
+            let node = Node(Rc::new(RefCell::new(NodeInner {
+                st,
+                addr,
+                bt: Rc::downgrade(&self.0),
+            })));
+            trace!("get_node: done from storage, loaded={:?}", node);
+            if self.cache_cap() != 0 {
+                // clean node, it's not written back when evicted
+                self.cache_put(&node, false)?;
+            }
+            Ok(node)
+        }
+
+        fn view<T>(
+            &self,
+            addr: Addr,
+            read: impl FnOnce(NodeView<K, Slot<V>>) -> Result<T, BtreeError>,
+        ) -> Result<T, BtreeError> {
+            // reads the stored node in place, the node must not be changed since the commit
+            let block_size = self.block_size();
+            let bti = self.0.borrow();
+            let end = addr as u64 + block_size as u64;
+            if addr < block_size || !addr.is_multiple_of(block_size) || end > bti.file_len {
+                return Err(BtreeError::Corrupt(format!("bad node address {}", addr)));
+            }
+            bti.counters
+                .node_reads
+                .set(bti.counters.node_reads.get() + 1);
+            read(NodeView::new(
+                bti.mmap.read(addr as usize, end as usize),
+                addr,
+            )?)
+        }
+
+        fn count(&self, counter: impl Fn(&Counters) -> &Cell<u64>) {
+            let bti = self.0.borrow();
+            let counter = counter(&bti.counters);
+            counter.set(counter.get() + 1);
+        }
+
+        fn descend(
+            &self,
+            addr: Addr,
+            before: &dyn Fn(&K) -> bool,
+        ) -> Result<Option<(Addr, PathStep)>, BtreeError> {
+            // child of the internal node, where the bound could be, None for a leaf.
+            // Nodes out of the cache are searched in place, without decoding.
+            if let Some(node) = self.cache_get(addr).or_else(|| self.get_dirty(addr)) {
+                if node.is_leaf() {
+                    return Ok(None);
+                }
+                return Ok(Some(node.find_next_node(before)));
+            }
+            self.view(addr, |view| {
+                if view.leaf {
+                    return Ok(None);
+                }
+                let index = view.bound_by(before)?;
+                Ok(Some((view.child(index)?, view.path_step(index)?)))
+            })
+        }
+
+        fn root(&self) -> Addr {
+            self.0.borrow().header.root
+        }
+
+        fn set_root(&self, addr: Addr) -> Result<(), BtreeError> {
+            trace!("Btree:set_root: addr={}", addr);
+            self.0.borrow_mut().header.root = addr;
+            self.flush()
+        }
+
+        fn find_leaf(&self, key: &K) -> Result<LeafRef<K, V>, BtreeError> {
+            self.find_leaf_bound(key, true)
+        }
+
+        fn find_leaf_bound(&self, key: &K, upper: bool) -> Result<LeafRef<K, V>, BtreeError> {
+            // leaf with the upper bound of the key or the lower one,
+            // the last could be at the end of the leaf before the key
+            trace!("Btree:find_leaf_bound: key={:?}, upper={}", key, upper);
+            if upper {
+                self.find_leaf_by(&|k| k <= key)
+            } else {
+                self.find_leaf_by(&|k| k < key)
+            }
+        }
+
+        fn find_leaf_by(&self, before: &dyn Fn(&K) -> bool) -> Result<LeafRef<K, V>, BtreeError> {
+            // leaf with the first key, which is not `before` the bound, see Node::bound_by
             let mut steps = Vec::new();
-            let mut node = self.get_node(self.root());
+            let mut addr = self.root();
+            while let Some((next_node_addr, step)) = self.descend(addr, before)? {
+                steps.push(step);
+                addr = next_node_addr;
+            }
+            let node = self.get_node(addr)?;
+            steps.push(PathStep::new(None, None, (0, addr)));
+            let path = Path::new(steps, self);
+            Ok((node, PathRef::tail(&path)))
+        }
+
+        fn next_leaf(&self, pref: &PathRef<K, V>) -> Result<Option<LeafRef<K, V>>, BtreeError> {
+            // path to the next leaf goes through the lowest node with the right sibling
+            let mut steps = pref.path.0.borrow().steps.clone();
+            steps.pop();
+            while let Some(step) = steps.pop() {
+                if step.right.is_none() {
+                    continue;
+                }
+                let mut node = self.get_node(step.node_addr())?;
+                let mut index = step.right_idx();
+                while !node.is_leaf() {
+                    steps.push(node.path_step(index));
+                    node = self.get_node(node.get_ref(index))?;
+                    index = 0;
+                }
+                steps.push(PathStep::new(None, None, (0, node.addr())));
+                let path = Path::new(steps, self);
+                return Ok(Some((node, PathRef::tail(&path))));
+            }
+            Ok(None)
+        }
+
+        fn prev_leaf(&self, pref: &PathRef<K, V>) -> Result<Option<LeafRef<K, V>>, BtreeError> {
+            // same as next_leaf through the lowest node with the left sibling
+            let mut steps = pref.path.0.borrow().steps.clone();
+            steps.pop();
+            while let Some(step) = steps.pop() {
+                if step.left.is_none() {
+                    continue;
+                }
+                let mut node = self.get_node(step.node_addr())?;
+                let mut index = step.left_idx();
+                while !node.is_leaf() {
+                    steps.push(node.path_step(index));
+                    node = self.get_node(node.get_ref(index))?;
+                    index = node.degree() as usize - 1;
+                }
+                steps.push(PathStep::new(None, None, (0, node.addr())));
+                let path = Path::new(steps, self);
+                return Ok(Some((node, PathRef::tail(&path))));
+            }
+            Ok(None)
+        }
+
+        fn find_first(&self, key: &K) -> Result<Option<LeafEntry<K, V>>, BtreeError> {
+            // the first entry of the key, equal keys could span several leaves
+            let (mut leaf, mut pref) = self.find_leaf_bound(key, false)?;
+            let mut idx = leaf.bound(key, false);
+            while idx == leaf.degree() as usize {
+                match self.next_leaf(&pref)? {
+                    Some((next, next_pref)) => {
+                        leaf = next;
+                        pref = next_pref;
+                        idx = 0;
+                    }
+                    None => return Ok(None),
+                }
+            }
+            if leaf.get_key(idx) != *key {
+                return Ok(None);
+            }
+            Ok(Some(((leaf, pref), idx)))
+        }
+
+        pub fn is_multimap(&self) -> bool {
+            self.0.borrow().header.multimap
+        }
+
+        fn min_degree(&self) -> Degree {
+            self.0.borrow().header.min_degree
+        }
+
+        fn set_min_degree(&self, degree: Degree) {
+            trace!("Btree:set_min_degree: degree={}", degree);
+            self.0.borrow_mut().header.min_degree = degree;
+        }
+
+        fn max_degree(&self) -> Degree {
+            self.0.borrow().header.max_degree
+        }
+
+        fn set_max_degree(&self, degree: Degree) {
+            trace!("Btree:set_max_degree: degree={}", degree);
+            self.0.borrow_mut().header.max_degree = degree;
+        }
+
+        pub fn set_degree(&self, min_degree: Degree, max_degree: Degree) -> Result<(), BtreeError> {
+            trace!(
+                "Btree:set_degree: min_degree={}, max_degree={}",
+                min_degree,
+                max_degree
+            );
+            self.atomic(|| {
+                self.set_min_degree(min_degree);
+                self.set_max_degree(max_degree);
+                Ok(())
+            })
+        }
+
+        pub fn find(&self, key: &K) -> Result<V, BtreeError> {
+            trace!("Btree:find: key={:?}", key);
+            // the first of the equal keys of multimap is found by the lower bound
+            let multimap = self.is_multimap();
+            let mut addr = self.root();
+            while let Some((next_node_addr, _)) =
+                self.descend(addr, &|k| if multimap { k < key } else { k <= key })?
+            {
+                addr = next_node_addr;
+            }
+            loop {
+                // leaf out of the cache is searched in place, only the found value is decoded
+                let found = match self.cache_get(addr).or_else(|| self.get_dirty(addr)) {
+                    Some(leaf) => leaf.search(key),
+                    None => self.view(addr, |view| view.search(key))?,
+                };
+                match found {
+                    LeafSearch::Found(slot) => return self.read_val(slot),
+                    LeafSearch::Next(next) if multimap => addr = next,
+                    _ => return Err(BtreeError::KeyNotFound),
+                }
+            }
+        }
+
+        pub fn insert(&self, key: K, val: V) -> Result<(), BtreeError> {
+            debug!("Btree:insert: key={:?}, val={:?}", key, val);
+            let mut mgr = self.insert_tasks(key, Slot::Inline(val))?;
+            self.atomic(|| mgr.run())
+        }
+
+        fn check_entry_size(&self, key: &K) -> Result<(), BtreeError> {
+            // every node must be able to hold a few entries,
+            // otherwise split would not free enough space.
+            // Large values are spilled, the key with the reference to them must fit.
+            let entry_size = bincode::serialized_size(key)?
+                + Slot::<V>::overflow_size().max(size_of::<Addr>() as u64)
+                + PAGE_SLOT_SIZE;
+            if entry_size > max_entry_size(self.block_size(), self.node_overhead()) {
+                return Err(BtreeError::EntryTooLarge);
+            }
+            Ok(())
+        }
+
+        fn spill(&self, key: &K, slot: Slot<V>) -> Result<Slot<V>, BtreeError> {
+            // writes the value to the overflow blocks, if the entry doesn't fit to the leaf
+            let limit = max_entry_size(self.block_size(), self.node_overhead());
+            if bincode::serialized_size(key)? + bincode::serialized_size(&slot)? + PAGE_SLOT_SIZE
+                <= limit
+            {
+                return Ok(slot);
+            }
+            let data = match &slot {
+                Slot::Inline(val) => bincode::serialize(val)?,
+                Slot::Overflow { .. } => return Ok(slot),
+            };
+            let block_size = self.block_size();
+            let addrs = (0..OverflowStored::blocks(data.len(), block_size))
+                .map(|_| self.alloc_overflow())
+                .collect::<Result<Vec<_>, _>>()?;
+            let blocks = OverflowStored::chain(&data, &addrs, block_size)?;
+            trace!("Btree:spill: key={:?}, blocks={:?}", key, addrs);
+            let mut bti = self.0.borrow_mut();
+            let pending = bti.pending.as_mut().unwrap();
+            pending.overflow.extend(addrs.iter().copied().zip(blocks));
+            Ok(Slot::Overflow {
+                head: addrs[0],
+                len: data.len() as u64,
+            })
+        }
+
+        fn alloc_overflow(&self) -> Result<Addr, BtreeError> {
+            // reused block may still be a free node in the cache or the operation
+            let addr = self.alloc_block()?;
+            let mut bti = self.0.borrow_mut();
+            bti.cache.borrow_mut().remove(addr);
+            bti.pending.as_mut().unwrap().dirty.remove(&addr);
+            Ok(addr)
+        }
+
+        fn get_overflow(&self, addr: Addr) -> Result<OverflowStored, BtreeError> {
+            let bti = self.0.borrow();
+            if let Some(block) = bti.pending.as_ref().and_then(|p| p.overflow.get(&addr)) {
+                return OverflowStored::decode(block, addr);
+            }
+            let end = addr as u64 + bti.header.block_size as u64;
+            if addr < bti.header.block_size
+                || !addr.is_multiple_of(bti.header.block_size)
+                || end > bti.file_len
+            {
+                return Err(BtreeError::Corrupt(format!(
+                    "bad overflow block address {}",
+                    addr
+                )));
+            }
+            OverflowStored::decode(bti.mmap.read(addr as usize, end as usize), addr)
+        }
+
+        fn put_overflow(&self, addr: Addr, st: &OverflowStored) -> Result<(), BtreeError> {
+            let block = st.encode()?;
+            let mut bti = self.0.borrow_mut();
+            bti.pending.as_mut().unwrap().overflow.insert(addr, block);
+            Ok(())
+        }
+
+        fn read_val(&self, slot: Slot<V>) -> Result<V, BtreeError> {
+            match slot {
+                Slot::Inline(val) => Ok(val),
+                Slot::Overflow { head, len } => {
+                    OverflowStored::read_value(head, len, |addr| self.get_overflow(addr))
+                }
+            }
+        }
+
+        fn free_overflow(&self, slot: &Slot<V>) -> Result<(), BtreeError> {
+            // blocks of the removed or replaced value go to the free list
+            let mut next = match slot {
+                Slot::Inline(_) => return Ok(()),
+                Slot::Overflow { head, .. } => Some(*head),
+            };
+            while let Some(addr) = next {
+                next = self.get_overflow(addr)?.next;
+                trace!("Btree:free_overflow: addr={}", addr);
+                if let Some(pending) = self.0.borrow_mut().pending.as_mut() {
+                    pending.overflow.remove(&addr);
+                }
+                let node = Node(Rc::new(RefCell::new(NodeInner {
+                    st: NodeStored::new(true),
+                    addr,
+                    bt: Rc::downgrade(&self.0),
+                })));
+                self.free_node(&node)?;
+            }
+            Ok(())
+        }
+
+        fn insert_tasks(&self, key: K, val: Slot<V>) -> Result<TaskManager<K, V>, BtreeError> {
+            self.check_entry_size(&key)?;
+            let (leaf, last_ref) = self.find_leaf(&key)?;
+            let index = match leaf.find(&key) {
+                // the new entry goes after the equal keys
+                Ok(_) if self.is_multimap() => leaf.bound(&key, true),
+                Ok(_) => return Err(BtreeError::DuplicateKey),
+                Err(idx) => idx,
+            };
+
+            let mut mgr = TaskManager::new();
+            mgr.add_insert(
+                InsertTarget::RefNodeAddr((last_ref, leaf.addr())),
+                index,
+                key,
+                Item::Val(val),
+            );
+            Ok(mgr)
+        }
+
+        pub fn remove(&self, key: &K) -> Result<V, BtreeError> {
+            debug!("Btree:remove: key={:?}", key);
+            let (mut mgr, result) = self.remove_tasks(key)?;
+            self.atomic(|| mgr.run())?;
+            Ok(result)
+        }
+
+        fn remove_tasks(&self, key: &K) -> Result<(TaskManager<K, V>, V), BtreeError> {
+            let ((leaf, last_ref), index) = self.find_entry(key)?;
+            let mut mgr = TaskManager::new();
+            let result = leaf.get_val(index)?;
+            mgr.add_remove(last_ref, index);
+            Ok((mgr, result))
+        }
+
+        fn find_entry(&self, key: &K) -> Result<LeafEntry<K, V>, BtreeError> {
+            // entry changed by the single key operations, the first one in multimap
+            if self.is_multimap() {
+                return self.find_first(key)?.ok_or(BtreeError::KeyNotFound);
+            }
+            let (leaf, last_ref) = self.find_leaf(key)?;
+            match leaf.find(key) {
+                Ok(idx) => Ok(((leaf, last_ref), idx)),
+                Err(_) => Err(BtreeError::KeyNotFound),
+            }
+        }
+
+        /// Values of all entries with the key, in insertion order for multimap.
+        pub fn find_all(&self, key: &K) -> Result<Vec<V>, BtreeError> {
+            trace!("Btree:find_all: key={:?}", key);
+            let mut result = Vec::new();
+            let ((mut leaf, _), mut idx) = match self.find_first(key)? {
+                Some(first) => first,
+                None => return Ok(result),
+            };
+            loop {
+                while idx < leaf.degree() as usize && leaf.get_key(idx) == *key {
+                    result.push(leaf.get_val(idx)?);
+                    idx += 1;
+                }
+                match leaf.next() {
+                    Some(next) if idx == leaf.degree() as usize => leaf = self.get_node(next)?,
+                    _ => return Ok(result),
+                }
+                idx = 0;
+            }
+        }
+
+        fn replace_val(&self, key: &K, val: V) -> Result<V, BtreeError> {
+            let entry = self.find_entry(key)?;
+            self.replace_entry(entry, key, val).map(|(old, _)| old)
+        }
+
+        fn replace_entry(
+            &self,
+            entry: LeafEntry<K, V>,
+            key: &K,
+            val: V,
+        ) -> Result<(V, bool), BtreeError> {
+            // in place while the leaf fits to the block, otherwise the entry
+            // is moved by remove and insert, true is returned then.
+            // Runs inside of the operation.
+            let ((leaf, last_ref), idx) = entry;
+            let val = self.spill(key, Slot::Inline(val))?;
+            let old_size = bincode::serialized_size(&leaf.0.borrow().st.vals[idx])?;
+            let new_size = bincode::serialized_size(&val)?;
+            if leaf.encoded_size() + new_size <= self.block_size() as u64 + old_size {
+                let old = leaf.update_val(idx, val);
+                let result = self.read_val(old.clone())?;
+                self.free_overflow(&old)?;
+                return Ok((result, false));
+            }
+            let old = leaf.get_val(idx)?;
+            let mut mgr = TaskManager::new();
+            mgr.add_remove(last_ref, idx);
+            mgr.run()?;
+            self.insert_tasks(key.clone(), val)?.run()?;
+            Ok((old, true))
+        }
+
+        /// Inserts the pair or replaces the value of the existing key,
+        /// returns the replaced value.
+        pub fn upsert(&self, key: K, val: V) -> Result<Option<V>, BtreeError> {
+            debug!("Btree:upsert: key={:?}, val={:?}", key, val);
+            self.check_entry_size(&key)?;
+            match self.find(&key) {
+                Ok(_) => self.atomic(|| self.replace_val(&key, val)).map(Some),
+                Err(BtreeError::KeyNotFound) => self.insert(key, val).map(|_| None),
+                Err(err) => Err(err),
+            }
+        }
+
+        /// Replaces the value of the existing key by `f(value)`, returns the old value.
+        pub fn update<F: FnOnce(&V) -> V>(&self, key: &K, f: F) -> Result<V, BtreeError> {
+            debug!("Btree:update: key={:?}", key);
+            let val = f(&self.find(key)?);
+            self.check_entry_size(key)?;
+            self.atomic(|| self.replace_val(key, val))
+        }
+
+        /// Returns the value of the key, inserts `f()` if the key is not found.
+        pub fn get_or_insert_with<F: FnOnce() -> V>(&self, key: K, f: F) -> Result<V, BtreeError> {
+            debug!("Btree:get_or_insert_with: key={:?}", key);
+            match self.find(&key) {
+                Err(BtreeError::KeyNotFound) => {
+                    let val = f();
+                    self.insert(key, val.clone())?;
+                    Ok(val)
+                }
+                result => result,
+            }
+        }
+
+        /// Starts a write transaction. Its changes are not visible through
+        /// the tree until `commit`, which applies them as one atomic operation.
+        pub fn transaction(&self) -> Transaction<K, V> {
+            trace!("Btree:transaction: called");
+            Transaction {
+                bt: self.clone(),
+                writes: BTreeMap::new(),
+            }
+        }
+
+        pub fn compact(&self) -> Result<(), BtreeError> {
+            debug!("Btree:compact: called");
+            self.check_no_snapshots()?;
+            // flush and disable cache
+            self.flush_cache()?;
+            let old_cache_cap = self.set_cache_cap(0)?;
+            let result = self.atomic(|| self.compact_blocks());
+            self.set_cache_cap(old_cache_cap)?;
+            result
+        }
+
+        fn check_no_snapshots(&self) -> Result<(), BtreeError> {
+            // compaction moves the blocks, the snapshots read them where they are
+            self.release_retired()?;
+            let snapshots = &mut self.0.borrow_mut().snapshots;
+            if snapshots.oldest().is_some() || !snapshots.retired.is_empty() {
+                return Err(BtreeError::SnapshotAlive);
+            }
+            Ok(())
+        }
+
+        fn compact_blocks(&self) -> Result<(), BtreeError> {
+            let block_size = self.block_size();
+            let file_size = self.get_file_size() as Addr;
+            // make list of all available block-addresses in the file
+            let mut addrs = Vec::new();
+            let mut addr = block_size;
+            while addr < file_size {
+                addrs.push(addr);
+                addr += block_size;
+            }
+            // traverse tree, DFS, with the overflow chains of the values
+            let mut blocks = Vec::new();
+            let mut stack = vec![(self.root(), BlockOwner::Root)];
+            while let Some((node_addr, owner)) = stack.pop() {
+                blocks.push((node_addr, owner));
+                let node = self.get_node(node_addr)?;
+                if !node.is_leaf() {
+                    for (index, child) in node.get_refs().into_iter().enumerate() {
+                        let parent = StepInfo {
+                            index,
+                            addr: node_addr,
+                        };
+                        stack.push((child, BlockOwner::Child(parent)));
+                    }
+                    continue;
+                }
+                for (index, slot) in node.0.borrow().st.vals.iter().enumerate() {
+                    let mut next = slot.head();
+                    let mut owner = BlockOwner::Value(StepInfo {
+                        index,
+                        addr: node_addr,
+                    });
+                    while let Some(addr) = next {
+                        // looped chain
+                        if blocks.len() > addrs.len() {
+                            break;
+                        }
+                        blocks.push((addr, owner));
+                        next = self.get_overflow(addr)?.next;
+                        owner = BlockOwner::Chain(addr);
+                    }
+                }
+            }
+            // sort blocks by addresses
+            blocks.sort_by_key(|(addr, _)| *addr);
+            if blocks.len() > addrs.len() {
+                return Err(BtreeError::Corrupt("block is referenced twice".to_string()));
+            }
+            // referring blocks could be moved before the blocks they refer to
+            let mut moved = HashMap::new();
+            // check if blocks used or not
+            while !blocks.is_empty() {
+                let (block, owner) = blocks.remove(0);
+                let addr = addrs.remove(0);
+                if block == addr {
+                    // block used, skip
+                    continue;
+                }
+                blocks.insert(0, (block, owner));
+                let (last, owner) = blocks.pop().unwrap();
+                // block is free. Move the last block to this one and update refs
+                moved.insert(last, addr);
+                self.move_block(last, addr, owner, &moved)?;
+            }
+            // if there is some unused blocks left - trim them
+            if !addrs.is_empty() {
+                let trim_size = addrs.len() as u64 * block_size as u64;
+                let addr = self.get_file_size();
+                trace!("Btree:compact: len before trim={}", addr);
+                self.set_file_size(addr - trim_size)?;
+            }
+            // free blocks are either reused or trimmed
+            self.0.borrow_mut().header.free = None;
+            self.flush()
+        }
+
+        /// One step of the incremental compaction: moves up to `max_blocks` used
+        /// blocks from the end of the file to the free blocks before them and cuts off
+        /// the free blocks at the end. Each step is a separate atomic operation
+        /// with the cache enabled, the tree is usable between the steps. Progress is
+        /// the file itself, compaction interrupted by a crash continues with the next step.
+        pub fn compact_step(&self, max_blocks: usize) -> Result<CompactProgress, BtreeError> {
+            debug!("Btree:compact_step: max_blocks={}", max_blocks);
+            self.check_no_snapshots()?;
+            self.atomic(|| self.compact_tail(max_blocks))
+        }
+
+        fn compact_tail(&self, max_blocks: usize) -> Result<CompactProgress, BtreeError> {
+            let block_size = self.block_size();
+            let mut end = self.get_file_size() as Addr;
+            // free list in its order, blocks taken from it are unlinked at the end
+            let mut list = Vec::new();
+            let mut next = self.0.borrow().header.free;
+            while let Some(addr) = next {
+                if list.len() as u64 >= end as u64 / block_size as u64 {
+                    return Err(BtreeError::Corrupt("free list is looped".to_string()));
+                }
+                list.push(addr);
+                next = self.get_node(addr)?.next();
+            }
+            let mut free: BTreeSet<Addr> = list.iter().copied().collect();
+            // plan the moves: the last used block goes to the first free one
+            let mut progress = CompactProgress::default();
+            let mut moves = Vec::new();
+            while end > block_size {
+                let last = end - block_size;
+                if free.remove(&last) {
+                    self.0.borrow().cache.borrow_mut().remove(last);
+                    progress.trimmed += 1;
+                } else if moves.len() < max_blocks {
+                    // free blocks are below the end, the last block is used
+                    match free.pop_first() {
+                        Some(to) => moves.push((last, to)),
+                        None => break,
+                    }
+                } else {
+                    break;
+                }
+                end = last;
+            }
+            progress.moved = moves.len();
+            progress.done = free.is_empty();
+
+            let owners = self.block_owners(moves.iter().map(|(from, _)| *from).collect())?;
+            let mut moved = HashMap::new();
+            for (from, to) in moves {
+                let owner = *owners.get(&from).ok_or_else(|| {
+                    BtreeError::Corrupt(format!("block {} is neither used nor free", from))
+                })?;
+                moved.insert(from, to);
+                self.move_block(from, to, owner, &moved)?;
+            }
+            // the rest of the free list is linked again
+            let rest: Vec<Addr> = list
+                .into_iter()
+                .filter(|addr| free.contains(addr))
+                .collect();
+            for (index, addr) in rest.iter().enumerate() {
+                let node = self.get_node(*addr)?;
+                let next = rest.get(index + 1).copied();
+                if node.next() != next {
+                    node.set_next(next);
+                }
+            }
+            self.0.borrow_mut().header.free = rest.first().copied();
+            self.set_file_size(end as u64)?;
+            self.flush()?;
+            debug!("Btree:compact_step: done, progress={:?}", progress);
+            Ok(progress)
+        }
+
+        fn block_owners(
+            &self,
+            mut blocks: HashSet<Addr>,
+        ) -> Result<HashMap<Addr, BlockOwner>, BtreeError> {
+            // traverse tree, DFS, until all blocks are found. Nodes out of the cache
+            // are read in place, the walk doesn't fill the cache.
+            let mut owners = HashMap::new();
+            let mut walked = 0;
+            let file_blocks = self.get_file_size() / self.block_size() as u64;
+            let mut stack = vec![(self.root(), BlockOwner::Root)];
+            while let Some((addr, owner)) = stack.pop() {
+                if blocks.is_empty() {
+                    break;
+                }
+                if blocks.remove(&addr) {
+                    owners.insert(addr, owner);
+                }
+                let (children, heads) = match self.peek_node(addr) {
+                    Some(node) => {
+                        let st = &node.0.borrow().st;
+                        (st.refs.clone(), st.vals.iter().map(Slot::head).collect())
+                    }
+                    None => self.view(addr, |view| {
+                        let mut children = Vec::new();
+                        let mut heads = Vec::new();
+                        for index in 0..view.count {
+                            if view.leaf {
+                                heads.push(view.val(index)?.head());
+                            } else {
+                                children.push(view.child(index)?);
+                            }
+                        }
+                        Ok((children, heads))
+                    })?,
+                };
+                for (index, child) in children.into_iter().enumerate() {
+                    stack.push((child, BlockOwner::Child(StepInfo { index, addr })));
+                }
+                for (index, head) in heads.into_iter().enumerate() {
+                    let mut owner = BlockOwner::Value(StepInfo { index, addr });
+                    let mut next = head;
+                    while let Some(block) = next {
+                        walked += 1;
+                        if walked > file_blocks {
+                            return Err(BtreeError::Corrupt(
+                                "overflow chain is looped".to_string(),
+                            ));
+                        }
+                        if blocks.remove(&block) {
+                            owners.insert(block, owner);
+                        }
+                        next = self.get_overflow(block)?.next;
+                        owner = BlockOwner::Chain(block);
+                    }
+                }
+            }
+            Ok(owners)
+        }
+
+        fn move_block(
+            &self,
+            from: Addr,
+            to: Addr,
+            owner: BlockOwner,
+            moved: &HashMap<Addr, Addr>,
+        ) -> Result<(), BtreeError> {
+            // the block is moved to the free one, its owner is updated.
+            // The cache keeps no node at the old address and no free node at the new one.
+            trace!(
+                "Btree:move_block: from={}, to={}, owner={:?}",
+                from,
+                to,
+                owner
+            );
+            let moved_addr = |addr| *moved.get(&addr).unwrap_or(&addr);
+            self.0.borrow().cache.borrow_mut().remove(to);
+            match owner {
+                BlockOwner::Root | BlockOwner::Child(_) => {
+                    let node = self.get_node(from)?;
+                    self.0.borrow().cache.borrow_mut().remove(from);
+                    node.set_addr(to);
+                    if node.is_leaf() {
+                        if let Some(prev) = node.prev() {
+                            self.get_node(prev)?.set_next(Some(to));
+                        }
+                        if let Some(next) = node.next() {
+                            self.get_node(next)?.set_prev(Some(to));
+                        }
+                    }
+                }
+                BlockOwner::Value(_) | BlockOwner::Chain(_) => {
+                    let st = self.get_overflow(from)?;
+                    self.put_overflow(to, &st)?;
+                    self.0
+                        .borrow_mut()
+                        .pending
+                        .as_mut()
+                        .unwrap()
+                        .overflow
+                        .remove(&from);
+                }
+            }
+            match owner {
+                // update root
+                BlockOwner::Root => self.set_root(to)?,
+                BlockOwner::Child(parent) => {
+                    let parent_node = self.get_node(moved_addr(parent.addr))?;
+                    parent_node.update_ref(parent.index, to);
+                }
+                BlockOwner::Value(leaf) => {
+                    let leaf_node = self.get_node(moved_addr(leaf.addr))?;
+                    let slot = leaf_node.0.borrow().st.vals[leaf.index].clone();
+                    if let Slot::Overflow { len, .. } = slot {
+                        leaf_node.update_val(leaf.index, Slot::Overflow { head: to, len });
+                    }
+                }
+                BlockOwner::Chain(prev) => {
+                    let prev = moved_addr(prev);
+                    let mut st = self.get_overflow(prev)?;
+                    st.next = Some(to);
+                    self.put_overflow(prev, &st)?;
+                }
+            }
+            Ok(())
+        }
+    }
+
+    impl<K: Key, V: Val> Btree<K, V> {
+        pub fn dump_to_stdout(&self) -> Result<(), BtreeError> {
+            println!("\n------------------------------");
+            print!("{}", self.dump_to_string()?);
+            println!("------------------------------");
+            Ok(())
+        }
+
+        pub fn dump_to_string(&self) -> Result<String, BtreeError> {
+            let mut result = String::new();
+            let mut stack = vec![self.root()];
+            while let Some(addr) = stack.pop() {
+                let node = self.get_node(addr)?;
+                writeln!(result, "{:?}", node).unwrap();
+                if !node.is_leaf() {
+                    stack.extend_from_slice(&node.0.borrow().st.refs)
+                }
+            }
+            Ok(result)
+        }
+
+        /// Returns the structure of the tree in the Graphviz format: nodes with their
+        /// block addresses, fill levels and keys, child edges and next links of the leaves.
+        pub fn to_dot(&self) -> Result<String, BtreeError> {
+            let nodes = self.export_nodes()?;
+            let mut result = String::new();
+            writeln!(result, "digraph btree {{").unwrap();
+            writeln!(result, "    node [shape=record, fontname=\"monospace\"];").unwrap();
+            for node in nodes.iter() {
+                let head = format!("A={} | {:.0}%", node.addr, node.fill * 100.0);
+                let keys = if node.leaf {
+                    // leaf keys are many, the bounds are enough
+                    match (node.keys.first(), node.keys.last()) {
+                        (Some(first), Some(last)) => format!(
+                            "{} .. {} ({})",
+                            dot_escape(first),
+                            dot_escape(last),
+                            node.keys.len()
+                        ),
+                        _ => "empty".to_string(),
+                    }
+                } else {
+                    let ports: Vec<String> = (0..node.keys.len())
+                        .map(|index| format!("<c{}> {}", index, dot_escape(&node.keys[index])))
+                        .collect();
+                    ports.join(" | ")
+                };
+                writeln!(
+                    result,
+                    "    n{} [label=\"{{{}}} | {}\"];",
+                    node.addr, head, keys
+                )
+                .unwrap();
+                for (index, child) in node.children.iter().enumerate() {
+                    writeln!(result, "    n{}:c{} -> n{};", node.addr, index, child).unwrap();
+                }
+                if let Some(next) = node.next {
+                    writeln!(
+                        result,
+                        "    n{} -> n{} [style=dashed, constraint=false];",
+                        node.addr, next
+                    )
+                    .unwrap();
+                }
+            }
+            // nodes of a level are drawn in a row
+            let height = nodes.last().map_or(0, |node| node.level + 1);
+            for level in 0..height {
+                let row: Vec<String> = nodes
+                    .iter()
+                    .filter(|node| node.level == level)
+                    .map(|node| format!("n{};", node.addr))
+                    .collect();
+                writeln!(result, "    {{ rank=same; {} }}", row.join(" ")).unwrap();
+            }
+            writeln!(result, "}}").unwrap();
+            Ok(result)
+        }
+
+        /// Returns the structure of the tree as JSON: the header fields and the nodes
+        /// level by level, the root first. Values are not included.
+        pub fn to_json(&self) -> Result<String, BtreeError> {
+            let nodes = self.export_nodes()?;
+            let tree = TreeExport {
+                block_size: self.block_size(),
+                root: self.root(),
+                height: nodes.last().map_or(0, |node| node.level + 1),
+                multimap: self.is_multimap(),
+                nodes,
+            };
+            serde_json::to_string_pretty(&tree).map_err(|err| BtreeError::Io(err.into()))
+        }
+
+        fn export_nodes(&self) -> Result<Vec<NodeExport<K>>, BtreeError> {
+            // BFS, nodes out of the cache are read in place
+            let block_size = self.block_size() as f64;
+            let mut nodes = Vec::new();
+            let mut level = vec![self.root()];
+            let mut depth = 0;
+            while !level.is_empty() {
+                let mut children = Vec::new();
+                for addr in level {
+                    let mut node = match self.peek_node(addr) {
+                        Some(node) => {
+                            let st = &node.0.borrow().st;
+                            NodeExport {
+                                addr,
+                                level: depth,
+                                leaf: st.leaf,
+                                keys: st.keys.clone(),
+                                children: st.refs.clone(),
+                                next: st.next,
+                                prev: st.prev,
+                                size: st.encoded_size(),
+                                fill: 0.0,
+                            }
+                        }
+                        None => self.view(addr, |view| {
+                            let mut node = NodeExport {
+                                addr,
+                                level: depth,
+                                leaf: view.leaf,
+                                keys: Vec::new(),
+                                children: Vec::new(),
+                                next: view.next(),
+                                prev: view.prev(),
+                                size: view.size(),
+                                fill: 0.0,
+                            };
+                            for index in 0..view.count {
+                                node.keys.push(view.key(index)?);
+                                if !view.leaf {
+                                    node.children.push(view.child(index)?);
+                                }
+                            }
+                            Ok(node)
+                        })?,
+                    };
+                    node.fill = node.size as f64 / block_size;
+                    children.extend_from_slice(&node.children);
+                    nodes.push(node);
+                }
+                level = children;
+                depth += 1;
+            }
+            Ok(nodes)
+        }
+    }
+
+    fn dot_escape<K: Debug>(key: &K) -> String {
+        // record labels treat these as the field separators
+        let mut result = String::new();
+        for c in format!("{:?}", key).chars() {
+            if "{}|<>\"\\ ".contains(c) {
+                result.push('\\');
+            }
+            result.push(c);
+        }
+        result
+    }
+
+    // node of the exported structure, see Btree::to_json
+    #[derive(Serialize)]
+    struct NodeExport<K> {
+        addr: Addr,
+        level: u32,
+        leaf: bool,
+        keys: Vec<K>,
+        children: Vec<Addr>,
+        next: Option<Addr>,
+        prev: Option<Addr>,
+        size: u64, // bytes of the block used by the node
+        fill: f64,
+    }
+
+    #[derive(Serialize)]
+    struct TreeExport<K> {
+        block_size: u32,
+        root: Addr,
+        height: u32,
+        multimap: bool,
+        nodes: Vec<NodeExport<K>>,
+    }
+
+    impl<K: Key, V: Val> Btree<K, V> {
+        /// Returns the cache and io counters and the shape of the tree.
+        /// Walks all nodes and the free list, the walk is not counted.
+        pub fn stats(&self) -> Result<BtreeStats, BtreeError> {
+            let cache = self.cache_stats();
+            let mut stats = {
+                let counters = &self.0.borrow().counters;
+                BtreeStats {
+                    cache_hits: cache.hits,
+                    cache_misses: cache.misses,
+                    evictions: cache.evictions,
+                    dirty_flushes: cache.write_backs,
+                    node_reads: counters.node_reads.get(),
+                    node_decodes: counters.node_decodes.get(),
+                    node_writes: counters.node_writes.get(),
+                    splits: counters.splits.get(),
+                    merges: counters.merges.get(),
+                    rebalances: counters.rebalances.get(),
+                    syncs: counters.syncs.get(),
+                    ..BtreeStats::default()
+                }
+            };
+            let mut used = 0;
+            let mut level = vec![self.root()];
+            while !level.is_empty() {
+                stats.height += 1;
+                let mut children = Vec::new();
+                for addr in level {
+                    used += self.stats_node(addr, &mut children)?;
+                    stats.nodes += 1;
+                }
+                level = children;
+            }
+            stats.fill_factor = used as f64 / (stats.nodes * self.block_size() as u64) as f64;
+            let mut next = self.0.borrow().header.free;
+            while let Some(addr) = next {
+                stats.free_blocks += 1;
+                next = self.view(addr, |view| Ok(view.next()))?;
+            }
+            self.0.borrow().counters.node_reads.set(stats.node_reads);
+            Ok(stats)
+        }
+
+        fn stats_node(&self, addr: Addr, children: &mut Vec<Addr>) -> Result<u64, BtreeError> {
+            // page size of the node, the cache may have it changed since written
+            if let Some(node) = self.peek_node(addr) {
+                let st = &node.0.borrow().st;
+                if !st.leaf {
+                    children.extend_from_slice(&st.refs);
+                }
+                return Ok(st.encoded_size());
+            }
+            self.view(addr, |view| {
+                if !view.leaf {
+                    for index in 0..view.count {
+                        children.push(view.child(index)?);
+                    }
+                }
+                Ok(view.size())
+            })
+        }
+    }
+
+    /// Result of `Btree::compact_step`.
+    #[derive(Debug, Default, Clone, PartialEq)]
+    pub struct CompactProgress {
+        pub moved: usize,   // used blocks moved to the free ones
+        pub trimmed: usize, // free blocks cut off at the end of the file
+        pub done: bool,     // no free blocks are left
+    }
+
+    /// Result of `Btree::stats`: counters since the tree is created or loaded
+    /// and the current shape of the tree.
+    #[derive(Debug, Default, Clone, PartialEq)]
+    pub struct BtreeStats {
+        pub cache_hits: u64,
+        pub cache_misses: u64,
+        pub evictions: u64,
+        pub dirty_flushes: u64, // changed nodes written back by the cache
+        pub node_reads: u64,    // nodes read from the file
+        pub node_decodes: u64,  // nodes read from the file and decoded whole
+        pub node_writes: u64,   // nodes written to the file
+        pub splits: u64,
+        pub merges: u64,
+        pub rebalances: u64, // entries moved between siblings
+        pub syncs: u64,      // journal and blocks synced to the disk, see Durability
+        pub height: u32,
+        pub nodes: u64,
+        pub fill_factor: f64, // used part of the node blocks
+        pub free_blocks: u64,
+    }
+
+    /// Result of `Btree::verify`: tree shape and the problems found.
+    #[derive(Debug, Default)]
+    pub struct VerifyReport {
+        pub nodes: u64,
+        pub leaves: u64,
+        pub depth: u32,
+        pub entries: u64,
+        pub overflow_blocks: u64,
+        pub free_blocks: u64,
+        pub problems: Vec<VerifyProblem>,
+    }
+
+    /// Integrity problem found by `Btree::verify`, `addr` is the block of the node.
+    #[derive(Debug, Clone, PartialEq, Eq)]
+    pub enum VerifyProblem {
+        Unreadable { addr: Addr, reason: String },
+        Malformed { addr: Addr }, // keys and values/children counts differ
+        KeyOrder { addr: Addr },  // keys are not strictly ascending
+        DegreeOverflow { addr: Addr, degree: Degree },
+        DegreeUnderflow { addr: Addr, degree: Degree },
+        Separator { addr: Addr }, // keys don't match the separators of the parent
+        LeafChain { addr: Addr }, // next/prev links skip or reorder leaves
+        LeafDepth { addr: Addr, depth: u32 },
+        OverflowChain { addr: Addr, index: usize }, // value chain of the leaf entry is broken
+        SharedBlock { addr: Addr },                 // block is referenced twice
+        FreeBlockInUse { addr: Addr },              // block is both in the tree and the free list
+        LostBlock { addr: Addr }, // block is neither in the tree nor the free list
+    }
+
+    impl VerifyReport {
+        pub fn is_ok(&self) -> bool {
+            self.problems.is_empty()
+        }
+    }
+
+    impl Display for VerifyProblem {
+        fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+            match self {
+                VerifyProblem::Unreadable { addr, reason } => {
+                    write!(f, "node {} is unreadable: {}", addr, reason)
+                }
+                VerifyProblem::Malformed { addr } => {
+                    write!(f, "node {} has mismatched keys and values", addr)
+                }
+                VerifyProblem::KeyOrder { addr } => {
+                    write!(f, "node {} keys are out of order", addr)
+                }
+                VerifyProblem::DegreeOverflow { addr, degree } => {
+                    write!(f, "node {} degree {} is over max_degree", addr, degree)
+                }
+                VerifyProblem::DegreeUnderflow { addr, degree } => {
+                    write!(f, "node {} degree {} is under min_degree", addr, degree)
+                }
+                VerifyProblem::Separator { addr } => {
+                    write!(f, "node {} keys don't match the parent separators", addr)
+                }
+                VerifyProblem::LeafChain { addr } => {
+                    write!(f, "leaf {} is out of the leaf chain", addr)
+                }
+                VerifyProblem::LeafDepth { addr, depth } => {
+                    write!(f, "leaf {} is at depth {}", addr, depth)
+                }
+                VerifyProblem::OverflowChain { addr, index } => {
+                    write!(f, "leaf {} value {} overflow chain is broken", addr, index)
+                }
+                VerifyProblem::SharedBlock { addr } => {
+                    write!(f, "block {} is referenced twice", addr)
+                }
+                VerifyProblem::FreeBlockInUse { addr } => {
+                    write!(f, "free block {} is used by the tree", addr)
+                }
+                VerifyProblem::LostBlock { addr } => {
+                    write!(f, "block {} is neither used nor free", addr)
+                }
+            }
+        }
+    }
+
+    // node to check with the key bounds from the parent separators
+    struct VerifyStep<K> {
+        addr: Addr,
+        depth: u32,
+        lo: Option<K>, // all keys of the subtree are >= lo
+        hi: Option<K>, // and < hi
+    }
+
+    impl<K: Key, V: Val> Btree<K, V> {
+        /// Walks the whole tree and the free list and checks their invariants.
+        /// Corrupted blocks are reported as problems, io errors are returned.
+        pub fn verify(&self) -> Result<VerifyReport, BtreeError> {
+            let mut report = VerifyReport::default();
+            let mut used = HashSet::new();
+            // traverse tree, DFS, leaves are visited in key order
+            let mut stack = vec![VerifyStep {
+                addr: self.root(),
+                depth: 1,
+                lo: None,
+                hi: None,
+            }];
+            let mut prev_leaf: Option<Node<K, V>> = None;
+            // entry sizes and nodes under min_degree, internal nodes and leaves
+            let mut sizes = [HashSet::new(), HashSet::new()];
+            let mut drained = [Vec::new(), Vec::new()];
+            while let Some(step) = stack.pop() {
+                let addr = step.addr;
+                if !used.insert(addr) {
+                    report.problems.push(VerifyProblem::SharedBlock { addr });
+                    continue;
+                }
+                let node = match self.verify_get_node(addr, &mut report)? {
+                    Some(node) => node,
+                    None => continue,
+                };
+                report.nodes += 1;
+                self.verify_node(&node, &step, &mut report);
+                let leaf = node.is_leaf() as usize;
+                sizes[leaf].extend(node.0.borrow().st.entry_sizes());
+                // a split of the full internal node may leave min_degree children,
+                // less is not expected in the other nodes but the root
+                if !node.is_root() && !node.is_empty() && node.degree() < self.min_degree() {
+                    drained[leaf].push((addr, node.degree()));
+                }
+
+                if node.is_leaf() {
+                    report.leaves += 1;
+                    report.entries += node.degree() as u64;
+                    self.verify_overflow(&node, &mut used, &mut report)?;
+                    if report.depth == 0 {
+                        report.depth = step.depth;
+                    } else if report.depth != step.depth {
+                        report.problems.push(VerifyProblem::LeafDepth {
+                            addr,
+                            depth: step.depth,
+                        });
+                    }
+                    let prev_addr = prev_leaf.as_ref().map(|prev| prev.addr());
+                    let linked = match &prev_leaf {
+                        Some(prev) => prev.next() == Some(addr),
+                        None => true,
+                    };
+                    if !linked || node.prev() != prev_addr {
+                        report.problems.push(VerifyProblem::LeafChain { addr });
+                    }
+                    prev_leaf = Some(node);
+                    continue;
+                }
+                let keys = node.0.borrow().st.keys.clone();
+                let refs = node.get_refs();
+                // push in reverse, so the leftmost child is checked first
+                for i in (0..refs.len().min(keys.len())).rev() {
+                    stack.push(VerifyStep {
+                        addr: refs[i],
+                        depth: step.depth + 1,
+                        lo: if i == 0 {
+                            step.lo.clone()
+                        } else {
+                            Some(keys[i].clone())
+                        },
+                        hi: match keys.get(i + 1) {
+                            Some(key) => Some(key.clone()),
+                            None => step.hi.clone(),
+                        },
+                    });
+                }
+            }
+            if let Some(last) = prev_leaf {
+                if last.next().is_some() {
+                    report
+                        .problems
+                        .push(VerifyProblem::LeafChain { addr: last.addr() });
+                }
+            }
+            // variable length entries are split by size, not by count,
+            // min_degree holds only when all entries are of the same size
+            for (sizes, drained) in sizes.iter().zip(drained) {
+                if sizes.len() <= 1 {
+                    let problems = drained
+                        .into_iter()
+                        .map(|(addr, degree)| VerifyProblem::DegreeUnderflow { addr, degree });
+                    report.problems.extend(problems);
+                }
+            }
+
+            // free list, stops on a block seen before
+            let mut free = HashSet::new();
+            let mut next = self.0.borrow().header.free;
+            while let Some(addr) = next {
+                if used.contains(&addr) {
+                    report.problems.push(VerifyProblem::FreeBlockInUse { addr });
+                    break;
+                }
+                if !free.insert(addr) {
+                    report.problems.push(VerifyProblem::SharedBlock { addr });
+                    break;
+                }
+                report.free_blocks += 1;
+                next = match self.verify_get_node(addr, &mut report)? {
+                    Some(node) => node.next(),
+                    None => None,
+                };
+            }
+
+            // every block is either in the tree, free or kept for the snapshots
+            let retired: HashSet<Addr> = {
+                let bti = self.0.borrow();
+                bti.snapshots
+                    .retired
+                    .iter()
+                    .map(|(_, addr)| *addr)
+                    .collect()
+            };
+            let block_size = self.block_size();
+            let file_size = self.get_file_size();
+            let mut addr = block_size;
+            while (addr as u64) < file_size {
+                if !used.contains(&addr) && !free.contains(&addr) && !retired.contains(&addr) {
+                    report.problems.push(VerifyProblem::LostBlock { addr });
+                }
+                addr += block_size;
+            }
+            debug!("Btree:verify: done, report={:?}", report);
+            Ok(report)
+        }
+
+        fn verify_overflow(
+            &self,
+            leaf: &Node<K, V>,
+            used: &mut HashSet<Addr>,
+            report: &mut VerifyReport,
+        ) -> Result<(), BtreeError> {
+            let addr = leaf.addr();
+            let vals = leaf.0.borrow().st.vals.clone();
+            for (index, slot) in vals.iter().enumerate() {
+                let (head, len) = match slot {
+                    Slot::Overflow { head, len } => (*head, *len),
+                    Slot::Inline(_) => continue,
+                };
+                let mut next = Some(head);
+                let mut size = 0;
+                while let Some(block) = next {
+                    if !used.insert(block) {
+                        report
+                            .problems
+                            .push(VerifyProblem::SharedBlock { addr: block });
+                        break;
+                    }
+                    report.overflow_blocks += 1;
+                    next = match self.get_overflow(block) {
+                        Ok(st) => {
+                            size += st.data.len() as u64;
+                            st.next
+                        }
+                        Err(BtreeError::Io(err)) => return Err(BtreeError::Io(err)),
+                        Err(err) => {
+                            let reason = err.to_string();
+                            report.problems.push(VerifyProblem::Unreadable {
+                                addr: block,
+                                reason,
+                            });
+                            break;
+                        }
+                    };
+                }
+                if next.is_none() && size != len {
+                    report
+                        .problems
+                        .push(VerifyProblem::OverflowChain { addr, index });
+                }
+            }
+            Ok(())
+        }
+
+        fn verify_get_node(
+            &self,
+            addr: Addr,
+            report: &mut VerifyReport,
+        ) -> Result<Option<Node<K, V>>, BtreeError> {
+            match self.get_node(addr) {
+                Ok(node) => Ok(Some(node)),
+                Err(BtreeError::Io(err)) => Err(BtreeError::Io(err)),
+                Err(err) => {
+                    let reason = err.to_string();
+                    report
+                        .problems
+                        .push(VerifyProblem::Unreadable { addr, reason });
+                    Ok(None)
+                }
+            }
+        }
+
+        fn verify_node(&self, node: &Node<K, V>, step: &VerifyStep<K>, report: &mut VerifyReport) {
+            let addr = node.addr();
+            let inner = node.0.borrow();
+            let st = &inner.st;
+            let items = if st.leaf {
+                st.vals.len()
+            } else {
+                st.refs.len()
+            };
+            let other = if st.leaf {
+                st.refs.len()
+            } else {
+                st.vals.len()
+            };
+            if items != st.keys.len() || other != 0 {
+                report.problems.push(VerifyProblem::Malformed { addr });
+            }
+            // the first key of the internal node is skipped by the search,
+            // it's only a copy of the parent separator and may be stale at the root
+            let keys = if st.leaf || st.keys.is_empty() {
+                &st.keys[..]
+            } else {
+                &st.keys[1..]
+            };
+            // equal keys are allowed in multimap, also in the separators
+            let multimap = self.is_multimap();
+            if keys
+                .windows(2)
+                .any(|pair| pair[0] > pair[1] || (!multimap && pair[0] == pair[1]))
+            {
+                report.problems.push(VerifyProblem::KeyOrder { addr });
+            }
+
+            let degree = st.keys.len() as Degree;
+            if degree > self.max_degree() {
+                report
+                    .problems
+                    .push(VerifyProblem::DegreeOverflow { addr, degree });
+            }
+            // only the root leaf may be empty
+            if node.is_empty() && !(node.is_root() && st.leaf) {
+                report
+                    .problems
+                    .push(VerifyProblem::DegreeUnderflow { addr, degree });
+            }
+
+            let out_of_bounds = |key: &K| {
+                step.lo.as_ref().is_some_and(|lo| key < lo)
+                    || step
+                        .hi
+                        .as_ref()
+                        .is_some_and(|hi| key > hi || (!multimap && key == hi))
+            };
+            // leaf separators may be shortened, see separator
+            let separator_matches = match (&step.lo, st.keys.first()) {
+                (Some(lo), Some(first)) if !st.leaf => first == lo,
+                _ => true,
+            };
+            if keys.iter().any(out_of_bounds) || !separator_matches {
+                report.problems.push(VerifyProblem::Separator { addr });
+            }
+        }
+    }
+
+    impl<K: Key, V: Val + PartialEq> Btree<K, V> {
+        /// Sets the value of the key to `new` if it's equal to `expected`.
+        /// Returns false if the value is different.
+        pub fn compare_and_swap(&self, key: &K, expected: &V, new: V) -> Result<bool, BtreeError> {
+            debug!("Btree:compare_and_swap: key={:?}, new={:?}", key, new);
+            if self.find(key)? != *expected {
+                return Ok(false);
+            }
+            self.check_entry_size(key)?;
+            self.atomic(|| self.replace_val(key, new)).map(|_| true)
+        }
+
+        /// Removes the first entry with the key and the value.
+        pub fn remove_one(&self, key: &K, val: &V) -> Result<(), BtreeError> {
+            debug!("Btree:remove_one: key={:?}, val={:?}", key, val);
+            let ((mut leaf, mut pref), mut idx) =
+                self.find_first(key)?.ok_or(BtreeError::KeyNotFound)?;
+            loop {
+                if idx == leaf.degree() as usize {
+                    // path is needed to remove from the next leaf
+                    let (next, next_pref) =
+                        self.next_leaf(&pref)?.ok_or(BtreeError::KeyNotFound)?;
+                    leaf = next;
+                    pref = next_pref;
+                    idx = 0;
+                    continue;
+                }
+                if leaf.get_key(idx) != *key {
+                    return Err(BtreeError::KeyNotFound);
+                }
+                if leaf.get_val(idx)? == *val {
+                    let mut mgr = TaskManager::new();
+                    mgr.add_remove(pref, idx);
+                    return self.atomic(|| mgr.run());
+                }
+                idx += 1;
+            }
+        }
+    }
+
+    /// Write transaction of `Btree`, see `Btree::transaction`.
+    ///
+    /// Changes are kept in memory. `find` of the transaction sees them,
+    /// the tree doesn't until `commit`. Dropped transaction is rolled back.
+    pub struct Transaction<K: Key, V: Val> {
+        bt: Btree<K, V>,
+        writes: BTreeMap<K, TxWrite<V>>,
+    }
+
+    // change of one key made by the transaction
+    struct TxWrite<V> {
+        existed: bool,  // key was in the tree when the transaction touched it
+        val: Option<V>, // value after the transaction, none if removed
+    }
+
+    impl<K: Key, V: Val> Transaction<K, V> {
+        pub fn find(&self, key: &K) -> Result<V, BtreeError> {
+            trace!("Transaction:find: key={:?}", key);
+            match self.writes.get(key) {
+                Some(write) => write.val.clone().ok_or(BtreeError::KeyNotFound),
+                None => self.bt.find(key),
+            }
+        }
+
+        pub fn insert(&mut self, key: K, val: V) -> Result<(), BtreeError> {
+            trace!("Transaction:insert: key={:?}, val={:?}", key, val);
+            self.bt.check_entry_size(&key)?;
+            match self.writes.get_mut(&key) {
+                Some(TxWrite { val: Some(_), .. }) => Err(BtreeError::DuplicateKey),
+                Some(write) => {
+                    write.val = Some(val);
+                    Ok(())
+                }
+                None => match self.bt.find(&key) {
+                    Ok(_) => Err(BtreeError::DuplicateKey),
+                    Err(BtreeError::KeyNotFound) => {
+                        let write = TxWrite {
+                            existed: false,
+                            val: Some(val),
+                        };
+                        self.writes.insert(key, write);
+                        Ok(())
+                    }
+                    Err(err) => Err(err),
+                },
+            }
+        }
+
+        /// Replaces the value of the existing key, returns the old one.
+        pub fn update(&mut self, key: K, val: V) -> Result<V, BtreeError> {
+            trace!("Transaction:update: key={:?}, val={:?}", key, val);
+            self.bt.check_entry_size(&key)?;
+            match self.writes.get_mut(&key) {
+                Some(write) => match write.val.as_mut() {
+                    Some(old) => Ok(std::mem::replace(old, val)),
+                    None => Err(BtreeError::KeyNotFound),
+                },
+                None => {
+                    let old = self.bt.find(&key)?;
+                    let write = TxWrite {
+                        existed: true,
+                        val: Some(val),
+                    };
+                    self.writes.insert(key, write);
+                    Ok(old)
+                }
+            }
+        }
+
+        pub fn remove(&mut self, key: &K) -> Result<V, BtreeError> {
+            trace!("Transaction:remove: key={:?}", key);
+            match self.writes.get_mut(key) {
+                Some(write) => write.val.take().ok_or(BtreeError::KeyNotFound),
+                None => {
+                    let old = self.bt.find(key)?;
+                    let write = TxWrite {
+                        existed: true,
+                        val: None,
+                    };
+                    self.writes.insert(key.clone(), write);
+                    Ok(old)
+                }
+            }
+        }
+
+        /// Applies all changes as one atomic operation. If the tree was changed
+        /// after the transaction had read a key, commit fails with `DuplicateKey`
+        /// or `KeyNotFound` and nothing is applied.
+        pub fn commit(self) -> Result<(), BtreeError> {
+            debug!("Transaction:commit: writes={}", self.writes.len());
+            let bt = self.bt;
+            let writes = self.writes;
+            bt.atomic(|| {
+                for (key, write) in writes.into_iter() {
+                    // existing key is checked by remove or update, the new one by insert
+                    match (write.existed, write.val) {
+                        (true, Some(val)) => {
+                            bt.replace_val(&key, val)?;
+                        }
+                        (true, None) => bt.remove_tasks(&key)?.0.run()?,
+                        (false, Some(val)) => bt.insert_tasks(key, Slot::Inline(val))?.run()?,
+                        (false, None) => (),
+                    }
+                }
+                Ok(())
+            })
+        }
+
+        pub fn rollback(self) {
+            debug!("Transaction:rollback: writes={}", self.writes.len());
+        }
+    }
+
+    /// Double-ended iterator over the key/value pairs of the tree, in key order.
+    /// Leaves are loaded lazily, one by one, following next/prev links.
+    /// A failed leaf load is yielded as an error and ends the iteration.
+    /// The tree must not be modified while the iterator is alive.
+    pub struct Iter<K: Key, V: Val> {
+        bt: Btree<K, V>,
+        front: Option<(Node<K, V>, usize)>, // leaf and index of the next element
+        back: Option<(Node<K, V>, usize)>,  // leaf and index after the last element
+        error: Option<BtreeError>,
+    }
+
+    type Position<K, V> = Option<(Node<K, V>, usize)>;
+    type RangePositions<K, V> = (Position<K, V>, Position<K, V>);
+
+    /// Iterator over the keys of the tree, see `Iter`.
+    pub struct Keys<K: Key, V: Val>(Iter<K, V>);
+
+    /// Iterator over the values of the tree, see `Iter`.
+    pub struct Values<K: Key, V: Val>(Iter<K, V>);
+
+    impl<K: Key, V: Val> Iter<K, V> {
+        fn fail(&mut self, err: BtreeError) {
+            self.front = None;
+            self.back = None;
+            self.error = Some(err);
+        }
+    }
+
+    impl<K: Key, V: Val> Iterator for Iter<K, V> {
+        type Item = Result<(K, V), BtreeError>;
+
+        fn next(&mut self) -> Option<Self::Item> {
+            if let Some(err) = self.error.take() {
+                return Some(Err(err));
+            }
+            let (node, idx) = self.front.take()?;
+            let item = match node.get_val(idx) {
+                Ok(val) => (node.get_key(idx), val),
+                Err(err) => {
+                    self.fail(err);
+                    return self.error.take().map(Err);
+                }
+            };
+            let last = match &self.back {
+                Some((back, end)) => back.addr() == node.addr() && *end == idx + 1,
+                None => true,
+            };
+            if last {
+                self.back = None;
+            } else {
+                match self.bt.seek_forward(node, idx + 1) {
+                    Ok(front) => self.front = front,
+                    Err(err) => self.fail(err),
+                }
+            }
+            Some(Ok(item))
+        }
+    }
+
+    impl<K: Key, V: Val> DoubleEndedIterator for Iter<K, V> {
+        fn next_back(&mut self) -> Option<Self::Item> {
+            if let Some(err) = self.error.take() {
+                return Some(Err(err));
+            }
+            let (node, end) = self.back.take()?;
+            let idx = end - 1;
+            let item = match node.get_val(idx) {
+                Ok(val) => (node.get_key(idx), val),
+                Err(err) => {
+                    self.fail(err);
+                    return self.error.take().map(Err);
+                }
+            };
+            let last = match &self.front {
+                Some((front, start)) => front.addr() == node.addr() && *start == idx,
+                None => true,
+            };
+            if last {
+                self.front = None;
+            } else {
+                match self.bt.seek_backward(node, idx) {
+                    Ok(back) => self.back = back,
+                    Err(err) => self.fail(err),
+                }
+            }
+            Some(Ok(item))
+        }
+    }
+
+    impl<K: Key, V: Val> Iterator for Keys<K, V> {
+        type Item = Result<K, BtreeError>;
+
+        fn next(&mut self) -> Option<Self::Item> {
+            self.0.next().map(|item| item.map(|(key, _)| key))
+        }
+    }
+
+    impl<K: Key, V: Val> DoubleEndedIterator for Keys<K, V> {
+        fn next_back(&mut self) -> Option<Self::Item> {
+            self.0.next_back().map(|item| item.map(|(key, _)| key))
+        }
+    }
+
+    impl<K: Key, V: Val> Iterator for Values<K, V> {
+        type Item = Result<V, BtreeError>;
+
+        fn next(&mut self) -> Option<Self::Item> {
+            self.0.next().map(|item| item.map(|(_, val)| val))
+        }
+    }
+
+    impl<K: Key, V: Val> DoubleEndedIterator for Values<K, V> {
+        fn next_back(&mut self) -> Option<Self::Item> {
+            self.0.next_back().map(|item| item.map(|(_, val)| val))
+        }
+    }
+
+    impl<K: Key, V: Val> Btree<K, V> {
+        /// Returns iterator over the pairs with keys in the range.
+        pub fn range<R: RangeBounds<K>>(&self, range: R) -> Iter<K, V> {
+            trace!("Btree:range: called");
+            self.iter_at(self.seek_range(range))
+        }
+
+        /// Returns iterator over the pairs with keys starting with the prefix, see `KeyPrefix`.
+        pub fn prefix<P: ?Sized>(&self, prefix: &P) -> Iter<K, V>
+        where
+            K: KeyPrefix<P>,
+        {
+            trace!("Btree:prefix: called");
+            self.iter_at(
+                self.seek_by(&|k| k.cmp_prefix(prefix) == Ordering::Less, &|k| {
+                    k.cmp_prefix(prefix) != Ordering::Greater
+                }),
+            )
+        }
+
+        fn iter_at(&self, positions: Result<RangePositions<K, V>, BtreeError>) -> Iter<K, V> {
+            let mut iter = Iter {
+                bt: self.clone(),
+                front: None,
+                back: None,
+                error: None,
+            };
+            match positions {
+                Ok((front, back)) => {
+                    iter.front = front;
+                    iter.back = back;
+                }
+                Err(err) => iter.fail(err),
+            }
+            iter
+        }
+
+        fn seek_range<R: RangeBounds<K>>(
+            &self,
+            range: R,
+        ) -> Result<RangePositions<K, V>, BtreeError> {
+            // first entry of the key for the included start, after the last one for the excluded
+            let front = match range.start_bound() {
+                Bound::Included(key) | Bound::Excluded(key) => {
+                    let upper = matches!(range.start_bound(), Bound::Excluded(_));
+                    let (leaf, _) = self.find_leaf_bound(key, upper)?;
+                    let idx = leaf.bound(key, upper);
+                    self.seek_forward(leaf, idx)?
+                }
+                Bound::Unbounded => self.seek_forward(self.edge_leaf(false)?, 0)?,
+            };
+            let back = match range.end_bound() {
+                Bound::Included(key) | Bound::Excluded(key) => {
+                    let upper = matches!(range.end_bound(), Bound::Included(_));
+                    let (leaf, _) = self.find_leaf_bound(key, upper)?;
+                    let end = leaf.bound(key, upper);
+                    self.seek_backward(leaf, end)?
+                }
+                Bound::Unbounded => {
+                    let leaf = self.edge_leaf(true)?;
+                    let end = leaf.degree() as usize;
+                    self.seek_backward(leaf, end)?
+                }
+            };
+            Ok(self.cut_empty(front, back))
+        }
+
+        fn seek_by(
+            &self,
+            start: &dyn Fn(&K) -> bool,
+            end: &dyn Fn(&K) -> bool,
+        ) -> Result<RangePositions<K, V>, BtreeError> {
+            // same as seek_range for the bounds given as Node::bound_by predicates
+            let (leaf, _) = self.find_leaf_by(start)?;
+            let idx = leaf.bound_by(start);
+            let front = self.seek_forward(leaf, idx)?;
+            let (leaf, _) = self.find_leaf_by(end)?;
+            let end = leaf.bound_by(end);
+            let back = self.seek_backward(leaf, end)?;
+            Ok(self.cut_empty(front, back))
+        }
+
+        fn cut_empty(&self, front: Position<K, V>, back: Position<K, V>) -> RangePositions<K, V> {
+            // bounds could be crossed, e.g. for the range between two neighbour keys
+            let empty = match (&front, &back) {
+                (Some((front, start)), Some((back, end))) => {
+                    front.get_key(*start) > back.get_key(end - 1)
+                }
+                _ => true,
+            };
+            if empty {
+                return (None, None);
+            }
+            (front, back)
+        }
+
+        pub fn iter(&self) -> Iter<K, V> {
+            self.range(..)
+        }
+
+        pub fn keys(&self) -> Keys<K, V> {
+            Keys(self.iter())
+        }
+
+        pub fn values(&self) -> Values<K, V> {
+            Values(self.iter())
+        }
+
+        fn edge_leaf(&self, rightmost: bool) -> Result<Node<K, V>, BtreeError> {
+            let mut node = self.get_node(self.root())?;
             while !node.is_leaf() {
-                let (next_node_addr, step) = node.find_next_node(key);
-                steps.push(step);
-                node = self.get_node(next_node_addr);
+                let idx = if rightmost {
+                    node.degree() as usize - 1
+                } else {
+                    0
+                };
+                node = self.get_node(node.get_ref(idx))?;
             }
-            steps.push(PathStep::new(None, None, (0, node.addr())));
-            let path = Path::new(steps, self);
-            (node, PathRef::tail(&path))
+            Ok(node)
+        }
+
+        fn seek_forward(
+            &self,
+            mut node: Node<K, V>,
+            mut idx: usize,
+        ) -> Result<Position<K, V>, BtreeError> {
+            // position at the element, skipping to the next leaves if needed
+            while idx >= node.degree() as usize {
+                node = match node.next() {
+                    Some(next) => self.get_node(next)?,
+                    None => return Ok(None),
+                };
+                idx = 0;
+            }
+            Ok(Some((node, idx)))
+        }
+
+        fn seek_backward(
+            &self,
+            mut node: Node<K, V>,
+            mut end: usize,
+        ) -> Result<Position<K, V>, BtreeError> {
+            // position after the element, skipping to the previous leaves if needed
+            while end == 0 {
+                node = match node.prev() {
+                    Some(prev) => self.get_node(prev)?,
+                    None => return Ok(None),
+                };
+                end = node.degree() as usize;
+            }
+            Ok(Some((node, end)))
         }
+    }
 
-        fn min_degree(&self) -> Degree {
-            self.0.borrow().header.min_degree
+    /// Position at an entry of the tree, moved by the seeks, `next` and `prev`.
+    /// The cursor keeps the leaf and the path to it, so `delete_current` and
+    /// `update_current` change the entry without a search. After the tree is
+    /// changed by other means, the cursor finds its entry again by the key,
+    /// the first or the last of the equal keys in multimap, as it moves.
+    pub struct Cursor<K: Key, V: Val> {
+        bt: Btree<K, V>,
+        pos: Option<CursorPos<K, V>>,
+        commits: u64, // operations committed, when the cursor was positioned
+    }
+
+    struct CursorPos<K: Key, V: Val> {
+        entry: LeafEntry<K, V>,
+        key: K,
+    }
+
+    impl<K: Key, V: Val> Btree<K, V> {
+        /// Returns the cursor, not positioned yet.
+        pub fn cursor(&self) -> Cursor<K, V> {
+            Cursor {
+                bt: self.clone(),
+                pos: None,
+                commits: self.commits(),
+            }
         }
 
-        fn set_min_degree(&self, degree: Degree) {
-            trace!("Btree:set_min_degree: degree={}", degree);
-            self.0.borrow_mut().header.min_degree = degree;
+        fn commits(&self) -> u64 {
+            self.0.borrow().counters.commits.get()
         }
 
-        fn max_degree(&self) -> Degree {
-            self.0.borrow().header.max_degree
+        fn reshapes(&self) -> u64 {
+            // node splits, merges and rebalances, which move the entries between leaves
+            let counters = &self.0.borrow().counters;
+            counters.splits.get() + counters.merges.get() + counters.rebalances.get()
         }
+    }
 
-        fn set_max_degree(&self, degree: Degree) {
-            trace!("Btree:set_max_degree: degree={}", degree);
-            self.0.borrow_mut().header.max_degree = degree;
+    impl<K: Key, V: Val> Cursor<K, V> {
+        /// Positions at the first entry with the key not less than the given one,
+        /// returns false if there is none.
+        pub fn seek(&mut self, key: &K) -> Result<bool, BtreeError> {
+            trace!("Cursor:seek: key={:?}", key);
+            let (leaf, pref) = self.bt.find_leaf_bound(key, false)?;
+            let idx = leaf.bound(key, false);
+            self.settle_forward((leaf, pref), idx)
         }
 
-        pub fn set_degree(&self, min_degree: Degree, max_degree: Degree) {
-            trace!(
-                "Btree:set_degree: min_degree={}, max_degree={}",
-                min_degree,
-                max_degree
-            );
-            self.set_min_degree(min_degree);
-            self.set_max_degree(max_degree);
-            self.flush();
+        /// Positions at the last entry with the key not greater than the given one,
+        /// returns false if there is none.
+        pub fn seek_for_prev(&mut self, key: &K) -> Result<bool, BtreeError> {
+            trace!("Cursor:seek_for_prev: key={:?}", key);
+            let (leaf, pref) = self.bt.find_leaf_bound(key, true)?;
+            let end = leaf.bound(key, true);
+            self.settle_backward((leaf, pref), end)
         }
 
-        pub fn find(&self, key: Key) -> Result<Val, ()> {
-            trace!("Btree:find: key={}", key);
-            let (leaf, _) = self.find_leaf(key);
-            match leaf.find(key) {
-                Ok(idx) => Ok(leaf.get_val(idx)),
-                Err(_) => Err(()),
+        pub fn seek_to_first(&mut self) -> Result<bool, BtreeError> {
+            let leaf_ref = self.bt.find_leaf_by(&|_| false)?;
+            self.settle_forward(leaf_ref, 0)
+        }
+
+        pub fn seek_to_last(&mut self) -> Result<bool, BtreeError> {
+            let leaf_ref = self.bt.find_leaf_by(&|_| true)?;
+            let end = leaf_ref.0.degree() as usize;
+            self.settle_backward(leaf_ref, end)
+        }
+
+        /// Moves to the next entry, returns false and leaves the cursor
+        /// not positioned after the last one.
+        #[allow(clippy::should_implement_trait)] // moves the cursor, it's not an iterator
+        pub fn next(&mut self) -> Result<bool, BtreeError> {
+            if self.pos.is_none() {
+                return Ok(false);
             }
+            // the next one is already there, if the entry is gone
+            if self.is_stale() && !self.restore(false)? {
+                return Ok(self.valid());
+            }
+            let (leaf_ref, idx) = self.pos.take().unwrap().entry;
+            self.settle_forward(leaf_ref, idx + 1)
         }
 
-        pub fn insert(&self, key: Key, val: Val) -> Result<(), ()> {
-            debug!("Btree:insert: key={}, val={}", key, val);
-            let (leaf, last_ref) = self.find_leaf(key);
-            let index = match leaf.find(key) {
-                Ok(_) => return Err(()),
-                Err(idx) => idx,
-            };
+        /// Moves to the previous entry, returns false and leaves the cursor
+        /// not positioned before the first one.
+        pub fn prev(&mut self) -> Result<bool, BtreeError> {
+            if self.pos.is_none() {
+                return Ok(false);
+            }
+            if self.is_stale() && !self.restore(true)? {
+                return Ok(self.valid());
+            }
+            let (leaf_ref, idx) = self.pos.take().unwrap().entry;
+            self.settle_backward(leaf_ref, idx)
+        }
 
-            let mut mgr = TaskManager::new();
-            mgr.add_insert(
-                InsertTarget::RefNodeAddr((last_ref, leaf.addr())),
-                index,
-                key,
-                val,
-            );
-            mgr.run();
-            Ok(())
+        pub fn valid(&self) -> bool {
+            self.pos.is_some()
         }
 
-        pub fn remove(&self, key: Key) -> Result<Val, ()> {
-            debug!("Btree:remove: key={}", key);
-            let (leaf, last_ref) = self.find_leaf(key);
-            let index = match leaf.find(key) {
-                Ok(idx) => idx,
-                Err(_) => return Err(()),
-            };
+        /// Key of the entry, the cursor is positioned at.
+        pub fn key(&self) -> Option<&K> {
+            self.pos.as_ref().map(|pos| &pos.key)
+        }
+
+        /// Value of the entry, the cursor is positioned at.
+        pub fn value(&self) -> Result<V, BtreeError> {
+            let pos = self.pos.as_ref().ok_or(BtreeError::KeyNotFound)?;
+            if self.is_stale() {
+                return self.bt.find(&pos.key);
+            }
+            let ((leaf, _), idx) = &pos.entry;
+            leaf.get_val(*idx)
+        }
+
+        /// Removes the entry, the cursor is positioned at, and moves to the next one.
+        /// Returns the removed value.
+        pub fn delete_current(&mut self) -> Result<V, BtreeError> {
+            debug!("Cursor:delete_current: key={:?}", self.key());
+            self.current()?;
+            let rank = self.rank()?;
+            let pos = self.pos.as_ref().unwrap();
+            let ((leaf, last_ref), idx) = &pos.entry;
+            let result = leaf.get_val(*idx)?;
             let mut mgr = TaskManager::new();
-            let result = leaf.get_val(index);
-            mgr.add_remove(last_ref, index);
-            mgr.run();
+            mgr.add_remove(last_ref.clone(), *idx);
+            let reshapes = self.bt.reshapes();
+            self.bt.atomic(|| mgr.run())?;
+            let CursorPos { entry, key } = self.pos.take().unwrap();
+            if self.bt.reshapes() == reshapes {
+                // entries after the removed one are shifted in the same leaf
+                let (leaf_ref, idx) = entry;
+                self.settle_forward(leaf_ref, idx)?;
+                return Ok(result);
+            }
+            // entries are moved, the next one is found by the key
+            // and the equal keys before the removed one
+            drop(entry);
+            self.seek(&key)?;
+            for _ in 0..rank {
+                self.next()?;
+            }
             Ok(result)
         }
 
-        pub fn compact(&self) -> Result<(), ()> {
-            debug!("Btree:compact: called");
-            // flush and disable cache
-            self.flush_cache();
-            let old_cache_cap = self.set_cache_cap(0);
-            // traverse tree, DFS
-            let mut node_refs = Vec::new();
-            let mut stack = Vec::new();
-            stack.push((PathStep::new(None, None, (0, self.root())), None));
-            while stack.len() > 0 {
-                let (step, pinfo) = stack.pop().unwrap();
-                node_refs.push((step, pinfo));
-                let node_addr = step.node_addr();
-                let node: Node = self.get_node(node_addr);
-                if node.is_leaf() {
-                    continue;
-                }
-                let vals = node.get_vals();
-                for (i, v) in vals.iter().enumerate() {
-                    let left = if i == 0 {
-                        None
-                    } else {
-                        Some((i - 1, vals[i - 1]))
-                    };
-                    let right = if i == vals.len() - 1 {
-                        None
-                    } else {
-                        Some((i + 1, vals[i + 1]))
-                    };
-                    let center = (i, *v);
-                    stack.push((
-                        PathStep::new(left, right, center),
-                        Some(StepInfo {
-                            index: i,
-                            addr: node.addr(),
-                        }),
-                    ));
-                }
-            }
-            // sort nodes by block-addresses
-            node_refs.sort_by_key(|x| x.0.node_addr());
-            let block_size = self.block_size();
-            let file_size = self.get_file_size() as Addr;
-            // make list of all available block-addresses in the file
-            let mut addrs = Vec::new();
-            let mut addr = block_size;
-            while addr < file_size {
-                addrs.push(addr);
-                addr += block_size;
+        /// Replaces the value of the entry, the cursor is positioned at,
+        /// returns the replaced value. In multimap the entry, which doesn't fit
+        /// to its leaf, goes after the equal keys, the cursor follows it.
+        pub fn update_current(&mut self, val: V) -> Result<V, BtreeError> {
+            debug!("Cursor:update_current: key={:?}, val={:?}", self.key(), val);
+            self.current()?;
+            let pos = self.pos.as_ref().unwrap();
+            let key = pos.key.clone();
+            self.bt.check_entry_size(&key)?;
+            let ((leaf, last_ref), idx) = &pos.entry;
+            let entry = ((leaf.clone(), last_ref.clone()), *idx);
+            let bt = self.bt.clone();
+            let (result, moved) = bt.atomic(|| bt.replace_entry(entry, &key, val))?;
+            if moved {
+                self.seek_for_prev(&key)?;
+            } else {
+                self.commits = self.bt.commits();
             }
-            debug_assert!(node_refs.len() <= addrs.len());
-            // check if blocks used or not
-            while node_refs.len() > 0 {
-                let (nref, pinfo) = node_refs.remove(0);
-                let addr = addrs.remove(0);
-                if nref.node_addr() == addr {
-                    // block used, skip
-                    continue;
+            Ok(result)
+        }
+
+        fn current(&mut self) -> Result<(), BtreeError> {
+            // positioned at the entry of its key, which is still in the tree
+            if self.pos.is_none() || self.is_stale() && !self.restore(false)? {
+                return Err(BtreeError::KeyNotFound);
+            }
+            Ok(())
+        }
+
+        fn rank(&self) -> Result<usize, BtreeError> {
+            // entries of the equal keys before the current one, multimap only
+            let pos = self.pos.as_ref().unwrap();
+            if !self.bt.is_multimap() {
+                return Ok(0);
+            }
+            let ((leaf, _), idx) = &pos.entry;
+            let (mut node, mut idx) = (leaf.clone(), *idx);
+            let mut rank = 0;
+            loop {
+                while idx > 0 && node.get_key(idx - 1) == pos.key {
+                    rank += 1;
+                    idx -= 1;
                 }
-                node_refs.insert(0, (nref, pinfo));
-                let (last_ref, parent_info) = node_refs.pop().unwrap();
-                // block is free. Move node from tail to this block and update refs
-                let last_node = self.get_node(last_ref.node_addr());
-                last_node.set_addr(addr);
-
-                let left_sibling_info = last_ref.left;
-                if left_sibling_info.is_some() {
-                    let left_sibling = self.get_node(left_sibling_info.unwrap().addr);
-                    left_sibling.set_next(Some(addr));
-                }
-                if parent_info.is_some() {
-                    let parent_info = parent_info.unwrap();
-                    let parent = self.get_node(parent_info.addr);
-                    parent.update_val(parent_info.index, addr);
-                } else {
-                    // update root
-                    self.set_root(addr)
+                if idx > 0 {
+                    return Ok(rank);
                 }
+                node = match node.prev() {
+                    Some(prev) => self.bt.get_node(prev)?,
+                    None => return Ok(rank),
+                };
+                idx = node.degree() as usize;
             }
-            // if there is some unused blocks left - trim them
-            if addrs.len() > 0 {
-                let trim_size = addrs.len() as u64 * block_size as u64;
-                let fd = &mut self.0.borrow_mut().fd;
-                let addr = fd.metadata().unwrap().len();
-                trace!("Btree:compact: len before trim={}", addr);
-                fd.set_len(addr - trim_size).unwrap();
-                let addr = fd.metadata().unwrap().len();
-                trace!("Btree:compact: len after trim={}", addr);
-            }
-            self.set_cache_cap(old_cache_cap);
-            Ok(())
         }
-    }
 
-    impl Btree {
-        pub fn dump_to_stdout(&self) {
-            write!(std::io::stdout(), "\n------------------------------\n").unwrap();
-            write!(std::io::stdout(), "{}", self.dump_to_string()).unwrap();
-            write!(std::io::stdout(), "------------------------------\n").unwrap();
+        fn is_stale(&self) -> bool {
+            self.commits != self.bt.commits()
         }
 
-        pub fn dump_to_string(&self) -> String {
-            let mut result = String::new();
-            let mut stack = Vec::new();
-            stack.push(self.root());
-            while stack.len() != 0 {
-                let node = self.get_node(stack.pop().unwrap());
-                write!(result, "{:?}\n", node).unwrap();
-                if !node.is_leaf() {
-                    stack.extend_from_slice(&node.0.borrow().st.vals)
+        fn restore(&mut self, backward: bool) -> Result<bool, BtreeError> {
+            // positions again by the key after the tree is changed,
+            // true if the key is still there
+            let key = self.pos.as_ref().unwrap().key.clone();
+            if backward {
+                self.seek_for_prev(&key)?;
+            } else {
+                self.seek(&key)?;
+            }
+            Ok(self.key() == Some(&key))
+        }
+
+        fn settle_forward(
+            &mut self,
+            (mut leaf, mut pref): LeafRef<K, V>,
+            mut idx: usize,
+        ) -> Result<bool, BtreeError> {
+            // position at the element, skipping to the next leaves if needed
+            self.pos = None;
+            self.commits = self.bt.commits();
+            while idx >= leaf.degree() as usize {
+                match self.bt.next_leaf(&pref)? {
+                    Some((next, next_pref)) => {
+                        leaf = next;
+                        pref = next_pref;
+                        idx = 0;
+                    }
+                    None => return Ok(false),
                 }
             }
-            result
+            let key = leaf.get_key(idx);
+            self.pos = Some(CursorPos {
+                entry: ((leaf, pref), idx),
+                key,
+            });
+            Ok(true)
+        }
+
+        fn settle_backward(
+            &mut self,
+            (mut leaf, mut pref): LeafRef<K, V>,
+            mut end: usize,
+        ) -> Result<bool, BtreeError> {
+            // position before the end, skipping to the previous leaves if needed
+            self.pos = None;
+            self.commits = self.bt.commits();
+            while end == 0 {
+                match self.bt.prev_leaf(&pref)? {
+                    Some((prev, prev_pref)) => {
+                        leaf = prev;
+                        pref = prev_pref;
+                        end = leaf.degree() as usize;
+                    }
+                    None => return Ok(false),
+                }
+            }
+            let key = leaf.get_key(end - 1);
+            self.pos = Some(CursorPos {
+                entry: ((leaf, pref), end - 1),
+                key,
+            });
+            Ok(true)
+        }
+    }
+
+    // Advisory lock of the index, `<path>.lock` next to it: exclusive for the writer,
+    // shared for the readers. The lock file is never removed, a removed one
+    // could be locked by two processes. Reader, who can't create the missing file,
+    // runs without the lock: there was no writer to create it.
+    fn lock_file(path: &FilePath, exclusive: bool) -> Result<Option<File>, BtreeError> {
+        let mut lock_path = path.as_os_str().to_owned();
+        lock_path.push(".lock");
+        let fd = match OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .open(&lock_path)
+        {
+            Ok(fd) => fd,
+            Err(_) if !exclusive => match File::open(&lock_path) {
+                Ok(fd) => fd,
+                Err(err) if err.kind() == io::ErrorKind::NotFound => {
+                    info!("lock_file: no lock file, path={:?}", lock_path);
+                    return Ok(None);
+                }
+                Err(err) => return Err(err.into()),
+            },
+            Err(err) => return Err(err.into()),
+        };
+        // std File has the inherent lock methods of its own in the newer toolchains
+        let locked = if exclusive {
+            FileExt::try_lock_exclusive(&fd)
+        } else {
+            FileExt::try_lock_shared(&fd)
+        };
+        match locked {
+            Ok(()) => Ok(Some(fd)),
+            Err(err) if err.raw_os_error() == fs2::lock_contended_error().raw_os_error() => {
+                Err(BtreeError::Locked)
+            }
+            Err(err) => Err(err.into()),
+        }
+    }
+
+    fn map_file(fd: &File, len: u64) -> io::Result<MmapMut> {
+        let mut map_len = MIN_MAP_SIZE;
+        while map_len < len {
+            map_len *= 2;
         }
+        unsafe { MmapOptions::new().len(map_len as usize).map_mut(fd) }
+    }
+
+    fn node_overhead<K: Key, V: Val>() -> u64 {
+        // encoded size of the empty node with the checksum
+        NodeStored::<K, Slot<V>>::new(true).encoded_size()
+    }
+
+    fn min_block_size<K: Key, V: Val>() -> u64 {
+        // the smallest entry with the spilled value fits to the quarter of the node,
+        // see max_entry_size. The header is smaller than that
+        let entry = min_encoded_size::<K>()
+            + Slot::<V>::overflow_size().max(size_of::<Addr>() as u64)
+            + PAGE_SLOT_SIZE;
+        node_overhead::<K, V>() + 4 * entry
+    }
+
+    fn max_entry_size(block_size: Block, overhead: u64) -> u64 {
+        // a node splits in two by the encoded size, one entry more must fit to the half.
+        (block_size as u64 - overhead) / 4
+    }
+
+    fn min_encoded_size<T: Serialize + DeserializeOwned>() -> u64 {
+        // size of the value decoded from zero bytes: zero numbers, empty strings
+        // and vectors, None, the first variant of enums. It's the smallest one
+        // for the most types, the types which can't be decoded so take 0.
+        let zeros = io::repeat(0).take(u16::MAX as u64);
+        bincode::deserialize_from::<_, T>(zeros)
+            .ok()
+            .and_then(|value| bincode::serialized_size(&value).ok())
+            .unwrap_or(0)
     }
 
-    fn get_max_degree(block_size: Block) -> Degree {
-        let degree = ((block_size as usize - size_of::<NodeStored>())
-            / (size_of::<Key>() + size_of::<Val>())) as Degree;
+    fn get_max_degree<K: Key, V: Val>(block_size: Block) -> Degree {
+        // by the smallest encoded entry, for variable length types it's an upper bound,
+        // node is also considered full when the next entry doesn't fit to the block.
+        let key_size = min_encoded_size::<K>();
+        let val_size = min_encoded_size::<Slot<V>>();
+        let entry_size = key_size + val_size.max(size_of::<Addr>() as u64) + PAGE_SLOT_SIZE;
+        let degree = ((block_size as u64 - node_overhead::<K, V>()) / entry_size) as Degree;
         debug!(
             "get_degree: called with block_size={}, degree={}",
             block_size, degree
@@ -1528,6 +6121,7 @@ pub mod btree {
 }
 
 #[cfg(test)]
+#[allow(clippy::useless_vec, clippy::needless_borrow)] // original cases are kept as written
 mod tests {
 
     extern crate env_logger;
@@ -1535,6 +6129,9 @@ mod tests {
     extern crate rand;
     use crate::btree;
     use log::debug;
+    use serde::{Deserialize, Serialize};
+    use std::io::Write;
+    use std::ops::Bound::Excluded;
 
     fn log_init() {
         let _ = env_logger::builder().is_test(true).try_init();
@@ -1548,10 +6145,10 @@ mod tests {
     #[test]
     fn base_test() {
         log_init();
-        let path = std::path::Path::new("test.idx");
+        let path = std::path::Path::new("test_base_test.idx");
         setup(&path);
-        let bt = btree::Btree::new(std::path::Path::new("test.idx"), 512, 2, 64, 8);
-        bt.set_degree(1, 3);
+        let bt = btree::Btree::new(std::path::Path::new("test_base_test.idx"), 512, 2, 8).unwrap();
+        bt.set_degree(1, 3).unwrap();
         for i in 1..=10 {
             let _ = bt.insert(i, i * 10 + i);
         }
@@ -1565,7 +6162,38 @@ mod tests {
             "Node A=1024, R=(-), L=(+), keys=[3, 4], vals=[33, 44], N:Some(2048)",
             "Node A=512, R=(-), L=(+), keys=[1, 2], vals=[11, 22], N:Some(1024)",
         ];
-        let result_string = bt.dump_to_string();
+        let result_string = bt.dump_to_string().unwrap();
+        let result: Vec<&str> = result_string.lines().collect();
+        assert!(result.len() == expected.len());
+        for (r, e) in result.iter().zip(expected.iter()) {
+            assert_eq!(r, e);
+        }
+    }
+
+    #[test]
+    fn base_compact() {
+        log_init();
+        let path = std::path::Path::new("test_base_compact.idx");
+        setup(&path);
+        let bt =
+            btree::Btree::new(std::path::Path::new("test_base_compact.idx"), 512, 2, 8).unwrap();
+        bt.set_degree(1, 3).unwrap();
+        for i in 1..=10 {
+            let _ = bt.insert(i, i * 10 + i);
+        }
+        let _ = bt.remove(&5);
+        let _ = bt.remove(&6);
+        let _ = bt.compact();
+        let expected = vec![
+            "Node A=3584, R=(+), L=(-), keys=[3, 7], vals=[1536, 3072], N:None",
+            "Node A=3072, R=(-), L=(-), keys=[7, 9], vals=[2048, 2560], N:None",
+            "Node A=2560, R=(-), L=(+), keys=[9, 10], vals=[99, 110], N:None",
+            "Node A=2048, R=(-), L=(+), keys=[7, 8], vals=[77, 88], N:Some(2560)",
+            "Node A=1536, R=(-), L=(-), keys=[1, 3], vals=[512, 1024], N:None",
+            "Node A=1024, R=(-), L=(+), keys=[3, 4], vals=[33, 44], N:Some(2048)",
+            "Node A=512, R=(-), L=(+), keys=[1, 2], vals=[11, 22], N:Some(1024)",
+        ];
+        let result_string = bt.dump_to_string().unwrap();
         let result: Vec<&str> = result_string.lines().collect();
         assert!(result.len() == expected.len());
         for (r, e) in result.iter().zip(expected.iter()) {
@@ -1574,49 +6202,164 @@ mod tests {
     }
 
     #[test]
-    fn base_compact() {
+    fn base_huge() {
+        log_init();
+        let path = std::path::Path::new("test_base_huge.idx");
+        {
+            setup(&path);
+            let bt = btree::Btree::new(std::path::Path::new("test_base_huge.idx"), 4096, 2, 100)
+                .unwrap();
+            for i in 1..=100_000 {
+                let _ = bt.insert(i, i * 10 + i);
+            }
+            bt.flush_cache().unwrap();
+        }
+        let bt: btree::Btree<u32, u32> = btree::Btree::load(path, 100).unwrap();
+        assert!(bt.find(&100_000).unwrap() == 1_100_000);
+    }
+
+    #[test]
+    fn generic_u64_keys() {
+        log_init();
+        let path = std::path::Path::new("test_u64.idx");
+        {
+            setup(path);
+            let bt = btree::Btree::<u64, i64>::new(path, 512, 2, 8).unwrap();
+            for i in (0..2_000u64).rev() {
+                assert!(bt.insert(i << 40, -(i as i64)).is_ok());
+            }
+            assert!(matches!(
+                bt.insert(7 << 40, 0),
+                Err(btree::BtreeError::DuplicateKey)
+            ));
+            bt.flush_cache().unwrap();
+        }
+        let bt: btree::Btree<u64, i64> = btree::Btree::load(path, 8).unwrap();
+        for i in 0..2_000u64 {
+            assert_eq!(bt.find(&(i << 40)).unwrap(), -(i as i64));
+        }
+        assert!(matches!(bt.find(&1), Err(btree::BtreeError::KeyNotFound)));
+    }
+
+    #[test]
+    fn generic_string_keys() {
+        // variable length keys. Node is split by the encoded size.
+        log_init();
+        let path = std::path::Path::new("test_string.idx");
+        setup(path);
+        let bt = btree::Btree::<String, String>::new(path, 512, 2, 8).unwrap();
+        let key = |i: usize| format!("{}-{}", "k".repeat(i % 50), i);
+        for i in 0..1_000 {
+            assert!(bt.insert(key(i), i.to_string()).is_ok());
+        }
+        // entry doesn't fit to the node
+        assert!(matches!(
+            bt.insert("k".repeat(512), String::new()),
+            Err(btree::BtreeError::EntryTooLarge)
+        ));
+        for i in (0..1_000).step_by(2) {
+            assert_eq!(bt.remove(&key(i)).unwrap(), i.to_string());
+        }
+        for i in 0..1_000 {
+            let result = bt.find(&key(i));
+            if i % 2 == 0 {
+                assert!(result.is_err());
+            } else {
+                assert_eq!(result.unwrap(), i.to_string());
+            }
+        }
+    }
+
+    #[test]
+    fn generic_keys_without_default() {
+        // degree is counted by the encoded size of the zero key
+        #[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+        struct Id(u64);
         log_init();
-        let path = std::path::Path::new("test.idx");
-        setup(&path);
-        let bt = btree::Btree::new(std::path::Path::new("test.idx"), 512, 2, 64, 8);
-        bt.set_degree(1, 3);
-        for i in 1..=10 {
-            let _ = bt.insert(i, i * 10 + i);
+        let path = std::path::Path::new("test_no_default.idx");
+        let plain = std::path::Path::new("test_no_default_u64.idx");
+        setup(path);
+        setup(plain);
+        let bt = btree::Btree::<Id, Id>::new(path, 512, 2, 8).unwrap();
+        let degree = |debug: String| debug.split(',').next().unwrap().to_string();
+        assert_eq!(
+            degree(format!("{:?}", bt)),
+            degree(format!(
+                "{:?}",
+                btree::Btree::<u64, u64>::new(plain, 512, 2, 8).unwrap()
+            ))
+        );
+        for i in (0..1_000).rev() {
+            bt.insert(Id(i), Id(i * 2)).unwrap();
         }
-        let _ = bt.remove(5);
-        let _ = bt.remove(6);
-        let _ = bt.compact();
-        let expected = vec![
-            "Node A=3584, R=(+), L=(-), keys=[3, 7], vals=[1536, 3072], N:None",
-            "Node A=3072, R=(-), L=(-), keys=[7, 9], vals=[2048, 2560], N:None",
-            "Node A=2560, R=(-), L=(+), keys=[9, 10], vals=[99, 110], N:None",
-            "Node A=2048, R=(-), L=(+), keys=[7, 8], vals=[77, 88], N:Some(2560)",
-            "Node A=1536, R=(-), L=(-), keys=[1, 3], vals=[512, 1024], N:None",
-            "Node A=1024, R=(-), L=(+), keys=[3, 4], vals=[33, 44], N:Some(2048)",
-            "Node A=512, R=(-), L=(+), keys=[1, 2], vals=[11, 22], N:Some(1024)",
-        ];
-        let result_string = bt.dump_to_string();
-        let result: Vec<&str> = result_string.lines().collect();
-        assert!(result.len() == expected.len());
-        for (r, e) in result.iter().zip(expected.iter()) {
-            assert_eq!(r, e);
+        assert_eq!(bt.find(&Id(500)).unwrap(), Id(1_000));
+        assert!(bt.verify().unwrap().is_ok());
+    }
+
+    #[test]
+    fn range_scan() {
+        log_init();
+        let path = std::path::Path::new("test_range.idx");
+        setup(path);
+        let bt = btree::Btree::<u32, u32>::new(path, 512, 2, 8).unwrap();
+        bt.set_degree(1, 3).unwrap();
+        for i in (1..=20).map(|i| i * 2) {
+            let _ = bt.insert(i, i * 10 + i);
         }
+        let keys: Vec<u32> = bt.range(10..20).map(|item| item.unwrap().0).collect();
+        assert_eq!(keys, [10, 12, 14, 16, 18]);
+        let keys: Vec<u32> = bt.range(9..=20).map(|item| item.unwrap().0).collect();
+        assert_eq!(keys, [10, 12, 14, 16, 18, 20]);
+        let keys: Vec<u32> = bt
+            .range((Excluded(10), Excluded(14)))
+            .map(|item| item.unwrap().0)
+            .collect();
+        assert_eq!(keys, [12]);
+        let keys: Vec<u32> = bt.range(35..).map(|item| item.unwrap().0).collect();
+        assert_eq!(keys, [36, 38, 40]);
+        let vals: Vec<u32> = bt.range(..5).map(|item| item.unwrap().1).collect();
+        assert_eq!(vals, [22, 44]);
+        assert_eq!(bt.range(11..12).count(), 0);
+        assert_eq!(bt.range(41..).count(), 0);
+        assert_eq!(bt.range((Excluded(30), Excluded(10))).count(), 0);
+        assert_eq!(
+            bt.keys().collect::<Result<Vec<u32>, _>>().unwrap(),
+            (1..=20).map(|i| i * 2).collect::<Vec<u32>>()
+        );
+        assert_eq!(bt.values().next().unwrap().unwrap(), 22);
     }
 
     #[test]
-    fn base_huge() {
+    fn range_scan_rev() {
+        // walk the leaves backward, also after merges and compact
         log_init();
-        let path = std::path::Path::new("test.idx");
-        {
-            setup(&path);
-            let bt = btree::Btree::new(std::path::Path::new("test.idx"), 4096, 2, 500_000, 100);
-            for i in 1..=100_000 {
-                let _ = bt.insert(i, i * 10 + i);
-            }
-            bt.flush_cache();
+        let path = std::path::Path::new("test_range_rev.idx");
+        setup(path);
+        let bt = btree::Btree::<u32, u32>::new(path, 512, 2, 0).unwrap();
+        bt.set_degree(1, 3).unwrap();
+        for i in 1..=30 {
+            let _ = bt.insert(i, i * 10 + i);
+        }
+        for i in 5..=25 {
+            let _ = bt.remove(&i);
         }
-        let bt = btree::Btree::load(path, 4096, 50_000, 100);
-        assert!(bt.find(100_000).unwrap() == 1_100_000);
+        let expected: Vec<u32> = (1..=4).chain(26..=30).rev().collect();
+        assert_eq!(
+            bt.keys().rev().collect::<Result<Vec<u32>, _>>().unwrap(),
+            expected
+        );
+        let _ = bt.compact();
+        assert_eq!(
+            bt.keys().rev().collect::<Result<Vec<u32>, _>>().unwrap(),
+            expected
+        );
+        let keys: Vec<u32> = bt.range(3..=27).rev().map(|item| item.unwrap().0).collect();
+        assert_eq!(keys, [27, 26, 4, 3]);
+        // meet in the middle
+        let mut iter = bt.iter();
+        assert_eq!(iter.next().unwrap().unwrap(), (1, 11));
+        assert_eq!(iter.next_back().unwrap().unwrap(), (30, 330));
+        assert_eq!(iter.count(), 7);
     }
 
     #[test]
@@ -1625,14 +6368,15 @@ mod tests {
         // insertion in the middle of the leaf node.
         // no split needed. Just plain insert.
         log_init();
-        let path = std::path::Path::new("test.idx");
+        let path = std::path::Path::new("test_insert_case_01.idx");
         setup(&path);
-        let bt = btree::Btree::new(std::path::Path::new("test.idx"), 512, 2, 64, 8);
+        let bt =
+            btree::Btree::new(std::path::Path::new("test_insert_case_01.idx"), 512, 2, 8).unwrap();
         for i in 5..=7 {
             let _ = bt.insert(i, i * 10 + i);
         }
         let expected = vec!["Node A=512, R=(+), L=(+), keys=[5, 6, 7], vals=[55, 66, 77], N:None"];
-        let result_string = bt.dump_to_string();
+        let result_string = bt.dump_to_string().unwrap();
         let result: Vec<&str> = result_string.lines().collect();
         assert!(result.len() == expected.len());
         for (r, e) in result.iter().zip(expected.iter()) {
@@ -1645,15 +6389,16 @@ mod tests {
         // Simple insert in the beginning of leaf.
         // Only left leaf node of the entire tree is affected.
         log_init();
-        let path = std::path::Path::new("test.idx");
+        let path = std::path::Path::new("test_insert_case_02.idx");
         setup(&path);
-        let bt = btree::Btree::new(std::path::Path::new("test.idx"), 512, 2, 64, 8);
-        bt.set_degree(1, 3);
+        let bt =
+            btree::Btree::new(std::path::Path::new("test_insert_case_02.idx"), 512, 2, 8).unwrap();
+        bt.set_degree(1, 3).unwrap();
         let _ = bt.insert(5, 55);
         let _ = bt.insert(6, 66);
         let _ = bt.insert(4, 44);
         let expected = vec!["Node A=512, R=(+), L=(+), keys=[4, 5, 6], vals=[44, 55, 66], N:None"];
-        let result_string = bt.dump_to_string();
+        let result_string = bt.dump_to_string().unwrap();
         let result: Vec<&str> = result_string.lines().collect();
         assert!(result.len() == expected.len());
         for (r, e) in result.iter().zip(expected.iter()) {
@@ -1665,15 +6410,16 @@ mod tests {
     fn insert_case_03() {
         // Simple insert at the end of the leaf node.
         log_init();
-        let path = std::path::Path::new("test.idx");
+        let path = std::path::Path::new("test_insert_case_03.idx");
         setup(&path);
-        let bt = btree::Btree::new(std::path::Path::new("test.idx"), 512, 2, 64, 8);
-        bt.set_degree(1, 3);
+        let bt =
+            btree::Btree::new(std::path::Path::new("test_insert_case_03.idx"), 512, 2, 8).unwrap();
+        bt.set_degree(1, 3).unwrap();
         for i in 4..=6 {
             let _ = bt.insert(i, i * 10 + i);
         }
         let expected = vec!["Node A=512, R=(+), L=(+), keys=[4, 5, 6], vals=[44, 55, 66], N:None"];
-        let result_string = bt.dump_to_string();
+        let result_string = bt.dump_to_string().unwrap();
         let result: Vec<&str> = result_string.lines().collect();
         assert!(result.len() == expected.len());
         for (r, e) in result.iter().zip(expected.iter()) {
@@ -1687,10 +6433,16 @@ mod tests {
         // Root is leaf. Insert. Split. Add new root and populate with new min keys.
         // Insert at the begin of the first half.
         log_init();
-        let path = std::path::Path::new("test.idx");
+        let path = std::path::Path::new("test_insert_split_case_01.idx");
         setup(&path);
-        let bt = btree::Btree::new(std::path::Path::new("test.idx"), 512, 2, 64, 8);
-        bt.set_degree(1, 3);
+        let bt = btree::Btree::new(
+            std::path::Path::new("test_insert_split_case_01.idx"),
+            512,
+            2,
+            8,
+        )
+        .unwrap();
+        bt.set_degree(1, 3).unwrap();
         for i in 5..=7 {
             let _ = bt.insert(i, i * 10 + i);
         }
@@ -1700,7 +6452,7 @@ mod tests {
             "Node A=1024, R=(-), L=(+), keys=[6, 7], vals=[66, 77], N:None",
             "Node A=512, R=(-), L=(+), keys=[4, 5], vals=[44, 55], N:Some(1024)",
         ];
-        let result_string = bt.dump_to_string();
+        let result_string = bt.dump_to_string().unwrap();
         let result: Vec<&str> = result_string.lines().collect();
         assert!(result.len() == expected.len());
         for (r, e) in result.iter().zip(expected.iter()) {
@@ -1713,10 +6465,16 @@ mod tests {
         // Insert with split.
         // Insert at the end of the first half.
         log_init();
-        let path = std::path::Path::new("test.idx");
+        let path = std::path::Path::new("test_insert_split_case_02.idx");
         setup(&path);
-        let bt = btree::Btree::new(std::path::Path::new("test.idx"), 512, 2, 64, 8);
-        bt.set_degree(1, 3);
+        let bt = btree::Btree::new(
+            std::path::Path::new("test_insert_split_case_02.idx"),
+            512,
+            2,
+            8,
+        )
+        .unwrap();
+        bt.set_degree(1, 3).unwrap();
         let _ = bt.insert(5, 55);
         let _ = bt.insert(8, 88);
         let _ = bt.insert(9, 99);
@@ -1727,7 +6485,7 @@ mod tests {
             "Node A=512, R=(-), L=(+), keys=[5, 7], vals=[55, 77], N:Some(1024)",
         ];
         // bt.dump_to_stdout();
-        let result_string = bt.dump_to_string();
+        let result_string = bt.dump_to_string().unwrap();
         let result: Vec<&str> = result_string.lines().collect();
         assert!(result.len() == expected.len());
         for (r, e) in result.iter().zip(expected.iter()) {
@@ -1740,10 +6498,16 @@ mod tests {
         // Insert with split.
         // Insert at the begin of the second half.
         log_init();
-        let path = std::path::Path::new("test.idx");
+        let path = std::path::Path::new("test_insert_split_case_03.idx");
         setup(&path);
-        let bt = btree::Btree::new(std::path::Path::new("test.idx"), 512, 2, 64, 8);
-        bt.set_degree(1, 3);
+        let bt = btree::Btree::new(
+            std::path::Path::new("test_insert_split_case_03.idx"),
+            512,
+            2,
+            8,
+        )
+        .unwrap();
+        bt.set_degree(1, 3).unwrap();
         let _ = bt.insert(1, 11);
         let _ = bt.insert(2, 22);
         let _ = bt.insert(4, 44);
@@ -1753,7 +6517,7 @@ mod tests {
             "Node A=1024, R=(-), L=(+), keys=[3, 4], vals=[33, 44], N:None",
             "Node A=512, R=(-), L=(+), keys=[1, 2], vals=[11, 22], N:Some(1024)",
         ];
-        let result_string = bt.dump_to_string();
+        let result_string = bt.dump_to_string().unwrap();
         let result: Vec<&str> = result_string.lines().collect();
         assert!(result.len() == expected.len());
         for (r, e) in result.iter().zip(expected.iter()) {
@@ -1766,10 +6530,16 @@ mod tests {
         // Insert with split.
         // Insert at the end of the second half.
         log_init();
-        let path = std::path::Path::new("test.idx");
+        let path = std::path::Path::new("test_insert_split_case_04.idx");
         setup(&path);
-        let bt = btree::Btree::new(std::path::Path::new("test.idx"), 512, 2, 64, 8);
-        bt.set_degree(1, 3);
+        let bt = btree::Btree::new(
+            std::path::Path::new("test_insert_split_case_04.idx"),
+            512,
+            2,
+            8,
+        )
+        .unwrap();
+        bt.set_degree(1, 3).unwrap();
         for i in 1..=4 {
             let _ = bt.insert(i, i * 10 + i);
         }
@@ -1778,7 +6548,7 @@ mod tests {
             "Node A=1024, R=(-), L=(+), keys=[3, 4], vals=[33, 44], N:None",
             "Node A=512, R=(-), L=(+), keys=[1, 2], vals=[11, 22], N:Some(1024)",
         ];
-        let result_string = bt.dump_to_string();
+        let result_string = bt.dump_to_string().unwrap();
         let result: Vec<&str> = result_string.lines().collect();
         assert!(result.len() == expected.len());
         for (r, e) in result.iter().zip(expected.iter()) {
@@ -1791,10 +6561,16 @@ mod tests {
         // Insert with split, which generates insert in the parent.
         // Parent overflowed and split. New grandparent contructed.
         log_init();
-        let path = std::path::Path::new("test.idx");
+        let path = std::path::Path::new("test_insert_split_case_05.idx");
         setup(&path);
-        let bt = btree::Btree::new(std::path::Path::new("test.idx"), 512, 2, 64, 8);
-        bt.set_degree(1, 3);
+        let bt = btree::Btree::new(
+            std::path::Path::new("test_insert_split_case_05.idx"),
+            512,
+            2,
+            8,
+        )
+        .unwrap();
+        bt.set_degree(1, 3).unwrap();
         for i in 1..=8 {
             let _ = bt.insert(i, i * 10 + i);
         }
@@ -1807,7 +6583,7 @@ mod tests {
             "Node A=1024, R=(-), L=(+), keys=[3, 4], vals=[33, 44], N:Some(2048)",
             "Node A=512, R=(-), L=(+), keys=[1, 2], vals=[11, 22], N:Some(1024)",
         ];
-        let result_string = bt.dump_to_string();
+        let result_string = bt.dump_to_string().unwrap();
         let result: Vec<&str> = result_string.lines().collect();
         assert!(result.len() == expected.len());
         for (r, e) in result.iter().zip(expected.iter()) {
@@ -1818,14 +6594,15 @@ mod tests {
     #[test]
     fn remove_case_01() {
         log_init();
-        let path = std::path::Path::new("test.idx");
+        let path = std::path::Path::new("test_remove_case_01.idx");
         setup(&path);
-        let bt = btree::Btree::new(std::path::Path::new("test.idx"), 512, 2, 64, 8);
-        bt.set_degree(1, 3);
+        let bt =
+            btree::Btree::new(std::path::Path::new("test_remove_case_01.idx"), 512, 2, 8).unwrap();
+        bt.set_degree(1, 3).unwrap();
         let _ = bt.insert(1, 11);
-        let _ = bt.remove(1);
+        let _ = bt.remove(&1);
         let expected = vec!["Node A=512, R=(+), L=(+), keys=[], vals=[], N:None"];
-        let result_string = bt.dump_to_string();
+        let result_string = bt.dump_to_string().unwrap();
         let result: Vec<&str> = result_string.lines().collect();
         assert!(result.len() == expected.len());
         for (r, e) in result.iter().zip(expected.iter()) {
@@ -1837,20 +6614,21 @@ mod tests {
     fn remove_case_02() {
         // remove minkey from leaf. Update parent node.
         log_init();
-        let path = std::path::Path::new("test.idx");
+        let path = std::path::Path::new("test_remove_case_02.idx");
         setup(&path);
-        let bt = btree::Btree::new(std::path::Path::new("test.idx"), 512, 2, 64, 8);
-        bt.set_degree(1, 3);
+        let bt =
+            btree::Btree::new(std::path::Path::new("test_remove_case_02.idx"), 512, 2, 8).unwrap();
+        bt.set_degree(1, 3).unwrap();
         for i in 1..=4 {
             let _ = bt.insert(i, i * 10 + i);
         }
-        let _ = bt.remove(3);
+        let _ = bt.remove(&3);
         let expected = vec![
             "Node A=1536, R=(+), L=(-), keys=[1, 4], vals=[512, 1024], N:None",
             "Node A=1024, R=(-), L=(+), keys=[4], vals=[44], N:None",
             "Node A=512, R=(-), L=(+), keys=[1, 2], vals=[11, 22], N:Some(1024)",
         ];
-        let result_string = bt.dump_to_string();
+        let result_string = bt.dump_to_string().unwrap();
         let result: Vec<&str> = result_string.lines().collect();
         assert!(result.len() == expected.len());
         for (r, e) in result.iter().zip(expected.iter()) {
@@ -1862,20 +6640,21 @@ mod tests {
     fn remove_case_03() {
         // remove minkey from leaf. Update parent node.
         log_init();
-        let path = std::path::Path::new("test.idx");
+        let path = std::path::Path::new("test_remove_case_03.idx");
         setup(&path);
-        let bt = btree::Btree::new(std::path::Path::new("test.idx"), 512, 2, 64, 8);
-        bt.set_degree(1, 3);
+        let bt =
+            btree::Btree::new(std::path::Path::new("test_remove_case_03.idx"), 512, 2, 8).unwrap();
+        bt.set_degree(1, 3).unwrap();
         for i in 1..=4 {
             let _ = bt.insert(i, i * 10 + i);
         }
-        let _ = bt.remove(1);
+        let _ = bt.remove(&1);
         let expected = vec![
             "Node A=1536, R=(+), L=(-), keys=[2, 3], vals=[512, 1024], N:None",
             "Node A=1024, R=(-), L=(+), keys=[3, 4], vals=[33, 44], N:None",
             "Node A=512, R=(-), L=(+), keys=[2], vals=[22], N:Some(1024)",
         ];
-        let result_string = bt.dump_to_string();
+        let result_string = bt.dump_to_string().unwrap();
         let result: Vec<&str> = result_string.lines().collect();
         assert!(result.len() == expected.len());
         for (r, e) in result.iter().zip(expected.iter()) {
@@ -1887,17 +6666,18 @@ mod tests {
     fn remove_case_04() {
         // remove all keys from leaf. Decrease height of tree/reset root.
         log_init();
-        let path = std::path::Path::new("test.idx");
+        let path = std::path::Path::new("test_remove_case_04.idx");
         setup(&path);
-        let bt = btree::Btree::new(std::path::Path::new("test.idx"), 512, 2, 64, 8);
-        bt.set_degree(1, 3);
+        let bt =
+            btree::Btree::new(std::path::Path::new("test_remove_case_04.idx"), 512, 2, 8).unwrap();
+        bt.set_degree(1, 3).unwrap();
         for i in 1..=4 {
             let _ = bt.insert(i, i * 10 + i);
         }
-        let _ = bt.remove(3);
-        let _ = bt.remove(4);
+        let _ = bt.remove(&3);
+        let _ = bt.remove(&4);
         let expected = vec!["Node A=512, R=(+), L=(+), keys=[1, 2], vals=[11, 22], N:None"];
-        let result_string = bt.dump_to_string();
+        let result_string = bt.dump_to_string().unwrap();
         let result: Vec<&str> = result_string.lines().collect();
         assert!(result.len() == expected.len());
         for (r, e) in result.iter().zip(expected.iter()) {
@@ -1909,17 +6689,18 @@ mod tests {
     fn remove_case_05() {
         // remove all keys from leaf. Decrease height of tree/reset root.
         log_init();
-        let path = std::path::Path::new("test.idx");
+        let path = std::path::Path::new("test_remove_case_05.idx");
         setup(&path);
-        let bt = btree::Btree::new(std::path::Path::new("test.idx"), 512, 2, 64, 8);
-        bt.set_degree(1, 3);
+        let bt =
+            btree::Btree::new(std::path::Path::new("test_remove_case_05.idx"), 512, 2, 8).unwrap();
+        bt.set_degree(1, 3).unwrap();
         for i in 1..=4 {
             let _ = bt.insert(i, i * 10 + i);
         }
-        let _ = bt.remove(1);
-        let _ = bt.remove(2);
+        let _ = bt.remove(&1);
+        let _ = bt.remove(&2);
         let expected = vec!["Node A=512, R=(+), L=(+), keys=[3, 4], vals=[33, 44], N:None"];
-        let result_string = bt.dump_to_string();
+        let result_string = bt.dump_to_string().unwrap();
         let result: Vec<&str> = result_string.lines().collect();
         assert!(result.len() == expected.len());
         for (r, e) in result.iter().zip(expected.iter()) {
@@ -1931,21 +6712,22 @@ mod tests {
     fn remove_case_06() {
         // remove keys from leaf. Rebalance from right sibling.
         log_init();
-        let path = std::path::Path::new("test.idx");
+        let path = std::path::Path::new("test_remove_case_06.idx");
         setup(&path);
-        let bt = btree::Btree::new(std::path::Path::new("test.idx"), 512, 2, 64, 8);
-        bt.set_degree(2, 4);
+        let bt =
+            btree::Btree::new(std::path::Path::new("test_remove_case_06.idx"), 512, 2, 8).unwrap();
+        bt.set_degree(2, 4).unwrap();
         for i in 1..=8 {
             let _ = bt.insert(i, i * 10 + i);
         }
-        let _ = bt.remove(4);
+        let _ = bt.remove(&4);
         let expected = vec![
             "Node A=1536, R=(+), L=(-), keys=[1, 3, 6], vals=[512, 1024, 2048], N:None",
             "Node A=2048, R=(-), L=(+), keys=[6, 7, 8], vals=[66, 77, 88], N:None",
             "Node A=1024, R=(-), L=(+), keys=[3, 5], vals=[33, 55], N:Some(2048)",
             "Node A=512, R=(-), L=(+), keys=[1, 2], vals=[11, 22], N:Some(1024)",
         ];
-        let result_string = bt.dump_to_string();
+        let result_string = bt.dump_to_string().unwrap();
         let result: Vec<&str> = result_string.lines().collect();
         assert!(result.len() == expected.len());
         for (r, e) in result.iter().zip(expected.iter()) {
@@ -1957,21 +6739,22 @@ mod tests {
     fn remove_case_07() {
         // remove min_key from leaf. Rebalance from right sibling.
         log_init();
-        let path = std::path::Path::new("test.idx");
+        let path = std::path::Path::new("test_remove_case_07.idx");
         setup(&path);
-        let bt = btree::Btree::new(std::path::Path::new("test.idx"), 512, 2, 64, 8);
-        bt.set_degree(2, 4);
+        let bt =
+            btree::Btree::new(std::path::Path::new("test_remove_case_07.idx"), 512, 2, 8).unwrap();
+        bt.set_degree(2, 4).unwrap();
         for i in 1..=8 {
             let _ = bt.insert(i, i * 10 + i);
         }
-        let _ = bt.remove(3);
+        let _ = bt.remove(&3);
         let expected = vec![
             "Node A=1536, R=(+), L=(-), keys=[1, 4, 6], vals=[512, 1024, 2048], N:None",
             "Node A=2048, R=(-), L=(+), keys=[6, 7, 8], vals=[66, 77, 88], N:None",
             "Node A=1024, R=(-), L=(+), keys=[4, 5], vals=[44, 55], N:Some(2048)",
             "Node A=512, R=(-), L=(+), keys=[1, 2], vals=[11, 22], N:Some(1024)",
         ];
-        let result_string = bt.dump_to_string();
+        let result_string = bt.dump_to_string().unwrap();
         let result: Vec<&str> = result_string.lines().collect();
         assert!(result.len() == expected.len());
         for (r, e) in result.iter().zip(expected.iter()) {
@@ -1983,20 +6766,21 @@ mod tests {
     fn remove_case_08() {
         // remove key from leaf. Rebalance from left sibling.
         log_init();
-        let path = std::path::Path::new("test.idx");
+        let path = std::path::Path::new("test_remove_case_08.idx");
         setup(&path);
-        let bt = btree::Btree::new(std::path::Path::new("test.idx"), 512, 2, 64, 8);
-        bt.set_degree(2, 4);
+        let bt =
+            btree::Btree::new(std::path::Path::new("test_remove_case_08.idx"), 512, 2, 8).unwrap();
+        bt.set_degree(2, 4).unwrap();
         for i in (1..=6).rev() {
             let _ = bt.insert(i, i * 10 + i);
         }
-        let _ = bt.remove(6);
+        let _ = bt.remove(&6);
         let expected = vec![
             "Node A=1536, R=(+), L=(-), keys=[1, 4], vals=[512, 1024], N:None",
             "Node A=1024, R=(-), L=(+), keys=[4, 5], vals=[44, 55], N:None",
             "Node A=512, R=(-), L=(+), keys=[1, 2, 3], vals=[11, 22, 33], N:Some(1024)",
         ];
-        let result_string = bt.dump_to_string();
+        let result_string = bt.dump_to_string().unwrap();
         let result: Vec<&str> = result_string.lines().collect();
         assert!(result.len() == expected.len());
         for (r, e) in result.iter().zip(expected.iter()) {
@@ -2008,20 +6792,21 @@ mod tests {
     fn remove_case_09() {
         // remove min_key from leaf. Rebalance from left sibling.
         log_init();
-        let path = std::path::Path::new("test.idx");
+        let path = std::path::Path::new("test_remove_case_09.idx");
         setup(&path);
-        let bt = btree::Btree::new(std::path::Path::new("test.idx"), 512, 2, 64, 8);
-        bt.set_degree(2, 4);
+        let bt =
+            btree::Btree::new(std::path::Path::new("test_remove_case_09.idx"), 512, 2, 8).unwrap();
+        bt.set_degree(2, 4).unwrap();
         for i in (1..=6).rev() {
             let _ = bt.insert(i, i * 10 + i);
         }
-        let _ = bt.remove(5);
+        let _ = bt.remove(&5);
         let expected = vec![
             "Node A=1536, R=(+), L=(-), keys=[1, 4], vals=[512, 1024], N:None",
             "Node A=1024, R=(-), L=(+), keys=[4, 6], vals=[44, 66], N:None",
             "Node A=512, R=(-), L=(+), keys=[1, 2, 3], vals=[11, 22, 33], N:Some(1024)",
         ];
-        let result_string = bt.dump_to_string();
+        let result_string = bt.dump_to_string().unwrap();
         let result: Vec<&str> = result_string.lines().collect();
         assert!(result.len() == expected.len());
         for (r, e) in result.iter().zip(expected.iter()) {
@@ -2033,26 +6818,1463 @@ mod tests {
     fn remove_case_10() {
         // remove min_key from leaf. Rebalance from right sibling.
         log_init();
-        let path = std::path::Path::new("test.idx");
+        let path = std::path::Path::new("test_remove_case_10.idx");
         setup(&path);
-        let bt = btree::Btree::new(std::path::Path::new("test.idx"), 512, 2, 64, 8);
-        bt.set_degree(1, 3);
+        let bt =
+            btree::Btree::new(std::path::Path::new("test_remove_case_10.idx"), 512, 2, 8).unwrap();
+        bt.set_degree(1, 3).unwrap();
         for i in 1..=8 {
             let _ = bt.insert(i, i * 10 + i);
         }
-        let _ = bt.remove(6);
-        let _ = bt.remove(5);
+        let _ = bt.remove(&6);
+        let _ = bt.remove(&5);
         let expected = vec![
             "Node A=1536, R=(+), L=(-), keys=[1, 3, 7], vals=[512, 1024, 2048], N:None",
             "Node A=2048, R=(-), L=(+), keys=[7, 8], vals=[77, 88], N:None",
             "Node A=1024, R=(-), L=(+), keys=[3, 4], vals=[33, 44], N:Some(2048)",
             "Node A=512, R=(-), L=(+), keys=[1, 2], vals=[11, 22], N:Some(1024)",
         ];
-        let result_string = bt.dump_to_string();
+        let result_string = bt.dump_to_string().unwrap();
         let result: Vec<&str> = result_string.lines().collect();
         assert!(result.len() == expected.len());
         for (r, e) in result.iter().zip(expected.iter()) {
             assert_eq!(r, e);
         }
     }
+
+    #[test]
+    fn journal_recovery() {
+        // tree is dropped without flush_cache, changes are in the journal only
+        log_init();
+        let path = std::path::Path::new("test_journal.idx");
+        {
+            setup(path);
+            let bt = btree::Btree::<u32, u32>::new(path, 512, 2, 64).unwrap();
+            for i in 0..2_000 {
+                assert!(bt.insert(i, i * 2).is_ok());
+            }
+            for i in (0..2_000).step_by(3) {
+                assert_eq!(bt.remove(&i).unwrap(), i * 2);
+            }
+        }
+        // torn record of the interrupted operation
+        let mut journal = std::fs::OpenOptions::new()
+            .append(true)
+            .open("test_journal.idx.journal")
+            .unwrap();
+        journal
+            .write_all(&[0x52, 0x4a, 0x54, 0x42, 0xff, 0x0f])
+            .unwrap();
+        let bt: btree::Btree<u32, u32> = btree::Btree::load(path, 8).unwrap();
+        for i in 0..2_000 {
+            match i % 3 {
+                0 => assert!(bt.find(&i).is_err()),
+                _ => assert_eq!(bt.find(&i).unwrap(), i * 2),
+            }
+        }
+        assert_eq!(bt.keys().count(), 1_333);
+        assert_eq!(
+            std::fs::metadata("test_journal.idx.journal").unwrap().len(),
+            0
+        );
+    }
+
+    #[test]
+    fn free_blocks_reuse() {
+        // merged blocks go to the free list and are reused by splits
+        log_init();
+        let path = std::path::Path::new("test_free.idx");
+        let file_size = || std::fs::metadata(path).unwrap().len();
+        let size = {
+            setup(path);
+            let bt = btree::Btree::<u32, u32>::new(path, 512, 2, 8).unwrap();
+            for i in 0..3_000 {
+                assert!(bt.insert(i, i).is_ok());
+            }
+            let size = file_size();
+            for i in 0..3_000 {
+                assert!(bt.remove(&i).is_ok());
+            }
+            bt.flush_cache().unwrap();
+            size
+        };
+        // free list survives reload
+        let bt: btree::Btree<u32, u32> = btree::Btree::load(path, 8).unwrap();
+        for round in 0..5 {
+            for i in 0..3_000 {
+                assert!(bt.insert(i, round).is_ok());
+            }
+            assert!(file_size() <= size);
+            for i in (0..3_000).rev() {
+                assert_eq!(bt.remove(&i).unwrap(), round);
+            }
+        }
+        assert_eq!(bt.iter().count(), 0);
+    }
+
+    #[test]
+    fn errors() {
+        log_init();
+        let path = std::path::Path::new("test_errors.idx");
+        setup(path);
+        assert!(matches!(
+            btree::Btree::<u32, u32>::load(path, 8),
+            Err(btree::BtreeError::Io(_))
+        ));
+        {
+            let bt = btree::Btree::<u32, u32>::new(path, 4096, 2, 8).unwrap();
+            assert!(bt.insert(1, 1).is_ok());
+            bt.flush_cache().unwrap();
+        }
+        // block size is read from the header
+        let bt: btree::Btree<u32, u32> = btree::Btree::load(path, 8).unwrap();
+        assert_eq!(bt.find(&1).unwrap(), 1);
+        drop(bt);
+        // damaged header: root address is changed, magic and version are intact
+        let mut data = std::fs::read(path).unwrap();
+        data[8] ^= 0x01;
+        std::fs::write(path, &data).unwrap();
+        assert!(matches!(
+            btree::Btree::<u32, u32>::load(path, 8),
+            Err(btree::BtreeError::Corrupt(_))
+        ));
+        // not an index file
+        let path = std::path::Path::new("test_foreign.idx");
+        setup(path);
+        std::fs::write(path, [0xff; 512]).unwrap();
+        assert!(matches!(
+            btree::Btree::<u32, u32>::load(path, 8),
+            Err(btree::BtreeError::HeaderMismatch(_))
+        ));
+        assert!(!std::path::Path::new("test_foreign.idx.journal").exists());
+    }
+
+    #[test]
+    fn file_grows_past_mapping() {
+        // the file is remapped when it outgrows the initial mapping
+        log_init();
+        let path = std::path::Path::new("test_grow.idx");
+        let val = |i: u32| format!("{:0>100}", i);
+        {
+            setup(path);
+            let bt = btree::Btree::<u32, String>::new(path, 512, 2, 8).unwrap();
+            for i in 0..10_000 {
+                assert!(bt.insert(i, val(i)).is_ok());
+            }
+            assert!(std::fs::metadata(path).unwrap().len() > 2 << 20);
+            for i in (0..10_000).step_by(7) {
+                assert_eq!(bt.find(&i).unwrap(), val(i));
+            }
+            bt.flush_cache().unwrap();
+        }
+        let bt: btree::Btree<u32, String> = btree::Btree::load(path, 8).unwrap();
+        assert_eq!(bt.iter().count(), 10_000);
+        assert_eq!(bt.find(&9_999).unwrap(), val(9_999));
+    }
+
+    #[test]
+    fn verify() {
+        log_init();
+        let path = std::path::Path::new("test_verify.idx");
+        setup(path);
+        {
+            let bt = btree::Btree::<u32, u32>::new(path, 512, 2, 8).unwrap();
+            for i in 0..1000 {
+                assert!(bt.insert(i, i).is_ok());
+            }
+            for i in 300..700 {
+                assert_eq!(bt.remove(&i).unwrap(), i);
+            }
+            let report = bt.verify().unwrap();
+            assert!(report.is_ok(), "{:?}", report.problems);
+            assert_eq!(report.entries, 600);
+            assert!(report.depth > 1);
+            assert!(report.free_blocks > 0);
+            bt.flush_cache().unwrap();
+        }
+        // bit rot in the leftmost leaf, the first node allocated
+        let mut data = std::fs::read(path).unwrap();
+        data[512 + 40] ^= 0x01;
+        std::fs::write(path, &data).unwrap();
+        let bt: btree::Btree<u32, u32> = btree::Btree::load(path, 0).unwrap();
+        assert!(matches!(bt.find(&0), Err(btree::BtreeError::Corrupt(_))));
+        assert_eq!(bt.find(&999).unwrap(), 999);
+        let report = bt.verify().unwrap();
+        assert!(!report.is_ok());
+        assert!(report.problems.contains(&btree::VerifyProblem::Unreadable {
+            addr: 512,
+            reason: "corrupted index: node checksum mismatch at 512".to_string(),
+        }));
+    }
+
+    #[test]
+    fn concurrent_threads() {
+        log_init();
+        let path = std::path::Path::new("test_concurrent.idx");
+        setup(path);
+        let options = btree::BtreeOptions::new()
+            .block_size(512)
+            .cache_size(16)
+            .cache_policy(btree::CacheKind::Clock)
+            .durability(btree::Durability::Async);
+        assert!(matches!(
+            btree::ConcurrentBtree::<u32, String>::new(path, &options.clone().multimap(true)),
+            Err(btree::BtreeError::HeaderMismatch(_))
+        ));
+        {
+            let bt = btree::ConcurrentBtree::<u32, String>::new(path, &options).unwrap();
+            // threads insert interleaved keys, so they meet in the same leaves
+            let writers: Vec<_> = (0..4u32)
+                .map(|t| {
+                    let bt = bt.clone();
+                    std::thread::spawn(move || {
+                        for i in (0..2000u32).filter(|i| i % 4 == t) {
+                            bt.insert(i, "v".repeat((i % 13) as usize)).unwrap();
+                        }
+                        for i in (0..2000u32).filter(|i| i % 4 == t && i % 3 == 0) {
+                            assert_eq!(bt.remove(&i).unwrap(), "v".repeat((i % 13) as usize));
+                        }
+                    })
+                })
+                .collect();
+            let reader = {
+                let bt = bt.clone();
+                std::thread::spawn(move || {
+                    for _ in 0..20 {
+                        let pairs = bt.range(..).unwrap();
+                        assert!(pairs.windows(2).all(|w| w[0].0 < w[1].0));
+                    }
+                })
+            };
+            for handle in writers {
+                handle.join().unwrap();
+            }
+            reader.join().unwrap();
+            assert!(matches!(bt.remove(&3), Err(btree::BtreeError::KeyNotFound)));
+            assert!(matches!(
+                bt.insert(1, String::new()),
+                Err(btree::BtreeError::DuplicateKey)
+            ));
+            assert_eq!(bt.find(&1000).unwrap(), "v".repeat(1000 % 13));
+            let keys: Vec<u32> = bt
+                .range(10..20)
+                .unwrap()
+                .into_iter()
+                .map(|(k, _)| k)
+                .collect();
+            assert_eq!(keys, vec![10, 11, 13, 14, 16, 17, 19]);
+            bt.flush().unwrap();
+        }
+        // same file format as Btree, the options are stored
+        let bt: btree::Btree<u32, String> = btree::Btree::load(path, 8).unwrap();
+        assert_eq!(bt.options().cache_policy, Some(btree::CacheKind::Clock));
+        assert_eq!(bt.options().durability, Some(btree::Durability::Async));
+        let report = bt.verify().unwrap();
+        assert!(report.is_ok(), "{:?}", report.problems);
+        let expected: Vec<u32> = (0..2000).filter(|i| i % 3 != 0).collect();
+        let keys: Result<Vec<u32>, _> = bt.keys().collect();
+        assert_eq!(keys.unwrap(), expected);
+        drop(bt);
+
+        // opened like Btree: structural settings are checked, the others are overridden
+        for wrong in [
+            btree::BtreeOptions::new().block_size(4096),
+            btree::BtreeOptions::new().alpha(3),
+            btree::BtreeOptions::new().multimap(true),
+        ]
+        .iter()
+        {
+            assert!(matches!(
+                btree::ConcurrentBtree::<u32, String>::open(path, wrong),
+                Err(btree::BtreeError::HeaderMismatch(_))
+            ));
+        }
+        let overrides = btree::BtreeOptions::new()
+            .cache_size(4)
+            .growth(btree::Growth::Blocks(64));
+        {
+            let bt = btree::ConcurrentBtree::<u32, String>::open(path, &overrides).unwrap();
+            let len = std::fs::metadata(path).unwrap().len();
+            for i in 2000..6000u32 {
+                bt.insert(i, i.to_string()).unwrap();
+            }
+            bt.flush().unwrap();
+            let grown = std::fs::metadata(path).unwrap().len() - len;
+            assert_eq!(grown % (64 * 512), 0);
+            assert!(grown > 0);
+        }
+        let bt = btree::Btree::<u32, String>::open(path, &btree::BtreeOptions::new()).unwrap();
+        assert_eq!(bt.options().cache_size, Some(16));
+        assert_eq!(bt.options().growth, Some(btree::Growth::Blocks(1)));
+        assert!(bt.verify().unwrap().is_ok());
+        assert_eq!(bt.find(&5999).unwrap(), "5999");
+    }
+
+    #[test]
+    fn concurrent_overflow() {
+        log_init();
+        let path = std::path::Path::new("test_concurrent_overflow.idx");
+        setup(path);
+        let options = btree::BtreeOptions::new().block_size(512).cache_size(4);
+        let val = |i: u32| i.to_string().repeat((i % 7 * 100) as usize);
+        {
+            // values written by Btree are read and replaced
+            let bt = btree::Btree::<u32, String>::create_with(path, &options).unwrap();
+            for i in 0..100u32 {
+                bt.insert(i, val(i)).unwrap();
+            }
+        }
+        {
+            let bt = btree::ConcurrentBtree::<u32, String>::open(path, &options).unwrap();
+            let writers: Vec<_> = (0..4u32)
+                .map(|t| {
+                    let bt = bt.clone();
+                    std::thread::spawn(move || {
+                        for i in (100..1000u32).filter(|i| i % 4 == t) {
+                            bt.insert(i, val(i)).unwrap();
+                        }
+                        for i in (0..1000u32).filter(|i| i % 4 == t && i % 3 == 0) {
+                            assert_eq!(bt.remove(&i).unwrap(), val(i));
+                        }
+                    })
+                })
+                .collect();
+            for handle in writers {
+                handle.join().unwrap();
+            }
+            // spilled value is read and freed before the flush
+            bt.insert(3, val(6)).unwrap();
+            assert_eq!(bt.find(&3).unwrap(), val(6));
+            assert_eq!(bt.remove(&3).unwrap(), val(6));
+            assert!(matches!(
+                bt.insert(1, val(6)),
+                Err(btree::BtreeError::DuplicateKey)
+            ));
+            assert_eq!(bt.find(&13).unwrap(), val(13));
+            let pairs = bt.range(20..30).unwrap();
+            assert_eq!(pairs.len(), 7);
+            assert!(pairs.iter().all(|(k, v)| *v == val(*k)));
+            bt.flush().unwrap();
+        }
+        let bt = btree::Btree::<u32, String>::open(path, &btree::BtreeOptions::new()).unwrap();
+        let report = bt.verify().unwrap();
+        assert!(report.is_ok(), "{:?}", report.problems);
+        for i in 0..1000u32 {
+            match i % 3 {
+                0 => assert!(matches!(bt.find(&i), Err(btree::BtreeError::KeyNotFound))),
+                _ => assert_eq!(bt.find(&i).unwrap(), val(i)),
+            }
+        }
+    }
+
+    #[test]
+    fn bulk_load() {
+        log_init();
+        let path = std::path::Path::new("test_bulk.idx");
+        setup(path);
+        let options = btree::BtreeOptions::new().block_size(512).cache_size(8);
+        let pairs = (0..5000u32).map(|i| (i * 2, i));
+        let bt = btree::Btree::bulk_load_with(path, &options, pairs, 0.7).unwrap();
+        let report = bt.verify().unwrap();
+        assert!(report.is_ok(), "{:?}", report.problems);
+        assert_eq!(report.entries, 5000);
+        assert_eq!(report.free_blocks, 0);
+        assert_eq!(bt.find(&4000).unwrap(), 2000);
+        // nodes have room for inserts
+        for i in 0..5000 {
+            assert!(bt.insert(i * 2 + 1, i).is_ok());
+        }
+        let keys: Result<Vec<u32>, _> = bt.keys().collect();
+        assert_eq!(keys.unwrap(), (0..10_000).collect::<Vec<u32>>());
+        assert!(bt.verify().unwrap().is_ok());
+        drop(bt);
+
+        let empty = std::iter::empty::<(u32, u32)>();
+        let bt = btree::Btree::bulk_load(path, empty, 1.0).unwrap();
+        assert_eq!(bt.iter().count(), 0);
+        assert!(bt.insert(1, 1).is_ok());
+        drop(bt);
+        let unsorted = vec![(1u32, 1u32), (3, 3), (2, 2)];
+        assert!(matches!(
+            btree::Btree::bulk_load_with(path, &options, unsorted, 1.0),
+            Err(btree::BtreeError::Unsorted)
+        ));
+        assert!(matches!(
+            btree::Btree::bulk_load_with(path, &options, vec![(1u32, 1u32), (1, 2)], 1.0),
+            Err(btree::BtreeError::DuplicateKey)
+        ));
+        for fill_factor in [0.0, -0.5, 1.01, f64::NAN].iter() {
+            assert!(matches!(
+                btree::Btree::bulk_load(path, vec![(1u32, 1u32)], *fill_factor),
+                Err(btree::BtreeError::BadFillFactor)
+            ));
+        }
+
+        // multimap keeps the equal keys in the given order, also across leaves
+        let options = options.multimap(true).durability(btree::Durability::Async);
+        let pairs = (0..3000u32).map(|i| (i / 100, i));
+        let bt = btree::Btree::bulk_load_with(path, &options, pairs, 0.8).unwrap();
+        assert!(bt.is_multimap());
+        // options are stored, the tree is opened with them
+        assert_eq!(bt.options().durability, Some(btree::Durability::Async));
+        let report = bt.verify().unwrap();
+        assert!(report.is_ok(), "{:?}", report.problems);
+        assert_eq!(report.entries, 3000);
+        assert_eq!(bt.find_all(&7).unwrap(), (700..800).collect::<Vec<u32>>());
+        bt.insert(7, 0).unwrap();
+        assert_eq!(bt.find_all(&7).unwrap().last(), Some(&0));
+        assert_eq!(bt.remove(&7).unwrap(), 700);
+        assert!(bt.verify().unwrap().is_ok());
+    }
+
+    #[test]
+    fn transaction() {
+        log_init();
+        let path = std::path::Path::new("test_transaction.idx");
+        setup(path);
+        let bt = btree::Btree::<u32, u32>::new(path, 512, 2, 8).unwrap();
+        for i in 0..100 {
+            bt.insert(i, i).unwrap();
+        }
+        let mut tx = bt.transaction();
+        for i in 100..300 {
+            tx.insert(i, i).unwrap();
+        }
+        for i in 0..50 {
+            assert_eq!(tx.remove(&i).unwrap(), i);
+        }
+        assert_eq!(tx.update(60, 6000).unwrap(), 60);
+        assert_eq!(tx.update(150, 1500).unwrap(), 150);
+        assert!(matches!(
+            tx.insert(60, 0),
+            Err(btree::BtreeError::DuplicateKey)
+        ));
+        assert!(matches!(
+            tx.remove(&10),
+            Err(btree::BtreeError::KeyNotFound)
+        ));
+        tx.insert(10, 1000).unwrap();
+        // changes are seen only through the transaction
+        assert_eq!(tx.find(&150).unwrap(), 1500);
+        assert!(matches!(bt.find(&150), Err(btree::BtreeError::KeyNotFound)));
+        assert_eq!(bt.find(&20).unwrap(), 20);
+        tx.commit().unwrap();
+        assert_eq!(bt.find(&10).unwrap(), 1000);
+        assert!(matches!(bt.find(&20), Err(btree::BtreeError::KeyNotFound)));
+        assert_eq!(bt.find(&60).unwrap(), 6000);
+        assert_eq!(bt.find(&150).unwrap(), 1500);
+        assert_eq!(bt.iter().count(), 251);
+
+        let mut tx = bt.transaction();
+        tx.remove(&60).unwrap();
+        tx.rollback();
+        assert_eq!(bt.find(&60).unwrap(), 6000);
+
+        // key inserted by the transaction appears in the tree before commit
+        let mut tx = bt.transaction();
+        tx.remove(&61).unwrap();
+        tx.insert(500, 500).unwrap();
+        bt.insert(500, 0).unwrap();
+        assert!(matches!(tx.commit(), Err(btree::BtreeError::DuplicateKey)));
+        assert_eq!(bt.find(&61).unwrap(), 61);
+        assert_eq!(bt.find(&500).unwrap(), 0);
+        let report = bt.verify().unwrap();
+        assert!(report.is_ok(), "{:?}", report.problems);
+        assert_eq!(report.entries, 252);
+    }
+
+    #[test]
+    fn snapshot() {
+        log_init();
+        let path = std::path::Path::new("test_snapshot.idx");
+        setup(path);
+        let bt = btree::Btree::<u32, u32>::new(path, 512, 2, 8).unwrap();
+        for i in 0..1000 {
+            bt.insert(i, i).unwrap();
+        }
+        let snapshot = bt.snapshot().unwrap();
+        let free = bt.verify().unwrap().free_blocks;
+        for i in 0..500 {
+            bt.remove(&i).unwrap();
+        }
+        for i in 1000..2000 {
+            bt.insert(i, i).unwrap();
+        }
+        // changed nodes are copied, the blocks of the snapshot are kept
+        let report = bt.verify().unwrap();
+        assert!(report.is_ok(), "{:?}", report.problems);
+        assert!(matches!(
+            bt.compact(),
+            Err(btree::BtreeError::SnapshotAlive)
+        ));
+        assert!(matches!(bt.find(&10), Err(btree::BtreeError::KeyNotFound)));
+        assert_eq!(snapshot.find(&10).unwrap(), 10);
+        assert!(matches!(
+            snapshot.find(&1500),
+            Err(btree::BtreeError::KeyNotFound)
+        ));
+        let keys: Result<Vec<u32>, _> = snapshot.iter().map(|r| r.map(|(k, _)| k)).collect();
+        assert_eq!(keys.unwrap(), (0..1000).collect::<Vec<u32>>());
+        let keys: Result<Vec<u32>, _> = snapshot
+            .range((Excluded(400), Excluded(600)))
+            .map(|r| r.map(|(k, _)| k))
+            .collect();
+        assert_eq!(keys.unwrap(), (401..600).collect::<Vec<u32>>());
+
+        // values replaced in place are copied too, each snapshot keeps its version
+        let second = bt.snapshot().unwrap();
+        for i in 500..1500 {
+            bt.update(&i, |v| v + 1).unwrap();
+        }
+        assert_eq!(bt.find(&700).unwrap(), 701);
+        assert_eq!(second.find(&700).unwrap(), 700);
+        assert_eq!(snapshot.find(&700).unwrap(), 700);
+        assert_eq!(second.find(&1200).unwrap(), 1200);
+        drop(snapshot);
+        let values: Result<Vec<u32>, _> = second.iter().map(|r| r.map(|(_, v)| v)).collect();
+        assert_eq!(values.unwrap(), (500..2000).collect::<Vec<u32>>());
+        assert!(bt.verify().unwrap().is_ok());
+        drop(second);
+        // blocks kept for the snapshots are freed
+        let report = bt.verify().unwrap();
+        assert!(report.is_ok(), "{:?}", report.problems);
+        assert!(report.free_blocks > free);
+        bt.compact().unwrap();
+        assert_eq!(bt.iter().count(), 1500);
+        assert_eq!(bt.find(&1999).unwrap(), 1999);
+        assert!(bt.verify().unwrap().is_ok());
+        drop(bt);
+        let bt = btree::Btree::<u32, u32>::load(path, 8).unwrap();
+        let report = bt.verify().unwrap();
+        assert!(report.is_ok(), "{:?}", report.problems);
+        assert_eq!(report.free_blocks, 0);
+        assert_eq!(bt.find(&700).unwrap(), 701);
+    }
+
+    #[test]
+    fn update_in_place() {
+        log_init();
+        let path = std::path::Path::new("test_update.idx");
+        setup(path);
+        let bt = btree::Btree::<u32, String>::new(path, 512, 2, 8).unwrap();
+        for i in 0..200 {
+            bt.insert(i, "a".to_string()).unwrap();
+        }
+        let nodes = bt.verify().unwrap().nodes;
+        assert_eq!(
+            bt.upsert(5, "b".to_string()).unwrap(),
+            Some("a".to_string())
+        );
+        assert_eq!(bt.upsert(500, "c".to_string()).unwrap(), None);
+        assert_eq!(bt.update(&6, |v| v.clone() + "d").unwrap(), "a");
+        assert_eq!(bt.find(&6).unwrap(), "ad");
+        assert!(matches!(
+            bt.update(&300, |v| v.clone()),
+            Err(btree::BtreeError::KeyNotFound)
+        ));
+        assert_eq!(bt.get_or_insert_with(7, || "e".to_string()).unwrap(), "a");
+        assert_eq!(bt.get_or_insert_with(501, || "f".to_string()).unwrap(), "f");
+        assert_eq!(bt.find(&501).unwrap(), "f");
+        assert!(!bt
+            .compare_and_swap(&8, &"x".to_string(), "g".to_string())
+            .unwrap());
+        assert!(bt
+            .compare_and_swap(&8, &"a".to_string(), "g".to_string())
+            .unwrap());
+        assert_eq!(bt.find(&8).unwrap(), "g");
+        // values of the same size are replaced in the leaf
+        let report = bt.verify().unwrap();
+        assert!(report.is_ok(), "{:?}", report.problems);
+        assert_eq!(report.nodes, nodes);
+        // larger values don't fit, the entries move to the split nodes
+        for i in 0..200 {
+            bt.update(&i, |_| "z".repeat(50)).unwrap();
+        }
+        // too large for the leaf, spilled to the overflow blocks
+        assert_eq!(
+            bt.upsert(0, "z".repeat(1000)).unwrap(),
+            Some("z".repeat(50))
+        );
+        assert_eq!(bt.find(&0).unwrap(), "z".repeat(1000));
+        let report = bt.verify().unwrap();
+        assert!(report.is_ok(), "{:?}", report.problems);
+        assert_eq!(report.entries, 202);
+        assert!(report.nodes > nodes);
+        assert_eq!(bt.find(&199).unwrap(), "z".repeat(50));
+    }
+
+    #[test]
+    fn multimap() {
+        log_init();
+        let path = std::path::Path::new("test_multimap.idx");
+        setup(path);
+        let bt = btree::Btree::<u32, u32>::new_multimap(path, 512, 2, 8).unwrap();
+        // values of a key span several leaves
+        for val in 0..50 {
+            for key in 0..20 {
+                bt.insert(key, val).unwrap();
+            }
+        }
+        assert_eq!(bt.find_all(&7).unwrap(), (0..50).collect::<Vec<u32>>());
+        assert_eq!(bt.find(&7).unwrap(), 0);
+        assert!(bt.find_all(&20).unwrap().is_empty());
+        assert_eq!(bt.range(5..=5).count(), 50);
+        assert_eq!(bt.range((Excluded(5), Excluded(8))).count(), 100);
+        assert_eq!(bt.range(5..7).rev().count(), 100);
+        bt.remove_one(&5, &25).unwrap();
+        assert!(matches!(
+            bt.remove_one(&5, &25),
+            Err(btree::BtreeError::KeyNotFound)
+        ));
+        assert_eq!(bt.remove(&5).unwrap(), 0);
+        let expected: Vec<u32> = (1..50).filter(|val| *val != 25).collect();
+        assert_eq!(bt.find_all(&5).unwrap(), expected);
+        assert_eq!(bt.update(&6, |val| val + 100).unwrap(), 0);
+        assert_eq!(bt.find(&6).unwrap(), 100);
+        for val in (0..50).rev() {
+            bt.remove_one(&9, &val).unwrap();
+        }
+        assert!(matches!(bt.find(&9), Err(btree::BtreeError::KeyNotFound)));
+        let report = bt.verify().unwrap();
+        assert!(report.is_ok(), "{:?}", report.problems);
+        assert_eq!(report.entries, 19 * 50 - 2);
+        drop(bt);
+        let bt = btree::Btree::<u32, u32>::load(path, 8).unwrap();
+        assert!(bt.is_multimap());
+        bt.insert(9, 1).unwrap();
+        bt.insert(9, 1).unwrap();
+        assert_eq!(bt.find_all(&9).unwrap(), vec![1, 1]);
+    }
+
+    #[test]
+    fn overflow() {
+        log_init();
+        let path = std::path::Path::new("test_overflow.idx");
+        setup(path);
+        let bt = btree::Btree::<u32, Vec<u8>>::new(path, 512, 2, 8).unwrap();
+        let value = |i: u32| {
+            vec![
+                i as u8;
+                if i.is_multiple_of(3) {
+                    2000 + i as usize
+                } else {
+                    20
+                }
+            ]
+        };
+        for i in 0..300 {
+            bt.insert(i, value(i)).unwrap();
+        }
+        let report = bt.verify().unwrap();
+        assert!(report.is_ok(), "{:?}", report.problems);
+        assert_eq!(report.overflow_blocks, 100 * 5);
+        assert_eq!(bt.find(&3).unwrap(), value(3));
+        let snapshot = bt.snapshot().unwrap();
+        // large values are freed on remove and replace
+        for i in (0..300).step_by(6) {
+            assert_eq!(bt.remove(&i).unwrap(), value(i));
+        }
+        assert_eq!(bt.upsert(3, vec![1; 10]).unwrap(), Some(value(3)));
+        assert_eq!(bt.update(&4, |_| vec![2; 5000]).unwrap(), value(4));
+        let report = bt.verify().unwrap();
+        assert!(report.is_ok(), "{:?}", report.problems);
+        // freed blocks are kept while the snapshot reads them
+        assert!(report.free_blocks < 200);
+        assert_eq!(snapshot.find(&6).unwrap(), value(6));
+        assert_eq!(snapshot.find(&4).unwrap(), value(4));
+        assert_eq!(snapshot.iter().count(), 300);
+        drop(snapshot);
+        assert!(bt.verify().unwrap().free_blocks > 200);
+        // overflow blocks are moved with the nodes
+        let len = std::fs::metadata(path).unwrap().len();
+        bt.compact().unwrap();
+        assert!(std::fs::metadata(path).unwrap().len() < len);
+        let report = bt.verify().unwrap();
+        assert!(report.is_ok(), "{:?}", report.problems);
+        assert_eq!(report.free_blocks, 0);
+        drop(bt);
+        let bt = btree::Btree::<u32, Vec<u8>>::load(path, 8).unwrap();
+        assert_eq!(bt.find(&3).unwrap(), vec![1; 10]);
+        assert_eq!(bt.find(&4).unwrap(), vec![2; 5000]);
+        for item in bt.range(7..) {
+            let (key, val) = item.unwrap();
+            if !key.is_multiple_of(6) {
+                assert_eq!(val, value(key));
+            }
+        }
+        bt.insert(1000, vec![0; 100_000]).unwrap();
+        assert_eq!(bt.find(&1000).unwrap().len(), 100_000);
+
+        let path = std::path::Path::new("test_overflow_bulk.idx");
+        setup(path);
+        let bt = btree::Btree::<Vec<u8>, Vec<u8>>::bulk_load_with(
+            path,
+            &btree::BtreeOptions::new().block_size(512).cache_size(8),
+            (0..50u8).map(|i| (vec![i], vec![i; 700])),
+            1.0,
+        )
+        .unwrap();
+        assert_eq!(bt.find(&vec![7]).unwrap(), vec![7; 700]);
+        let report = bt.verify().unwrap();
+        assert!(report.is_ok(), "{:?}", report.problems);
+        assert!(matches!(
+            bt.insert(vec![0; 200], Vec::new()),
+            Err(btree::BtreeError::EntryTooLarge)
+        ));
+    }
+
+    #[test]
+    fn slotted_page() {
+        log_init();
+        let path = std::path::Path::new("test_slotted_page.idx");
+        setup(path);
+        let key = |i: u32| format!("key-{}", "x".repeat((i % 17) as usize) + &i.to_string());
+        {
+            let bt = btree::Btree::<String, u32>::new(path, 512, 2, 8).unwrap();
+            for i in 0..2000 {
+                bt.insert(key(i), i).unwrap();
+            }
+            for i in (0..2000).step_by(3) {
+                assert_eq!(bt.remove(&key(i)).unwrap(), i);
+            }
+            bt.flush_cache().unwrap();
+        }
+        // no cache, nodes are searched in place on the mapped blocks
+        let bt: btree::Btree<String, u32> = btree::Btree::load(path, 0).unwrap();
+        for i in 0..2000 {
+            match bt.find(&key(i)) {
+                Ok(val) => assert_eq!(val, i),
+                Err(btree::BtreeError::KeyNotFound) => assert!(i.is_multiple_of(3)),
+                Err(err) => panic!("{:?}", err),
+            }
+        }
+        assert!(matches!(
+            bt.find(&"missing".to_string()),
+            Err(btree::BtreeError::KeyNotFound)
+        ));
+        let mut keys: Vec<String> = (0..2000).filter(|i| i % 3 != 0).map(key).collect();
+        keys.sort();
+        let found: Vec<String> = bt
+            .range((Excluded(keys[10].clone()), Excluded(keys[20].clone())))
+            .map(|entry| entry.unwrap().0)
+            .collect();
+        assert_eq!(found, keys[11..20].to_vec());
+        let snapshot = bt.snapshot().unwrap();
+        assert_eq!(
+            snapshot.find(&keys[100]).unwrap(),
+            bt.find(&keys[100]).unwrap()
+        );
+        let report = bt.verify().unwrap();
+        assert!(report.is_ok(), "{:?}", report.problems);
+    }
+
+    #[test]
+    fn key_compression() {
+        log_init();
+        let path = std::path::Path::new("test_key_compression.idx");
+        setup(path);
+        let key = |i: u32| format!("tenant/acme-corporation/objects/{:08}", i);
+        let bt = btree::Btree::<String, u32>::new(path, 2048, 2, 16).unwrap();
+        for i in 0..5_000 {
+            bt.insert(key(i), i).unwrap();
+        }
+        let report = bt.verify().unwrap();
+        assert!(report.is_ok(), "{:?}", report.problems);
+        assert_eq!(report.entries, 5_000);
+        // 48 bytes of the encoded key, over 60 with the value and the slot,
+        // a leaf holds more than a block of such entries
+        assert!(report.leaves < 5_000 * 60 / 2048, "{}", report.leaves);
+        // short separators are replaced by the longer keys, when leaves are rebalanced
+        for i in (0..5_000).filter(|i| i % 5 != 0) {
+            assert_eq!(bt.remove(&key(i)).unwrap(), i);
+        }
+        for i in (0..5_000).step_by(5) {
+            assert_eq!(bt.find(&key(i)).unwrap(), i);
+        }
+        assert!(bt.find(&key(1)).is_err());
+        let report = bt.verify().unwrap();
+        assert!(report.is_ok(), "{:?}", report.problems);
+        let found: Vec<u32> = bt
+            .range(key(100)..key(200))
+            .map(|entry| entry.unwrap().1)
+            .collect();
+        assert_eq!(found, (100..200).step_by(5).collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn cache_policies() {
+        log_init();
+        let path = std::path::Path::new("test_cache_policies.idx");
+        let set_policy = |bt: &btree::Btree<u32, u32>, policy| match policy {
+            0 => bt.set_cache_policy(btree::LruPolicy::default()),
+            1 => bt.set_cache_policy(btree::ClockPolicy::default()),
+            2 => bt.set_cache_policy(btree::TwoQPolicy::default()),
+            _ => bt.set_cache_policy(btree::ArcPolicy::default()),
+        };
+        for policy in 0..4 {
+            setup(path);
+            let bt = btree::Btree::<u32, u32>::new(path, 256, 2, 8).unwrap();
+            set_policy(&bt, policy);
+            for i in 0..2_000 {
+                bt.insert(i, i * 2).unwrap();
+            }
+            for i in (1..2_000).step_by(2) {
+                assert_eq!(bt.remove(&i).unwrap(), i * 2);
+            }
+            let report = bt.verify().unwrap();
+            assert!(report.is_ok(), "{:?}", report.problems);
+            bt.flush_cache().unwrap();
+            let stats = bt.cache_stats();
+            assert!(stats.hits > 0 && stats.misses > 0, "{:?}", stats);
+            assert!(stats.evictions > 0 && stats.write_backs > 0, "{:?}", stats);
+            // clean nodes are not written again
+            for i in (0..2_000).step_by(2) {
+                assert_eq!(bt.find(&i).unwrap(), i * 2);
+            }
+            bt.flush_cache().unwrap();
+            assert_eq!(bt.cache_stats().write_backs, stats.write_backs);
+            drop(bt);
+            let bt = btree::Btree::<u32, u32>::load(path, 8).unwrap();
+            set_policy(&bt, policy);
+            for i in 0..2_000 {
+                assert_eq!(
+                    bt.find(&i).ok(),
+                    if i % 2 == 0 { Some(i * 2) } else { None }
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn stats() {
+        log_init();
+        let path = std::path::Path::new("test_stats.idx");
+        setup(path);
+        let bt = btree::Btree::<u32, u32>::new(path, 256, 2, 4).unwrap();
+        for i in 0..1_000 {
+            bt.insert(i, i).unwrap();
+        }
+        let stats = bt.stats().unwrap();
+        let report = bt.verify().unwrap();
+        assert!(stats.splits > 0 && stats.node_writes > 0, "{:?}", stats);
+        assert!(
+            stats.cache_hits > 0 && stats.cache_misses > 0,
+            "{:?}",
+            stats
+        );
+        assert_eq!(stats.height, report.depth);
+        assert_eq!(stats.nodes, report.nodes);
+        assert!(
+            stats.fill_factor > 0.4 && stats.fill_factor <= 1.0,
+            "{:?}",
+            stats
+        );
+        assert_eq!(
+            (stats.merges, stats.rebalances, stats.free_blocks),
+            (0, 0, 0)
+        );
+        for i in (0..1_000).filter(|i| i % 10 != 0) {
+            bt.remove(&i).unwrap();
+        }
+        bt.flush_cache().unwrap();
+        let stats = bt.stats().unwrap();
+        let report = bt.verify().unwrap();
+        assert!(stats.merges > 0 && stats.rebalances > 0, "{:?}", stats);
+        assert!(
+            stats.dirty_flushes > 0 && stats.evictions > 0,
+            "{:?}",
+            stats
+        );
+        assert_eq!(stats.nodes, report.nodes);
+        assert_eq!(stats.free_blocks, report.free_blocks);
+        assert!(stats.free_blocks > 0);
+        // the walk itself is not counted
+        let node_reads = bt.stats().unwrap().node_reads;
+        assert_eq!(bt.stats().unwrap().node_reads, node_reads);
+    }
+
+    #[test]
+    fn find_in_place() {
+        // cold find reads the leaf in place, no node is decoded whole
+        log_init();
+        let path = std::path::Path::new("test_find_in_place.idx");
+        setup(path);
+        {
+            let bt = btree::Btree::<u32, String>::new_multimap(path, 512, 2, 8).unwrap();
+            for i in 0..2_000u32 {
+                bt.insert(i / 50, format!("{}-{}", i / 50, i)).unwrap();
+            }
+            bt.flush_cache().unwrap();
+        }
+        let bt = btree::Btree::<u32, String>::load(path, 8).unwrap();
+        // equal keys span several leaves, the first value is found
+        for key in 0..40u32 {
+            assert_eq!(bt.find(&key).unwrap(), format!("{}-{}", key, key * 50));
+        }
+        assert!(matches!(bt.find(&40), Err(btree::BtreeError::KeyNotFound)));
+        let snapshot = bt.snapshot().unwrap();
+        assert_eq!(snapshot.find(&7).unwrap(), "7-350");
+        assert!(matches!(
+            snapshot.find(&40),
+            Err(btree::BtreeError::KeyNotFound)
+        ));
+        let stats = bt.stats().unwrap();
+        assert!(stats.node_reads > 0);
+        assert_eq!(stats.node_decodes, 0);
+        drop(snapshot);
+        // changed nodes are decoded
+        bt.insert(3, "new".to_string()).unwrap();
+        assert!(bt.stats().unwrap().node_decodes > 0);
+        assert_eq!(bt.find(&3).unwrap(), "3-150");
+    }
+
+    #[test]
+    fn compact_step() {
+        log_init();
+        let path = std::path::Path::new("test_compact_step.idx");
+        setup(path);
+        let bt = btree::Btree::<u32, String>::new(path, 256, 2, 8).unwrap();
+        for i in 0..2_000 {
+            // every tenth value goes to the overflow blocks
+            let val = if i % 10 == 0 {
+                "v".repeat(300)
+            } else {
+                i.to_string()
+            };
+            bt.insert(i, val).unwrap();
+        }
+        for i in (0..2_000).filter(|i| i % 3 != 0) {
+            bt.remove(&i).unwrap();
+        }
+        let size = std::fs::metadata(path).unwrap().len();
+        let free = bt.verify().unwrap().free_blocks;
+        assert!(free > 0);
+        let mut steps = 0;
+        let mut moved = 0;
+        loop {
+            // the tree is usable between the steps, replaced values free their blocks
+            steps += 1;
+            assert!(bt.find(&(steps * 3)).is_ok());
+            bt.upsert(steps * 3, "x".to_string()).unwrap();
+            let progress = bt.compact_step(4).unwrap();
+            assert!(progress.moved <= 4);
+            moved += progress.moved;
+            if progress.done {
+                break;
+            }
+        }
+        assert!(steps > 1 && moved > 0, "{} {}", steps, moved);
+        let report = bt.verify().unwrap();
+        assert!(report.is_ok(), "{:?}", report.problems);
+        assert_eq!(report.free_blocks, 0);
+        bt.flush_cache().unwrap();
+        assert!(std::fs::metadata(path).unwrap().len() < size);
+        drop(bt);
+
+        let bt = btree::Btree::<u32, String>::load(path, 8).unwrap();
+        for i in 0..2_000 {
+            let expected = match i {
+                _ if i % 3 != 0 => None,
+                _ if i > 0 && i <= steps * 3 => Some("x".to_string()),
+                _ if i % 10 == 0 => Some("v".repeat(300)),
+                _ => Some(i.to_string()),
+            };
+            assert_eq!(bt.find(&i).ok(), expected, "{}", i);
+        }
+    }
+
+    #[test]
+    fn export() {
+        log_init();
+        let path = std::path::Path::new("test_export.idx");
+        setup(path);
+        let bt = btree::Btree::<String, u32>::new(path, 256, 2, 4).unwrap();
+        for i in 0..300 {
+            bt.insert(format!("key|{:03}", i), i).unwrap();
+        }
+        let report = bt.verify().unwrap();
+
+        let json: serde_json::Value = serde_json::from_str(&bt.to_json().unwrap()).unwrap();
+        let nodes = json["nodes"].as_array().unwrap();
+        assert_eq!(nodes.len() as u64, report.nodes);
+        assert_eq!(json["height"], report.depth);
+        assert_eq!(json["root"], nodes[0]["addr"]);
+        let leaves: Vec<_> = nodes.iter().filter(|node| node["leaf"] == true).collect();
+        assert_eq!(leaves.len() as u64, report.leaves);
+        assert_eq!(leaves[0]["keys"][0], "key|000");
+        for pair in leaves.windows(2) {
+            assert_eq!(pair[0]["next"], pair[1]["addr"]);
+        }
+        let fill = nodes[0]["fill"].as_f64().unwrap();
+        assert!(fill > 0.0 && fill < 1.0);
+
+        let dot = bt.to_dot().unwrap();
+        assert!(dot.starts_with("digraph btree {"));
+        // child edges and the next links
+        let edges = dot.matches(" -> ").count() as u64;
+        assert_eq!(edges, report.nodes - 1 + report.leaves - 1);
+        assert!(dot.contains("key\\|000"));
+    }
+
+    #[test]
+    fn options() {
+        log_init();
+        let path = std::path::Path::new("test_options.idx");
+        setup(path);
+        let options = btree::BtreeOptions::new()
+            .block_size(512)
+            .alpha(3)
+            .cache_size(16)
+            .cache_policy(btree::CacheKind::Clock)
+            .durability(btree::Durability::Async)
+            .growth(btree::Growth::Blocks(8));
+        {
+            let bt = btree::Btree::<u32, u32>::create_with(path, &options).unwrap();
+            // header block, then the root leaf and the preallocated blocks
+            assert_eq!(std::fs::metadata(path).unwrap().len(), 9 * 512);
+            assert_eq!(bt.verify().unwrap().free_blocks, 7);
+            for i in 0..1_000 {
+                bt.insert(i, i * 2).unwrap();
+            }
+            assert_eq!(std::fs::metadata(path).unwrap().len() % (8 * 512), 512);
+            bt.flush_cache().unwrap();
+        }
+        // everything is stored, nothing has to be repeated
+        let stored = options.clone().multimap(false);
+        {
+            let bt = btree::Btree::<u32, u32>::open(path, &btree::BtreeOptions::new()).unwrap();
+            assert_eq!(bt.options(), stored);
+            assert_eq!(bt.find(&500).unwrap(), 1_000);
+        }
+        // overrides apply to this open only
+        {
+            let overrides = btree::BtreeOptions::new()
+                .block_size(512)
+                .cache_size(4)
+                .durability(btree::Durability::Sync);
+            let bt = btree::Btree::<u32, u32>::open(path, &overrides).unwrap();
+            assert_eq!(bt.options().cache_size, Some(4));
+            assert_eq!(bt.options().durability, Some(btree::Durability::Sync));
+            assert_eq!(bt.options().alpha, Some(3));
+            bt.upsert(1, 1).unwrap();
+            bt.flush_cache().unwrap();
+        }
+        {
+            let bt = btree::Btree::<u32, u32>::load(path, 32).unwrap();
+            assert_eq!(bt.options().cache_size, Some(32));
+            assert_eq!(bt.options().durability, Some(btree::Durability::Async));
+            assert_eq!(bt.find(&1).unwrap(), 1);
+        }
+        // structural settings can't drift from the stored ones
+        for wrong in [
+            btree::BtreeOptions::new().block_size(4096),
+            btree::BtreeOptions::new().alpha(2),
+            btree::BtreeOptions::new().multimap(true),
+        ]
+        .iter()
+        {
+            match btree::Btree::<u32, u32>::open(path, wrong) {
+                Err(btree::BtreeError::HeaderMismatch(_)) => {}
+                other => panic!("expected HeaderMismatch, got {:?}", other.map(|_| ())),
+            }
+        }
+        let bt = btree::Btree::<u32, u32>::open(path, &btree::BtreeOptions::new()).unwrap();
+        assert_eq!(bt.options(), stored);
+        assert!(bt.verify().unwrap().is_ok());
+        drop(bt);
+        // settings the tree can't work with are rejected before the file is touched
+        let bad = |result: Result<(), btree::BtreeError>| {
+            matches!(result, Err(btree::BtreeError::BadOptions(_)))
+        };
+        let other = std::path::Path::new("test_options_bad.idx");
+        setup(other);
+        for wrong in [
+            btree::BtreeOptions::new().alpha(0),
+            btree::BtreeOptions::new().alpha(1),
+            btree::BtreeOptions::new().block_size(64),
+            btree::BtreeOptions::new().growth(btree::Growth::Blocks(0)),
+            btree::BtreeOptions::new().growth(btree::Growth::Percent(0)),
+        ]
+        .iter()
+        {
+            assert!(bad(
+                btree::Btree::<u32, u32>::create_with(other, wrong).map(|_| ())
+            ));
+            assert!(bad(btree::Btree::<u32, u32>::bulk_load_with(
+                other,
+                wrong,
+                vec![(1, 1)],
+                1.0
+            )
+            .map(|_| ())));
+            assert!(bad(
+                btree::ConcurrentBtree::<u32, u32>::new(other, wrong).map(|_| ())
+            ));
+        }
+        assert!(!other.exists());
+        // block of the long keys takes more
+        let long = btree::BtreeOptions::new().block_size(512);
+        assert!(bad(btree::Btree::<[u64; 16], u32>::create_with(
+            other, &long
+        )
+        .map(|_| ())));
+        let growth = btree::BtreeOptions::new().growth(btree::Growth::Percent(0));
+        assert!(bad(
+            btree::Btree::<u32, u32>::open(path, &growth).map(|_| ())
+        ));
+        let bt = btree::Btree::<u32, u32>::open(path, &btree::BtreeOptions::new()).unwrap();
+        assert_eq!(bt.options(), stored);
+    }
+
+    #[test]
+    fn durability() {
+        log_init();
+        let path = std::path::Path::new("test_durability.idx");
+        setup(path);
+        let options = btree::BtreeOptions::new().block_size(256).cache_size(8);
+        btree::Btree::<u32, u32>::create_with(path, &options).unwrap();
+        let open = |durability| {
+            let options = btree::BtreeOptions::new().durability(durability);
+            btree::Btree::<u32, u32>::open(path, &options).unwrap()
+        };
+        {
+            let bt = open(btree::Durability::Sync);
+            for i in 0..20 {
+                bt.insert(i, i).unwrap();
+            }
+            assert_eq!(bt.stats().unwrap().syncs, 20);
+            bt.flush_cache().unwrap();
+        }
+        {
+            // batch is synced by the number of operations, the interval is far away
+            let bt = open(btree::Durability::GroupCommit {
+                ops: 10,
+                interval_ms: 60_000,
+            });
+            for i in 20..115 {
+                bt.insert(i, i).unwrap();
+            }
+            assert_eq!(bt.stats().unwrap().syncs, 9);
+            bt.sync().unwrap();
+            assert_eq!(bt.stats().unwrap().syncs, 10);
+        }
+        {
+            // interval passed, every commit closes the batch
+            let bt = open(btree::Durability::GroupCommit {
+                ops: 1_000,
+                interval_ms: 0,
+            });
+            for i in 115..120 {
+                bt.insert(i, i).unwrap();
+            }
+            assert_eq!(bt.stats().unwrap().syncs, 5);
+        }
+        {
+            let bt = open(btree::Durability::Async);
+            for i in 120..200 {
+                bt.insert(i, i).unwrap();
+            }
+            bt.remove(&0).unwrap();
+            assert_eq!(bt.stats().unwrap().syncs, 0);
+            bt.sync().unwrap();
+            assert_eq!(bt.stats().unwrap().syncs, 1);
+            bt.upsert(1, 100).unwrap();
+            // dropped without flush_cache, the journal has the rest
+        }
+        {
+            // idle tree is synced by the caller once the interval passed
+            let bt = open(btree::Durability::GroupCommit {
+                ops: 1_000,
+                interval_ms: 50,
+            });
+            assert!(!bt.sync_if_due().unwrap());
+            bt.insert(200, 200).unwrap();
+            assert!(!bt.sync_if_due().unwrap());
+            std::thread::sleep(std::time::Duration::from_millis(60));
+            assert!(bt.sync_if_due().unwrap());
+            assert_eq!(bt.stats().unwrap().syncs, 1);
+            // nothing is committed since
+            std::thread::sleep(std::time::Duration::from_millis(60));
+            assert!(!bt.sync_if_due().unwrap());
+        }
+        let bt = btree::Btree::<u32, u32>::load(path, 8).unwrap();
+        assert!(bt.find(&0).is_err());
+        assert_eq!(bt.find(&1).unwrap(), 100);
+        for i in 2..=200 {
+            assert_eq!(bt.find(&i).unwrap(), i);
+        }
+        assert!(bt.verify().unwrap().is_ok());
+    }
+
+    #[test]
+    fn durability_order() {
+        log_init();
+        let path = std::path::Path::new("test_durability_order.idx");
+        let journal = std::path::Path::new("test_durability_order.idx.journal");
+        setup(path);
+        let options = btree::BtreeOptions::new()
+            .block_size(256)
+            .cache_size(0)
+            .durability(btree::Durability::GroupCommit {
+                ops: 50,
+                interval_ms: 60_000,
+            });
+        let bt = btree::Btree::<u32, u32>::create_with(path, &options).unwrap();
+        bt.sync().unwrap();
+        let index = std::fs::read(path).unwrap();
+        for i in 0..40 {
+            bt.insert(i, i).unwrap();
+        }
+        // records are not synced yet, the index file has only grown with zeros
+        let unsynced = std::fs::read(path).unwrap();
+        assert_eq!(unsynced[..index.len()], index[..]);
+        assert!(unsynced[index.len()..].iter().all(|byte| *byte == 0));
+        assert!(std::fs::metadata(journal).unwrap().len() > 0);
+        for i in 0..40 {
+            assert_eq!(bt.find(&i).unwrap(), i);
+        }
+        assert!(bt.verify().unwrap().is_ok());
+        bt.sync().unwrap();
+        assert_ne!(std::fs::read(path).unwrap(), unsynced);
+
+        // the file is cut off after the journal sync only
+        for i in 0..30 {
+            bt.remove(&i).unwrap();
+        }
+        let index = std::fs::read(path).unwrap();
+        bt.compact().unwrap();
+        assert!(std::fs::metadata(path).unwrap().len() < index.len() as u64);
+        assert!(bt.verify().unwrap().is_ok());
+        drop(bt);
+        let bt = btree::Btree::<u32, u32>::load(path, 0).unwrap();
+        for i in 30..40 {
+            assert_eq!(bt.find(&i).unwrap(), i);
+        }
+        assert!(bt.find(&0).is_err());
+    }
+
+    #[test]
+    fn locking() {
+        log_init();
+        let path = std::path::Path::new("test_locking.idx");
+        let journal = std::path::Path::new("test_locking.idx.journal");
+        setup(path);
+        let locked = |result: Result<btree::Btree<u32, String>, btree::BtreeError>| {
+            matches!(result, Err(btree::BtreeError::Locked))
+        };
+        let options = btree::BtreeOptions::new();
+        {
+            let bt = btree::Btree::<u32, String>::new(path, 512, 2, 16).unwrap();
+            assert!(locked(btree::Btree::open(path, &options)));
+            assert!(locked(btree::Btree::open_read_only(path, &options)));
+            assert!(locked(btree::Btree::new(path, 512, 2, 16)));
+            assert!(matches!(
+                btree::ConcurrentBtree::<u32, String>::open(path, &options),
+                Err(btree::BtreeError::Locked)
+            ));
+            for i in 0..500 {
+                bt.insert(i, "v".repeat(i as usize % 300)).unwrap();
+            }
+            bt.flush_cache().unwrap();
+            // the rest stays in the cache and the journal
+            for i in 500..700 {
+                bt.insert(i, i.to_string()).unwrap();
+            }
+            bt.remove(&7).unwrap();
+        }
+        let file = std::fs::read(path).unwrap();
+        let journal_len = std::fs::metadata(journal).unwrap().len();
+        assert!(journal_len > 0);
+        {
+            let reader = btree::Btree::<u32, String>::open_read_only(path, &options).unwrap();
+            let other = btree::Btree::<u32, String>::open_read_only(path, &options).unwrap();
+            assert!(reader.is_read_only());
+            assert!(locked(btree::Btree::open(path, &options)));
+            assert_eq!(reader.find(&300).unwrap(), "");
+            assert_eq!(reader.find(&299).unwrap(), "v".repeat(299));
+            assert_eq!(other.find(&650).unwrap(), "650");
+            assert!(reader.find(&7).is_err());
+            assert_eq!(reader.iter().count(), 699);
+            assert!(reader.verify().unwrap().is_ok());
+            assert!(matches!(
+                reader.insert(1_000, String::new()),
+                Err(btree::BtreeError::ReadOnly)
+            ));
+            assert!(matches!(
+                reader.remove(&1),
+                Err(btree::BtreeError::ReadOnly)
+            ));
+            assert!(matches!(reader.compact(), Err(btree::BtreeError::ReadOnly)));
+            reader.flush_cache().unwrap();
+            reader.sync().unwrap();
+            assert_eq!(reader.find(&1).unwrap(), "v");
+        }
+        // readers don't touch the file and the journal
+        assert!(std::fs::read(path).unwrap() == file);
+        assert_eq!(std::fs::metadata(journal).unwrap().len(), journal_len);
+        let bt = btree::Btree::<u32, String>::open(path, &options).unwrap();
+        assert!(!bt.is_read_only());
+        assert_eq!(bt.find(&650).unwrap(), "650");
+        assert_eq!(bt.iter().count(), 699);
+    }
+
+    #[test]
+    fn cursor() {
+        log_init();
+        let path = std::path::Path::new("test_cursor.idx");
+        setup(path);
+        let options = btree::BtreeOptions::new().block_size(512);
+        let bt = btree::Btree::<u32, String>::create_with(path, &options).unwrap();
+        for i in (0..1_000).step_by(2) {
+            bt.insert(i, i.to_string()).unwrap();
+        }
+        let mut cursor = bt.cursor();
+        assert!(!cursor.valid());
+        assert!(cursor.value().is_err());
+        assert!(cursor.seek(&501).unwrap());
+        assert_eq!(cursor.key(), Some(&502));
+        assert!(cursor.seek_for_prev(&501).unwrap());
+        assert_eq!(cursor.key(), Some(&500));
+        assert_eq!(cursor.value().unwrap(), "500");
+        assert!(!cursor.seek(&999).unwrap());
+        assert!(cursor.seek_for_prev(&0).unwrap());
+        assert_eq!(cursor.key(), Some(&0));
+        assert!(!cursor.prev().unwrap());
+        assert!(!cursor.valid());
+
+        // both directions go through all leaves
+        assert!(cursor.seek_to_first().unwrap());
+        let mut keys = vec![*cursor.key().unwrap()];
+        while cursor.next().unwrap() {
+            keys.push(*cursor.key().unwrap());
+        }
+        assert_eq!(keys, (0..1_000).step_by(2).collect::<Vec<_>>());
+        assert!(!cursor.next().unwrap());
+        assert!(cursor.seek_to_last().unwrap());
+        let mut keys = vec![*cursor.key().unwrap()];
+        while cursor.prev().unwrap() {
+            keys.push(*cursor.key().unwrap());
+        }
+        assert_eq!(keys, (0..1_000).step_by(2).rev().collect::<Vec<_>>());
+
+        // every third entry is removed, the cursor goes to the next one
+        assert!(cursor.seek(&0).unwrap());
+        let mut i = 0;
+        while cursor.valid() {
+            if i % 3 == 0 {
+                let key = *cursor.key().unwrap();
+                assert_eq!(cursor.delete_current().unwrap(), key.to_string());
+            } else {
+                cursor.next().unwrap();
+            }
+            i += 1;
+        }
+        let expected = (0..1_000)
+            .step_by(2)
+            .enumerate()
+            .filter(|(n, _)| n % 3 != 0)
+            .map(|(_, key)| key)
+            .collect::<Vec<_>>();
+        assert_eq!(
+            bt.keys().map(|key| key.unwrap()).collect::<Vec<_>>(),
+            expected
+        );
+
+        // values are replaced, the large ones don't fit to the leaf
+        assert!(cursor.seek(&100).unwrap());
+        assert_eq!(cursor.update_current("x".repeat(300)).unwrap(), "100");
+        assert_eq!(cursor.key(), Some(&100));
+        assert_eq!(cursor.value().unwrap(), "x".repeat(300));
+        assert!(cursor.next().unwrap());
+        assert_eq!(cursor.update_current(String::new()).unwrap(), "104");
+        assert!(cursor.prev().unwrap());
+        assert_eq!(cursor.key(), Some(&100));
+        assert_eq!(bt.find(&104).unwrap(), "");
+
+        // the tree changed by other means, the cursor finds its place by the key
+        bt.insert(101, "101".to_string()).unwrap();
+        assert!(cursor.next().unwrap());
+        assert_eq!(cursor.key(), Some(&101));
+        bt.remove(&101).unwrap();
+        assert!(cursor.delete_current().is_err());
+        assert_eq!(cursor.key(), Some(&104));
+        assert!(bt.verify().unwrap().is_ok());
+        drop(cursor);
+
+        // multimap: cursor keeps the order of the equal keys
+        let path = std::path::Path::new("test_cursor_multimap.idx");
+        setup(path);
+        let options = options.multimap(true);
+        let bt = btree::Btree::<u32, u32>::create_with(path, &options).unwrap();
+        for i in 0..600 {
+            bt.insert(i % 3, i).unwrap();
+        }
+        let mut cursor = bt.cursor();
+        assert!(cursor.seek(&1).unwrap());
+        while cursor.key() == Some(&1) {
+            let val = cursor.value().unwrap();
+            if val.is_multiple_of(2) {
+                assert_eq!(cursor.delete_current().unwrap(), val);
+            } else {
+                cursor.next().unwrap();
+            }
+        }
+        assert_eq!(cursor.key(), Some(&2));
+        assert_eq!(
+            bt.find_all(&1).unwrap(),
+            (1..600).step_by(6).collect::<Vec<_>>()
+        );
+        assert!(cursor.seek_for_prev(&1).unwrap());
+        assert_eq!(cursor.value().unwrap(), 595);
+        assert!(bt.verify().unwrap().is_ok());
+    }
+
+    #[test]
+    fn prefix() {
+        log_init();
+        let path = std::path::Path::new("test_prefix.idx");
+        setup(path);
+        let options = btree::BtreeOptions::new().block_size(512);
+        let bt = btree::Btree::<(u32, String, u32), u32>::create_with(path, &options).unwrap();
+        for user in 0..20 {
+            for (n, tag) in ["a", "b", "c"].iter().enumerate() {
+                for i in 0..10 {
+                    bt.insert((user, tag.to_string(), i), user * 100 + n as u32 * 10 + i)
+                        .unwrap();
+                }
+            }
+        }
+        let vals = |iter: btree::Iter<_, u32>| iter.map(|item| item.unwrap().1).collect::<Vec<_>>();
+        assert_eq!(
+            vals(bt.prefix(&7)),
+            (700..710)
+                .chain(710..720)
+                .chain(720..730)
+                .collect::<Vec<_>>()
+        );
+        assert_eq!(
+            vals(bt.prefix(&(7, "b".to_string()))),
+            (710..720).collect::<Vec<_>>()
+        );
+        let last = bt.prefix(&(7, "b".to_string())).next_back();
+        assert_eq!(last.unwrap().unwrap(), ((7, "b".to_string(), 9), 719));
+        assert!(bt.prefix(&20).next().is_none());
+        assert!(bt.prefix(&(7, "d".to_string())).next().is_none());
+
+        let path = std::path::Path::new("test_prefix_string.idx");
+        setup(path);
+        let bt = btree::Btree::<String, u32>::create_with(path, &options).unwrap();
+        for (i, word) in ["ab", "abc", "abd", "ac", "b", "ba"].iter().enumerate() {
+            bt.insert(word.to_string(), i as u32).unwrap();
+        }
+        let keys = |prefix: &str| {
+            bt.prefix(prefix)
+                .map(|item| item.unwrap().0)
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(keys("ab"), ["ab", "abc", "abd"]);
+        assert_eq!(keys("b"), ["b", "ba"]);
+        assert_eq!(keys(""), ["ab", "abc", "abd", "ac", "b", "ba"]);
+        assert!(keys("abe").is_empty());
+    }
 }
@@ -0,0 +1,168 @@
+// Runs the btree command-line tool the way a user does and checks its output and exit codes.
+use std::path::PathBuf;
+use std::process::{Command, Output};
+
+fn index(name: &str) -> PathBuf {
+    let path = PathBuf::from(env!("CARGO_TARGET_TMPDIR")).join(name);
+    let _ = std::fs::remove_file(&path);
+    let _ = std::fs::remove_file(path.with_extension("idx.journal"));
+    path
+}
+
+fn btree(args: &[&str]) -> Output {
+    Command::new(env!("CARGO_BIN_EXE_btree"))
+        .args(args)
+        .output()
+        .unwrap()
+}
+
+fn run(args: &[&str]) -> (i32, String, String) {
+    let output = btree(args);
+    (
+        output.status.code().unwrap(),
+        String::from_utf8(output.stdout).unwrap(),
+        String::from_utf8(output.stderr).unwrap(),
+    )
+}
+
+fn ok(args: &[&str]) -> String {
+    let (code, stdout, stderr) = run(args);
+    assert_eq!(code, 0, "btree {:?}: {}", args, stderr);
+    stdout
+}
+
+#[test]
+fn cli_usage_errors() {
+    let path = index("cli_usage.idx");
+    let file = path.to_str().unwrap();
+    let usage = |args: &[&str], message: &str| {
+        let (code, stdout, stderr) = run(args);
+        assert_eq!(code, 2, "btree {:?}", args);
+        assert!(stdout.is_empty());
+        assert!(stderr.contains(message), "btree {:?}: {}", args, stderr);
+        assert!(stderr.contains("usage: btree"));
+    };
+    usage(&[], "command and file are required");
+    usage(&["create"], "command and file are required");
+    usage(&["drop", file], "unknown command drop");
+    usage(&["--bogus", "create", file], "unknown option --bogus");
+    usage(
+        &["--cache", "many", "create", file],
+        "bad value of --cache: many",
+    );
+    usage(&["--cache"], "--cache needs a value");
+    usage(
+        &["--cache-policy", "fifo", "create", file],
+        "unknown cache policy fifo",
+    );
+    // arity of the commands
+    usage(
+        &["create", file, "extra"],
+        "wrong number of arguments of create",
+    );
+    usage(&["get", file], "wrong number of arguments of get");
+    usage(&["del", file, "a", "b"], "wrong number of arguments of del");
+    usage(&["put", file, "a"], "wrong number of arguments of put");
+    usage(
+        &["scan", file, "a", "b", "c"],
+        "wrong number of arguments of scan",
+    );
+    let (code, _, stderr) = run(&["--help"]);
+    assert_eq!(code, 2);
+    assert!(stderr.starts_with("usage: btree"));
+    assert!(!path.exists());
+}
+
+#[test]
+fn cli_commands() {
+    let path = index("cli_commands.idx");
+    let file = path.to_str().unwrap();
+    ok(&["create", file]);
+    for key in ["b", "d", "a", "c", "e"].iter() {
+        ok(&["put", file, key, &key.repeat(2)]);
+    }
+    assert_eq!(ok(&["get", file, "c"]), "cc\n");
+    // put replaces the value of the existing key
+    ok(&["put", file, "c", "new"]);
+    assert_eq!(ok(&["get", file, "c"]), "new\n");
+    ok(&["del", file, "d"]);
+    let (code, stdout, stderr) = run(&["get", file, "d"]);
+    assert_eq!(code, 1);
+    assert!(stdout.is_empty());
+    assert_eq!(stderr, "btree: key not found\n");
+    assert_eq!(run(&["del", file, "d"]).0, 1);
+
+    // from is included, to is excluded
+    assert_eq!(ok(&["scan", file]), "a\taa\nb\tbb\nc\tnew\ne\tee\n");
+    assert_eq!(ok(&["scan", file, "b"]), "b\tbb\nc\tnew\ne\tee\n");
+    assert_eq!(ok(&["scan", file, "b", "e"]), "b\tbb\nc\tnew\n");
+    assert_eq!(ok(&["scan", file, "bb", "c"]), "");
+    assert_eq!(ok(&["--limit", "2", "scan", file]), "a\taa\nb\tbb\n");
+
+    assert!(ok(&["verify", file]).starts_with("nodes=1 leaves=1 depth=1 entries=4 "));
+    assert!(ok(&["stats", file]).contains("height: 1"));
+    assert!(ok(&["dump", file]).starts_with("Node A="));
+    assert!(ok(&["--format", "json", "dump", file]).starts_with('{'));
+    assert!(ok(&["--format", "dot", "dump", file]).starts_with("digraph"));
+    assert_eq!(run(&["--format", "xml", "dump", file]).0, 1);
+    assert_eq!(run(&["get", &format!("{}.missing", file), "a"]).0, 1);
+}
+
+#[test]
+fn cli_types_and_multimap() {
+    let path = index("cli_multimap.idx");
+    let file = path.to_str().unwrap();
+    let typed = |args: &[&str]| {
+        let mut all = vec!["--key", "u32", "--val", "i64"];
+        all.extend_from_slice(args);
+        ok(&all)
+    };
+    typed(&["--multimap", "create", file]);
+    typed(&["put", file, "7", "-1"]);
+    typed(&["put", file, "7", "-2"]);
+    typed(&["put", file, "3", "30"]);
+    // multimap put adds the value, get prints all of them in insertion order
+    assert_eq!(typed(&["get", file, "7"]), "-1\n-2\n");
+    assert_eq!(typed(&["scan", file, "4"]), "7\t-1\n7\t-2\n");
+    let (code, _, stderr) = run(&["--key", "u32", "--val", "i64", "get", file, "x"]);
+    assert_eq!(code, 1);
+    assert_eq!(stderr, "btree: bad key: x\n");
+    let (code, _, stderr) = run(&["--key", "f64", "get", file, "1"]);
+    assert_eq!(code, 1);
+    assert_eq!(stderr, "btree: unknown key type f64\n");
+}
+
+#[test]
+fn cli_compact_steps() {
+    let path = index("cli_compact.idx");
+    let file = path.to_str().unwrap();
+    let typed = |args: &[&str]| {
+        let mut all = vec!["--key", "u32", "--val", "u32", "--block-size", "512"];
+        all.extend_from_slice(args);
+        ok(&all)
+    };
+    typed(&["create", file]);
+    for i in 0..300 {
+        let key = i.to_string();
+        typed(&["put", file, &key, &key]);
+    }
+    for i in 0..300 {
+        if i % 4 != 0 {
+            typed(&["del", file, &i.to_string()]);
+        }
+    }
+    let len = std::fs::metadata(&path).unwrap().len();
+    let steps = typed(&["--step", "1", "compact", file]);
+    let steps: usize = steps
+        .trim()
+        .strip_prefix("steps=")
+        .unwrap()
+        .parse()
+        .unwrap();
+    assert!(steps > 0);
+    assert!(std::fs::metadata(&path).unwrap().len() < len);
+    assert!(typed(&["verify", file]).contains(" free_blocks=0"));
+    // nothing is left to compact, the last call doesn't count
+    assert_eq!(typed(&["--step", "1", "compact", file]), "steps=0\n");
+    assert_eq!(typed(&["get", file, "296"]), "296\n");
+}